@@ -0,0 +1,160 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use common::{ChatLine, Comment, ReviewSession, SessionRecords, SharedDiff, Verdict};
+use network::signing;
+use storage::Storage;
+
+const FORMAT: &str = "reviewmesh-archive";
+const VERSION: u32 = 1;
+
+/// One line of an archive. The first line is always the header; the rest
+/// may come in any order. Records keep their signatures, so the archive can
+/// be checked wherever it is imported.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Line {
+    Header { format: String, version: u32, exported_at: DateTime<Utc> },
+    Session(ReviewSession),
+    Diff(SharedDiff),
+    Comment(Comment),
+    Chat(ChatLine),
+    Verdict(Verdict),
+}
+
+/// Writes a session and everything recorded for it to `out` as JSON lines.
+/// Session keys stay behind; they are handed out by the owner.
+pub fn export(storage: &Storage, session_id: &str, out: &Path) -> Result<(), Box<dyn Error>> {
+    let records = storage
+        .load_records(session_id)?
+        .ok_or_else(|| format!("session {} not found", session_id))?;
+    let mut lines = vec![
+        Line::Header { format: FORMAT.to_string(), version: VERSION, exported_at: Utc::now() },
+        Line::Session(records.session),
+    ];
+    lines.extend(records.diff.map(Line::Diff));
+    lines.extend(records.comments.into_iter().map(Line::Comment));
+    lines.extend(records.chat.into_iter().map(Line::Chat));
+    lines.extend(records.verdicts.into_iter().map(Line::Verdict));
+
+    let mut writer = BufWriter::new(File::create(out)?);
+    for line in &lines {
+        serde_json::to_writer(&mut writer, line)?;
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Merges an archive written by [`export`] into the database and returns the
+/// session's id. Records are matched by id, so importing the same archive
+/// twice changes nothing. Records not signed by the session's owner or one
+/// of its members are dropped.
+pub fn import(storage: &Storage, archive: &Path) -> Result<String, Box<dyn Error>> {
+    let mut lines = BufReader::new(File::open(archive)?).lines();
+    match lines.next().transpose()?.map(|l| serde_json::from_str(&l)).transpose() {
+        Ok(Some(Line::Header { format, version, .. })) if format == FORMAT => {
+            if version > VERSION {
+                return Err(format!("archive version {} is newer than this build supports ({})", version, VERSION).into());
+            }
+        }
+        _ => return Err(format!("{} is not a review archive", archive.display()).into()),
+    }
+
+    let mut session = None;
+    let (mut diff, mut comments, mut chat, mut verdicts) = (None, vec![], vec![], vec![]);
+    for (number, line) in lines.enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let line = serde_json::from_str(&line).map_err(|e| format!("line {}: {}", number + 2, e))?;
+        match line {
+            Line::Header { .. } => return Err(format!("line {}: unexpected header", number + 2).into()),
+            Line::Session(s) if session.is_none() => session = Some(s),
+            Line::Session(_) => return Err("the archive holds more than one session".into()),
+            Line::Diff(d) => diff = Some(d),
+            Line::Comment(comment) => comments.push(comment),
+            Line::Chat(line) => chat.push(line),
+            Line::Verdict(verdict) => verdicts.push(verdict),
+        }
+    }
+    let session = session.ok_or("the archive holds no session")?;
+    let records = SessionRecords { session, diff, comments, chat, verdicts };
+
+    let id = records.session.id.clone();
+    if records.diff.as_ref().is_some_and(|d| d.session_id != id)
+        || records.comments.iter().any(|c| c.session_id != id)
+        || records.chat.iter().any(|c| c.session_id != id)
+        || records.verdicts.iter().any(|v| v.session_id != id)
+    {
+        return Err(format!("the archive mixes records from sessions other than {}", id).into());
+    }
+    let known = storage.load_session(&id)?;
+    let records = signing::verify_records(records, known.as_ref()).ok_or("the session isn't signed by its owner")?;
+    storage.merge_records(&records)?;
+    Ok(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use common::testing::TempDir;
+    use common::{SessionDetails, SessionState};
+    use network::identity::NodeIdentity;
+
+    #[test]
+    fn importing_an_export_twice_restores_the_session_once() {
+        let dir = TempDir::new("archive");
+        let created_at = Utc.timestamp_opt(1_600_000_000, 0).unwrap();
+        let node = NodeIdentity::load(dir.path(), "alice".to_string(), None).unwrap();
+        let source = Storage::new(dir.join("source.db").to_str().unwrap()).unwrap();
+        let mut session = ReviewSession {
+            id: "s1".to_string(),
+            title: "Login".to_string(),
+            created_at,
+            participants: vec!["alice".to_string()],
+            owner: node.identity.peer_id.clone(),
+            members: vec![],
+            revoked: vec![],
+            revision: 0,
+            signature: String::new(),
+            state: SessionState::Open,
+            details: SessionDetails::default(),
+        };
+        node.sign(&mut session);
+        source.save_session(&session).unwrap();
+        let mut chat = ChatLine {
+            id: "c1".to_string(),
+            session_id: "s1".to_string(),
+            author: "alice".to_string(),
+            author_id: node.identity.peer_id.clone(),
+            body: "Looks good".to_string(),
+            created_at,
+            signature: String::new(),
+        };
+        node.sign(&mut chat);
+        source.save_chat(&chat).unwrap();
+
+        let archive = dir.join("s1.rmesh");
+        export(&source, "s1", &archive).unwrap();
+        let target = Storage::new(dir.join("target.db").to_str().unwrap()).unwrap();
+        import(&target, &archive).unwrap();
+        import(&target, &archive).unwrap();
+        let (expected, imported) = (source.load_records("s1").unwrap(), target.load_records("s1").unwrap());
+        assert_eq!(imported, expected);
+
+        // An unsigned line, say one added by hand, doesn't get in.
+        source.save_chat(&ChatLine { id: "c2".to_string(), signature: String::new(), ..chat }).unwrap();
+        export(&source, "s1", &archive).unwrap();
+        import(&target, &archive).unwrap();
+        let imported = target.load_records("s1").unwrap().unwrap();
+        assert_eq!(imported.chat.len(), 1);
+    }
+}
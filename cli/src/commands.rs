@@ -0,0 +1,911 @@
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, NaiveDate, Utc};
+use clap::{Args, Subcommand};
+use uuid::Uuid;
+
+use common::{ChatLine, Comment, Decision, JoinRequest, MeshMessage, Resolution, ReviewSession, SessionState, SharedDiff, Side, Verdict};
+use git_integration::{bundle, compute_diff, owners};
+use network::access;
+use network::config::NetworkConfig;
+use network::identity::{self, NodeIdentity, Profile};
+use network::transfer::BundleResponse;
+use network::{Multiaddr, NetworkManager, PeerId};
+use storage::{HitRecord, SearchFilter, Storage};
+
+use crate::{archive, git_store, keys, session};
+
+#[derive(Args)]
+pub struct PublishArgs {
+    /// Also publish the change to peers on the mesh
+    #[arg(long)]
+    pub publish: bool,
+    /// Seconds to wait for a peer before giving up on publishing
+    #[arg(long, default_value_t = 5)]
+    pub peer_timeout: u64,
+    /// Dial this peer or relay as well as the configured ones
+    #[arg(long = "peer", value_name = "MULTIADDR")]
+    pub peers: Vec<Multiaddr>,
+}
+
+/// Per-run overrides of `network.json` for long-running nodes.
+#[derive(Args)]
+pub struct NetworkArgs {
+    /// Listen on this address instead of the configured ones, e.g.
+    /// /ip4/0.0.0.0/tcp/4002, /ip6/::/tcp/4002 or /ip4/0.0.0.0/tcp/4003/ws
+    #[arg(long = "listen", value_name = "MULTIADDR")]
+    pub listen: Vec<Multiaddr>,
+    /// Dial this peer or relay as well as the configured ones
+    #[arg(long = "peer", value_name = "MULTIADDR")]
+    pub peers: Vec<Multiaddr>,
+}
+
+impl NetworkArgs {
+    /// The settings saved in `dir` with these overrides applied.
+    pub fn config(&self, dir: &Path) -> Result<NetworkConfig, Box<dyn Error>> {
+        let mut config = NetworkConfig::load(dir)?;
+        config.bootstrap.extend(self.peers.iter().cloned());
+        if !self.listen.is_empty() {
+            config.listen = self.listen.clone();
+        }
+        Ok(config)
+    }
+}
+
+/// Full-text search over comments and chat.
+#[derive(Args)]
+pub struct SearchArgs {
+    /// Words that must all appear; end one with * to match a prefix
+    #[arg(required = true)]
+    pub query: Vec<String>,
+    #[arg(long)]
+    pub session: Option<String>,
+    /// Display name or PeerId
+    #[arg(long)]
+    pub author: Option<String>,
+    /// Only comments on this file
+    #[arg(long)]
+    pub file: Option<String>,
+    /// Only entries from this date (YYYY-MM-DD or RFC 3339) on
+    #[arg(long, value_parser = parse_date)]
+    pub since: Option<DateTime<Utc>>,
+    /// Only entries before this date
+    #[arg(long, value_parser = parse_date)]
+    pub until: Option<DateTime<Utc>>,
+    /// Only resolved comments
+    #[arg(long, conflicts_with = "unresolved")]
+    pub resolved: bool,
+    /// Only unresolved comments
+    #[arg(long)]
+    pub unresolved: bool,
+    /// Leave chat out
+    #[arg(long)]
+    pub comments_only: bool,
+    #[arg(long, default_value_t = 50)]
+    pub limit: usize,
+    #[arg(long)]
+    pub json: bool,
+}
+
+fn parse_date(value: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        return Ok(date.and_hms_opt(0, 0, 0).expect("midnight exists").and_utc());
+    }
+    DateTime::parse_from_rfc3339(value)
+        .map(|t| t.with_timezone(&Utc))
+        .map_err(|_| format!("{} is not a date like 2024-05-01 or 2024-05-01T12:00:00Z", value))
+}
+
+#[derive(Subcommand)]
+pub enum CommentCommand {
+    /// Comment on a line of a file
+    Add {
+        session_id: String,
+        body: String,
+        #[arg(long)]
+        file: String,
+        #[arg(long)]
+        line: usize,
+        /// Count --line in the old file, to comment on a removed line
+        #[arg(long)]
+        old: bool,
+        /// Branch to diff against, used to attach the comment to its hunk
+        #[arg(short, long)]
+        target_branch: Option<String>,
+        #[command(flatten)]
+        publish: PublishArgs,
+    },
+    /// List the comments of a session
+    List {
+        session_id: String,
+        #[arg(long)]
+        json: bool,
+        /// Only show unresolved comments
+        #[arg(long)]
+        unresolved: bool,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ChatCommand {
+    /// Send a chat message to a session
+    Send {
+        session_id: String,
+        body: String,
+        #[command(flatten)]
+        publish: PublishArgs,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum IdentityCommand {
+    /// Show the name, email and PeerId peers see
+    Show,
+    /// Change the name or email shown to peers
+    Set {
+        #[arg(long)]
+        name: Option<String>,
+        #[arg(long)]
+        email: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum PeerCommand {
+    /// List the peers and relays dialed on startup
+    List,
+    /// Dial a peer or relay on every startup
+    Add {
+        /// e.g. /ip4/203.0.113.7/tcp/4001/p2p/12D3KooW...
+        address: Multiaddr,
+    },
+    /// Stop dialing a peer or relay on startup
+    Remove {
+        address: Multiaddr,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum SessionCommand {
+    /// List every session in storage
+    List {
+        #[arg(long)]
+        json: bool,
+        /// Include archived sessions
+        #[arg(long)]
+        all: bool,
+    },
+    /// Show a session with its comments and chat
+    Show {
+        session_id: String,
+        #[arg(long)]
+        json: bool,
+    },
+    /// Print a session's state, or move a session you own to a new one:
+    /// open, approved, merged, abandoned or archived
+    State {
+        session_id: String,
+        state: Option<SessionState>,
+        #[command(flatten)]
+        publish: PublishArgs,
+    },
+    /// List the files of a session's diff that have owners in
+    /// .reviewmesh/CODEOWNERS on the target branch, and whether one of
+    /// them has approved
+    Reviewers {
+        session_id: String,
+    },
+    /// Change the title, description, labels or linked issues of a session
+    /// you own
+    Edit {
+        session_id: String,
+        #[arg(long)]
+        title: Option<String>,
+        #[arg(long)]
+        description: Option<String>,
+        /// Add a label; repeat for several
+        #[arg(long = "label", value_name = "LABEL")]
+        labels: Vec<String>,
+        #[arg(long = "remove-label", value_name = "LABEL")]
+        remove_labels: Vec<String>,
+        /// Link an issue, e.g. #42 or a tracker URL; repeat for several
+        #[arg(long = "issue", value_name = "ISSUE")]
+        issues: Vec<String>,
+        #[arg(long = "remove-issue", value_name = "ISSUE")]
+        remove_issues: Vec<String>,
+        #[command(flatten)]
+        publish: PublishArgs,
+    },
+    /// Print an invite token for a session you own
+    Invite {
+        session_id: String,
+        /// How long the token admits new members
+        #[arg(long, default_value_t = 24)]
+        expires_in_hours: i64,
+    },
+    /// Ask a session's owner to admit you, using their invite token
+    Join {
+        token: String,
+        /// Seconds to wait for the owner to admit you
+        #[arg(long, default_value_t = 30)]
+        timeout: u64,
+        /// Dial this peer or relay as well as the configured ones
+        #[arg(long = "peer", value_name = "MULTIADDR")]
+        peers: Vec<Multiaddr>,
+    },
+    /// List the owner, members and revoked peers of a session
+    Members {
+        session_id: String,
+    },
+    /// Remove a member from a session you own
+    Revoke {
+        session_id: String,
+        peer_id: String,
+        #[command(flatten)]
+        publish: PublishArgs,
+    },
+    /// Merge sessions with their copies under refs/reviewmesh/ in the
+    /// repository, so they travel with git push and fetch
+    Sync {
+        /// Defaults to every session in the database or the repository
+        session_id: Option<String>,
+    },
+    /// Write a session with its diff, comments, chat and verdicts to a
+    /// portable archive
+    Export {
+        session_id: String,
+        /// Usually named <session-id>.rmesh
+        out: PathBuf,
+    },
+    /// Merge a session archive into the database
+    Import {
+        archive: PathBuf,
+    },
+}
+
+pub async fn comment(storage: &Storage, node: &NodeIdentity, repo: &str, command: CommentCommand) -> Result<(), Box<dyn Error>> {
+    match command {
+        CommentCommand::Add { session_id, body, file, line, old, target_branch, publish } => {
+            let session = joined_session(storage, &session_id)?;
+            let side = if old { Side::Old } else { Side::New };
+            let hunk_id = match target_branch {
+                Some(branch) => compute_diff(repo, &branch)?
+                    .into_iter()
+                    .find(|h| h.file == file && h.lines().iter().any(|l| l.anchor() == (side, line)))
+                    .map(|h| h.id)
+                    .ok_or_else(|| format!("{}:{} is not part of the diff against {}", file, line, branch))?,
+                None => String::new(),
+            };
+            let mut comment = Comment {
+                id: Uuid::new_v4().to_string(),
+                session_id: session.id,
+                author: node.identity.name.clone(),
+                author_id: node.identity.peer_id.clone(),
+                file,
+                hunk_id,
+                line,
+                side,
+                body,
+                created_at: Utc::now(),
+                resolved: false,
+                resolved_at: None,
+                signature: String::new(),
+            };
+            node.sign(&mut comment);
+            storage.save_comment(&comment)?;
+            publish_with(storage, node, &comment.session_id, &publish, |network| network.publish_comment(&comment)).await?;
+            println!("{}", comment.id);
+        }
+        CommentCommand::List { session_id, json, unresolved } => {
+            let comments: Vec<Comment> = storage
+                .load_comments(&session_id)?
+                .into_iter()
+                .filter(|c| !unresolved || !c.resolved)
+                .collect();
+            if json {
+                println!("{}", serde_json::to_string_pretty(&comments)?);
+            } else {
+                for c in &comments {
+                    let state = if c.resolved { "resolved" } else { "open" };
+                    println!("{}  {}:{}  [{}]  {}: {}", c.id, c.file, c.line, state, c.author, c.body);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn search(storage: &Storage, args: SearchArgs) -> Result<(), Box<dyn Error>> {
+    let filter = SearchFilter {
+        session_id: args.session,
+        author: args.author,
+        file: args.file,
+        since: args.since,
+        until: args.until,
+        resolved: match (args.resolved, args.unresolved) {
+            (true, _) => Some(true),
+            (_, true) => Some(false),
+            _ => None,
+        },
+        comments_only: args.comments_only,
+        limit: args.limit,
+    };
+    let hits = storage.search(&args.query.join(" "), &filter)?;
+    if args.json {
+        let hits: Vec<_> = hits
+            .iter()
+            .map(|hit| match &hit.record {
+                HitRecord::Comment(c) => serde_json::json!({ "kind": "comment", "snippet": hit.snippet, "comment": c }),
+                HitRecord::Chat(c) => serde_json::json!({ "kind": "chat", "snippet": hit.snippet, "chat": c }),
+            })
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&hits)?);
+    } else {
+        for hit in &hits {
+            match &hit.record {
+                HitRecord::Comment(c) => {
+                    let state = if c.resolved { "resolved" } else { "open" };
+                    println!("{}  {}  {}:{}  [{}]  {}: {}", c.session_id, c.created_at.format("%Y-%m-%d"), c.file, c.line, state, c.author, hit.snippet);
+                }
+                HitRecord::Chat(c) => {
+                    println!("{}  {}  chat  {}: {}", c.session_id, c.created_at.format("%Y-%m-%d"), c.author, hit.snippet);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+pub async fn chat(storage: &Storage, node: &NodeIdentity, command: ChatCommand) -> Result<(), Box<dyn Error>> {
+    match command {
+        ChatCommand::Send { session_id, body, publish } => {
+            let session = joined_session(storage, &session_id)?;
+            let mut chat_line = ChatLine {
+                id: Uuid::new_v4().to_string(),
+                session_id: session.id,
+                author: node.identity.name.clone(),
+                author_id: node.identity.peer_id.clone(),
+                body,
+                created_at: Utc::now(),
+                signature: String::new(),
+            };
+            node.sign(&mut chat_line);
+            storage.save_chat(&chat_line)?;
+            publish_with(storage, node, &chat_line.session_id, &publish, |network| network.publish_chat(&chat_line)).await?;
+            println!("{}", chat_line.id);
+        }
+    }
+    Ok(())
+}
+
+pub async fn resolve(storage: &Storage, node: &NodeIdentity, comment_id: &str, resolved: bool, publish: PublishArgs) -> Result<(), Box<dyn Error>> {
+    let mut comment = storage
+        .load_comment(comment_id)?
+        .ok_or_else(|| format!("comment {} not found", comment_id))?;
+    let now = Utc::now();
+    comment.resolved = resolved;
+    comment.resolved_at = Some(now);
+    storage.save_comment(&comment)?;
+    let mut resolution = Resolution {
+        comment_id: comment.id,
+        session_id: comment.session_id,
+        resolved,
+        author: node.identity.name.clone(),
+        author_id: node.identity.peer_id.clone(),
+        created_at: now,
+        signature: String::new(),
+    };
+    node.sign(&mut resolution);
+    publish_with(storage, node, &resolution.session_id, &publish, |network| network.publish_resolution(&resolution)).await?;
+    Ok(())
+}
+
+pub async fn verdict(
+    storage: &Storage,
+    node: &NodeIdentity,
+    session_id: &str,
+    decision: Decision,
+    body: String,
+    publish: PublishArgs,
+) -> Result<(), Box<dyn Error>> {
+    let session = joined_session(storage, session_id)?;
+    let mut verdict = Verdict {
+        id: Uuid::new_v4().to_string(),
+        session_id: session.id,
+        author: node.identity.name.clone(),
+        author_id: node.identity.peer_id.clone(),
+        decision,
+        body,
+        created_at: Utc::now(),
+        signature: String::new(),
+    };
+    node.sign(&mut verdict);
+    storage.save_verdict(&verdict)?;
+    publish_with(storage, node, &verdict.session_id, &publish, |network| network.publish_verdict(&verdict)).await?;
+    println!("{}", verdict.id);
+    Ok(())
+}
+
+pub fn identity(node: &NodeIdentity, command: IdentityCommand) -> Result<(), Box<dyn Error>> {
+    match command {
+        IdentityCommand::Show => {
+            println!("{}", node.identity);
+            println!("PeerId: {}", node.identity.peer_id);
+            println!("Config: {}", identity::config_dir().display());
+        }
+        IdentityCommand::Set { name, email } => {
+            let dir = identity::config_dir();
+            let mut profile = Profile::load(&dir)?;
+            if name.is_some() {
+                profile.name = name;
+            }
+            if email.is_some() {
+                profile.email = email;
+            }
+            profile.save(&dir)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn peer(command: PeerCommand) -> Result<(), Box<dyn Error>> {
+    let dir = identity::config_dir();
+    let mut config = NetworkConfig::load(&dir)?;
+    match command {
+        PeerCommand::List => {
+            for address in &config.bootstrap {
+                println!("{}", address);
+            }
+        }
+        PeerCommand::Add { address } => {
+            if !config.bootstrap.contains(&address) {
+                config.bootstrap.push(address);
+                config.save(&dir)?;
+            }
+        }
+        PeerCommand::Remove { address } => {
+            let before = config.bootstrap.len();
+            config.bootstrap.retain(|a| *a != address);
+            if config.bootstrap.len() == before {
+                return Err(format!("{} is not a configured peer", address).into());
+            }
+            config.save(&dir)?;
+        }
+    }
+    Ok(())
+}
+
+pub async fn session(storage: &Storage, node: &NodeIdentity, repo: &str, command: SessionCommand) -> Result<(), Box<dyn Error>> {
+    match command {
+        SessionCommand::List { json, all } => {
+            let mut sessions = storage.list_sessions()?;
+            sessions.retain(|s| all || s.state != SessionState::Archived);
+            if json {
+                println!("{}", serde_json::to_string_pretty(&sessions)?);
+            } else {
+                for s in &sessions {
+                    println!("{}  {}  {}  {}  {}", s.id, s.state, s.created_at.format("%Y-%m-%d %H:%M"), s.title, s.participants.join(", "));
+                }
+            }
+        }
+        SessionCommand::Show { session_id, json } => {
+            let session = storage
+                .load_session(&session_id)?
+                .ok_or_else(|| format!("session {} not found", session_id))?;
+            let comments = storage.load_comments(&session_id)?;
+            let chat = storage.load_chat(&session_id)?;
+            if json {
+                let value = serde_json::json!({
+                    "session": session,
+                    "comments": comments,
+                    "chat": chat,
+                });
+                println!("{}", serde_json::to_string_pretty(&value)?);
+            } else {
+                let open = comments.iter().filter(|c| !c.resolved).count();
+                let details = &session.details;
+                println!("{}: {}", session.id, session.title);
+                println!("State:        {}", session.state);
+                println!("Created:      {}", session.created_at.to_rfc3339());
+                if !details.author.is_empty() {
+                    println!("Author:       {}", details.author);
+                }
+                if let Some(branches) = details.branches() {
+                    println!("Branches:     {}", branches);
+                }
+                if !details.labels.is_empty() {
+                    println!("Labels:       {}", details.labels.join(", "));
+                }
+                if !details.issues.is_empty() {
+                    println!("Issues:       {}", details.issues.join(", "));
+                }
+                println!("Participants: {}", session.participants.join(", "));
+                println!("Comments:     {} ({} open)", comments.len(), open);
+                println!("Chat lines:   {}", chat.len());
+                if !details.description.is_empty() {
+                    println!();
+                    println!("{}", details.description);
+                }
+            }
+        }
+        SessionCommand::State { session_id, state: None, .. } => {
+            let session = storage
+                .load_session(&session_id)?
+                .ok_or_else(|| format!("session {} not found", session_id))?;
+            println!("{}", session.state);
+        }
+        SessionCommand::State { session_id, state: Some(state), publish } => {
+            let mut session = owned_session(storage, node, &session_id)?;
+            if !session.state.can_become(state) {
+                return Err(format!("{} is {} and cannot become {}", session_id, session.state, state).into());
+            }
+            if state == SessionState::Approved {
+                let waiting: Vec<String> = reviewers(storage, repo, &session_id)?
+                    .into_iter()
+                    .filter(|owned| !owned.approved)
+                    .map(|owned| format!("{} ({})", owned.file, owned.owners.join(", ")))
+                    .collect();
+                if !waiting.is_empty() {
+                    return Err(format!("{} still needs an owner's approval for {}", session_id, waiting.join(", ")).into());
+                }
+            }
+            session.state = state;
+            session::reissue(&mut session, node);
+            storage.save_session(&session)?;
+            publish_with(storage, node, &session.id, &publish, |network| session::publish(network, node, &session)).await?;
+        }
+        SessionCommand::Reviewers { session_id } => {
+            for owned in reviewers(storage, repo, &session_id)? {
+                let status = if owned.approved { "approved" } else { "waiting " };
+                println!("{}  {}  {}", status, owned.file, owned.owners.join(", "));
+            }
+        }
+        SessionCommand::Edit { session_id, title, description, labels, remove_labels, issues, remove_issues, publish } => {
+            let mut session = owned_session(storage, node, &session_id)?;
+            let details = &mut session.details;
+            if let Some(title) = title {
+                session.title = title;
+            }
+            if let Some(description) = description {
+                details.description = description;
+            }
+            details.labels.retain(|l| !remove_labels.contains(l));
+            for label in labels {
+                if !details.labels.contains(&label) {
+                    details.labels.push(label);
+                }
+            }
+            details.issues.retain(|i| !remove_issues.contains(i));
+            for issue in issues {
+                if !details.issues.contains(&issue) {
+                    details.issues.push(issue);
+                }
+            }
+            session::reissue(&mut session, node);
+            storage.save_session(&session)?;
+            publish_with(storage, node, &session.id, &publish, |network| session::publish(network, node, &session)).await?;
+        }
+        SessionCommand::Invite { session_id, expires_in_hours } => {
+            let session = owned_session(storage, node, &session_id)?;
+            let expires_at = Utc::now() + chrono::Duration::hours(expires_in_hours);
+            println!("{}", access::create_invite(&node.keypair, &session.id, expires_at));
+        }
+        SessionCommand::Join { token, timeout, peers } => {
+            let invite = access::parse_invite(&token)?;
+            let mut join = JoinRequest {
+                session_id: invite.session_id.clone(),
+                proof: access::prove_invite(&token, &node.peer_id())?,
+                author: node.identity.name.clone(),
+                author_id: node.identity.peer_id.clone(),
+                created_at: Utc::now(),
+                signature: String::new(),
+            };
+            node.sign(&mut join);
+            let session = request_admission(storage, node, &join, &invite.owner.to_base58(), &peers, Duration::from_secs(timeout)).await?;
+            println!("Joined {}: {}", session.id, session.title);
+        }
+        SessionCommand::Members { session_id } => {
+            let session = storage
+                .load_session(&session_id)?
+                .ok_or_else(|| format!("session {} not found", session_id))?;
+            println!("owner    {}", session.owner);
+            for member in session.members.iter().filter(|m| **m != session.owner) {
+                println!("member   {}", member);
+            }
+            for peer in &session.revoked {
+                println!("revoked  {}", peer);
+            }
+        }
+        SessionCommand::Revoke { session_id, peer_id, publish } => {
+            let mut session = owned_session(storage, node, &session_id)?;
+            if peer_id == session.owner {
+                return Err("the owner cannot be revoked".into());
+            }
+            session.members.retain(|m| *m != peer_id);
+            if !session.revoked.contains(&peer_id) {
+                session.revoked.push(peer_id);
+            }
+            session::reissue(&mut session, node);
+            storage.save_session(&session)?;
+            keys::rotate(storage, &session.id)?;
+            let grants = keys::grants(storage, node, &session)?;
+            publish_with(storage, node, &session.id, &publish, |network| {
+                // The remaining members need the new key to open the session.
+                for grant in &grants {
+                    network.publish_key_grant(grant)?;
+                }
+                session::publish(network, node, &session)
+            })
+            .await?;
+        }
+        SessionCommand::Sync { session_id } => {
+            let ids = match session_id {
+                Some(id) => vec![id],
+                None => git_store::session_ids(storage, repo)?,
+            };
+            for id in ids {
+                let status = if git_store::sync(storage, repo, &id)? { "updated" } else { "up to date" };
+                println!("{}  {}", id, status);
+            }
+        }
+        SessionCommand::Export { session_id, out } => {
+            archive::export(storage, &session_id, &out)?;
+        }
+        SessionCommand::Import { archive } => {
+            let id = archive::import(storage, &archive)?;
+            println!("Imported {}", id);
+        }
+    }
+    Ok(())
+}
+
+/// A file with owners, and whether one of them has approved it.
+struct OwnedFile {
+    file: String,
+    owners: Vec<String>,
+    approved: bool,
+}
+
+/// Each file of the session's shared diff that the rules on its target
+/// branch give owners. Only a target branch without a rules file means no
+/// owners; a session or diff that can't be found is an error, so approving
+/// can't skip the check.
+fn reviewers(storage: &Storage, repo: &str, session_id: &str) -> Result<Vec<OwnedFile>, Box<dyn Error>> {
+    let session = storage
+        .load_session(session_id)?
+        .ok_or_else(|| format!("session {} not found", session_id))?;
+    let diff = storage
+        .load_diff(session_id)?
+        .ok_or_else(|| format!("session {} has no shared diff; open it for review with --target-branch first", session_id))?;
+    let Some(rules) = owners::load(repo, &diff.target_branch)? else {
+        return Ok(vec![]);
+    };
+    let verdicts = session::member_verdicts(&session, &storage.load_verdicts(session_id)?);
+    let waiting: Vec<&str> = rules.awaiting_approval(&diff.hunks, &verdicts).into_iter().map(|(file, _)| file).collect();
+    Ok(rules
+        .required_reviewers(&diff.hunks)
+        .into_iter()
+        .map(|(file, owners)| OwnedFile { file: file.to_string(), owners: owners.to_vec(), approved: !waiting.contains(&file) })
+        .collect())
+}
+
+fn owned_session(storage: &Storage, node: &NodeIdentity, session_id: &str) -> Result<ReviewSession, Box<dyn Error>> {
+    let session = storage
+        .load_session(session_id)?
+        .ok_or_else(|| format!("session {} not found", session_id))?;
+    if session.owner != node.identity.peer_id {
+        return Err(format!("only the owner of {} can change it", session_id).into());
+    }
+    Ok(session)
+}
+
+/// Repeats the join request until `owner` admits this node, sends it the
+/// session key and the sealed session, or `timeout` elapses. Returns the
+/// saved session.
+async fn request_admission(
+    storage: &Storage,
+    node: &NodeIdentity,
+    join: &JoinRequest,
+    owner: &str,
+    peers: &[Multiaddr],
+    timeout: Duration,
+) -> Result<ReviewSession, Box<dyn Error>> {
+    let mut network = NetworkManager::new(node, &short_lived_config(peers)?)?;
+    let deadline = Instant::now() + timeout;
+    let mut admitted = false;
+    network.wait_for_peers(timeout).await;
+    while Instant::now() < deadline {
+        network.publish_join(join)?;
+        network.run_for(Duration::from_secs(2)).await;
+        for message in network.poll_messages() {
+            match message {
+                MeshMessage::Membership(membership) if membership.session_id == join.session_id && membership.owner == owner => {
+                    admitted |= membership.members.contains(&join.author_id);
+                }
+                // The session itself is sealed, so it only arrives once a key has.
+                MeshMessage::KeyGrant(grant) if grant.session_id == join.session_id => {
+                    if let Some(key) = keys::accept(storage, node, owner, &grant)? {
+                        network.add_session_key(&grant.session_id, grant.epoch, key);
+                    }
+                }
+                MeshMessage::Session(session)
+                    if session.id == join.session_id && session.owner == owner && session.members.contains(&join.author_id) =>
+                {
+                    storage.save_session(&session)?;
+                    return Ok(session);
+                }
+                _ => {}
+            }
+        }
+    }
+    if admitted {
+        Err("admitted, but no session key arrived; join again while the owner has the session open".into())
+    } else {
+        Err("the session owner did not admit us; they must have the session open".into())
+    }
+}
+
+/// A session this node created or joined. Commands never create sessions:
+/// only the owner's review does, and everyone else joins with an invite.
+fn joined_session(storage: &Storage, session_id: &str) -> Result<ReviewSession, Box<dyn Error>> {
+    Ok(storage
+        .load_session(session_id)?
+        .ok_or_else(|| format!("session {} not found; join it first", session_id))?)
+}
+
+/// Publishes through a short-lived mesh node once a peer is listening,
+/// sealing review content with the keys of `session_id`.
+async fn publish_with<F>(storage: &Storage, node: &NodeIdentity, session_id: &str, args: &PublishArgs, publish: F) -> Result<(), Box<dyn Error>>
+where
+    F: FnOnce(&mut NetworkManager) -> Result<(), Box<dyn Error>>,
+{
+    if !args.publish {
+        return Ok(());
+    }
+    let mut network = NetworkManager::new(node, &short_lived_config(&args.peers)?)?;
+    keys::install(storage, session_id, &mut network)?;
+    if network.wait_for_peers(Duration::from_secs(args.peer_timeout)).await == 0 {
+        eprintln!("No peers found; saved locally only");
+        return Ok(());
+    }
+    publish(&mut network)?;
+    network.run_for(Duration::from_secs(1)).await;
+    Ok(())
+}
+
+/// Fetches the reviewed commits from the session owner and imports them as
+/// `review/<session>/<branch>` in `repo`.
+pub async fn fetch(storage: &Storage, node: &NodeIdentity, repo: &str, session_id: &str, peers: &[Multiaddr], timeout: u64) -> Result<(), Box<dyn Error>> {
+    let session = joined_session(storage, session_id)?;
+    let owner: PeerId = session.owner.parse()?;
+    if owner == node.peer_id() {
+        return Err("you own this session; its branches are already in your repository".into());
+    }
+    let timeout = Duration::from_secs(timeout);
+    let mut network = NetworkManager::new(node, &short_lived_config(peers)?)?;
+    network.wait_for_peers(timeout).await;
+    let data = network.fetch_bundle(&owner, &session.id, timeout).await?.into_bytes()?;
+
+    let path = std::env::temp_dir().join(format!("reviewmesh-{}.bundle", Uuid::new_v4()));
+    std::fs::write(&path, data)?;
+    let imported = bundle::import_bundle(repo, &path, &format!("review/{}", session.id));
+    std::fs::remove_file(&path)?;
+    for branch in imported? {
+        println!("Fetched {}", branch);
+    }
+    Ok(())
+}
+
+/// Writes the bundle of the commit `diff` was taken at, naming it `branch`,
+/// for a member who asked. Used by the owner's open review.
+pub fn bundle_response(repo: &str, diff: &SharedDiff, branch: &str) -> BundleResponse {
+    let path = std::env::temp_dir().join(format!("reviewmesh-{}.bundle", Uuid::new_v4()));
+    let data = bundle::create_bundle(repo, &diff.head_commit, branch, &diff.target_branch, &path).and_then(|_| Ok(std::fs::read(&path)?));
+    let _ = std::fs::remove_file(&path);
+    match data {
+        Ok(data) => BundleResponse::from_bytes(&data),
+        Err(e) => BundleResponse::Refused(format!("could not bundle the review: {}", e)),
+    }
+}
+
+/// The saved network settings with `peers` dialed as well. One-off commands
+/// listen on a random port so they don't collide with a review open on the
+/// configured one.
+fn short_lived_config(peers: &[Multiaddr]) -> Result<NetworkConfig, Box<dyn Error>> {
+    let mut config = NetworkConfig::load(&identity::config_dir())?;
+    config.bootstrap.extend(peers.iter().cloned());
+    config.listen.clear();
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::testing::TempDir;
+    use common::SessionDetails;
+    use network::signing::{verify, Verification};
+
+    fn stored_session(dir: &TempDir) -> (Storage, NodeIdentity) {
+        let node = NodeIdentity::load(dir.path(), "alice".to_string(), None).unwrap();
+        let storage = Storage::new(dir.join("reviews.db").to_str().unwrap()).unwrap();
+        let mut session = ReviewSession {
+            id: "s1".to_string(),
+            title: "Login".to_string(),
+            created_at: Utc::now(),
+            participants: vec!["alice".to_string()],
+            owner: node.identity.peer_id.clone(),
+            members: vec![node.identity.peer_id.clone()],
+            revoked: vec![],
+            revision: 1,
+            signature: String::new(),
+            state: SessionState::Open,
+            details: SessionDetails::default(),
+        };
+        node.sign(&mut session);
+        storage.save_session(&session).unwrap();
+        (storage, node)
+    }
+
+    fn local() -> PublishArgs {
+        PublishArgs { publish: false, peer_timeout: 0, peers: vec![] }
+    }
+
+    #[tokio::test]
+    async fn comments_are_signed_by_this_node_on_stored_sessions_only() {
+        let dir = TempDir::new("comment-add");
+        let (storage, node) = stored_session(&dir);
+        let add = |session_id: &str| CommentCommand::Add {
+            session_id: session_id.to_string(),
+            body: "Check the token expiry".to_string(),
+            file: "src/login.rs".to_string(),
+            line: 12,
+            old: false,
+            target_branch: None,
+            publish: local(),
+        };
+
+        let missing = comment(&storage, &node, "", add("s2")).await.unwrap_err();
+        assert_eq!(missing.to_string(), "session s2 not found; join it first");
+        assert!(storage.load_session("s2").unwrap().is_none());
+
+        comment(&storage, &node, "", add("s1")).await.unwrap();
+        let comments = storage.load_comments("s1").unwrap();
+        assert_eq!(comments.len(), 1);
+        assert_eq!(comments[0].author, "alice");
+        assert_eq!(verify(&comments[0]), Verification::Verified);
+    }
+
+    #[tokio::test]
+    async fn chat_is_signed_by_this_node_on_stored_sessions_only() {
+        let dir = TempDir::new("chat-send");
+        let (storage, node) = stored_session(&dir);
+        let send = |session_id: &str| ChatCommand::Send {
+            session_id: session_id.to_string(),
+            body: "Pushed a fix".to_string(),
+            publish: local(),
+        };
+
+        let missing = chat(&storage, &node, send("s2")).await.unwrap_err();
+        assert_eq!(missing.to_string(), "session s2 not found; join it first");
+        assert!(storage.load_session("s2").unwrap().is_none());
+
+        chat(&storage, &node, send("s1")).await.unwrap();
+        let lines = storage.load_chat("s1").unwrap();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].author, "alice");
+        assert_eq!(verify(&lines[0]), Verification::Verified);
+    }
+
+    #[tokio::test]
+    async fn sessions_without_a_shared_diff_cannot_be_approved() {
+        let dir = TempDir::new("approve");
+        let (storage, node) = stored_session(&dir);
+        let approve = SessionCommand::State { session_id: "s1".to_string(), state: Some(SessionState::Approved), publish: local() };
+
+        let error = session(&storage, &node, "", approve).await.unwrap_err();
+        assert!(error.to_string().starts_with("session s1 has no shared diff"));
+        assert_eq!(storage.load_session("s1").unwrap().unwrap().state, SessionState::Open);
+    }
+}
@@ -0,0 +1,167 @@
+use std::error::Error;
+use std::io;
+use std::time::Duration;
+
+use crossterm::event::{self, Event, KeyCode};
+use tui::backend::{Backend, CrosstermBackend};
+use tui::layout::{Constraint, Direction, Layout};
+use tui::style::{Color, Modifier, Style};
+use tui::text::{Span, Spans};
+use tui::widgets::{List, ListItem, Paragraph};
+use tui::{Frame, Terminal};
+
+use common::{MeshMessage, Membership, SessionState};
+use network::NetworkManager;
+use storage::{SessionSummary, Storage};
+
+use crate::{inner_height, keep_visible, pane_block, step, TerminalGuard};
+
+/// One line of `cli sessions`.
+pub fn summary_line(summary: &SessionSummary) -> String {
+    let s = &summary.session;
+    format!(
+        "{}  {}  {}  {}  {} open, {} resolved  {}  {}",
+        s.id,
+        s.state,
+        summary.last_activity.format("%Y-%m-%d %H:%M"),
+        summary.target_branch.as_deref().unwrap_or("-"),
+        summary.open_comments,
+        summary.resolved_comments,
+        s.title,
+        s.participants.join(", "),
+    )
+}
+
+struct Dashboard<'a> {
+    storage: &'a Storage,
+    summaries: Vec<SessionSummary>,
+    /// Memberships of sessions announced on the mesh that aren't in
+    /// storage, newest revision of each. The sessions themselves are sealed.
+    discovered: Vec<Membership>,
+    network: NetworkManager,
+    /// Whether archived sessions are listed.
+    all: bool,
+    selected: usize,
+    scroll: usize,
+    height: usize,
+    notice: Option<String>,
+}
+
+impl Dashboard<'_> {
+    fn len(&self) -> usize {
+        self.summaries.len() + self.discovered.len()
+    }
+
+    /// Reloads storage, to pick up sessions joined or changed by another
+    /// command, and takes in session announcements from the mesh.
+    fn refresh(&mut self) -> Result<(), Box<dyn Error>> {
+        self.summaries = self.storage.session_summaries()?;
+        for message in self.network.poll_messages() {
+            let MeshMessage::Membership(membership) = message else { continue };
+            match self.discovered.iter_mut().find(|m| m.session_id == membership.session_id) {
+                Some(known) if known.revision >= membership.revision => {}
+                Some(known) => *known = membership,
+                None => self.discovered.push(membership),
+            }
+        }
+        let summaries = &self.summaries;
+        self.discovered.retain(|d| !summaries.iter().any(|s| s.session.id == d.session_id));
+        let all = self.all;
+        self.summaries.retain(|s| all || s.session.state != SessionState::Archived);
+        self.selected = self.selected.min(self.len().saturating_sub(1));
+        Ok(())
+    }
+
+    fn select(&mut self, down: bool) {
+        self.selected = step(self.selected, self.len(), down);
+        self.scroll = keep_visible(self.selected, self.scroll, self.height);
+        self.notice = None;
+    }
+
+    fn ui(&mut self, f: &mut Frame<impl Backend>) {
+        let screen = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Min(0), Constraint::Length(1)].as_ref())
+            .split(f.size());
+        self.height = inner_height(screen[0]);
+
+        let discovered = self.discovered.iter().map(|m| {
+            let text = format!("{}  on the mesh, not joined  owner {}  {} members", m.session_id, m.owner, m.members.len());
+            (text, Style::default().fg(Color::DarkGray))
+        });
+        let rows: Vec<ListItem> = self
+            .summaries
+            .iter()
+            .map(|s| (summary_line(s), Style::default()))
+            .chain(discovered)
+            .enumerate()
+            .skip(self.scroll)
+            .take(self.height)
+            .map(|(i, (text, mut style))| {
+                if i == self.selected {
+                    style = style.add_modifier(Modifier::REVERSED);
+                }
+                ListItem::new(Spans::from(Span::styled(text, style)))
+            })
+            .collect();
+        let title = format!("Sessions ({} local, {} on the mesh)", self.summaries.len(), self.discovered.len());
+        f.render_widget(List::new(rows).block(pane_block(&title, true)), screen[0]);
+
+        let status = self
+            .notice
+            .clone()
+            .unwrap_or_else(|| "Enter: open | Up/Down: select | Esc: quit".to_string());
+        f.render_widget(Paragraph::new(status), screen[1]);
+    }
+}
+
+/// Lets the user pick a session, listing those in storage and those being
+/// announced on the mesh, archived ones only when `all` is set. Returns the
+/// chosen session, or `None` on Esc.
+pub fn pick(storage: &Storage, network: NetworkManager, all: bool) -> Result<Option<SessionSummary>, Box<dyn Error>> {
+    let mut dashboard = Dashboard {
+        storage,
+        summaries: vec![],
+        discovered: vec![],
+        network,
+        all,
+        selected: 0,
+        scroll: 0,
+        height: 0,
+        notice: None,
+    };
+    dashboard.refresh()?;
+
+    let _guard = TerminalGuard::enter(false)?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(io::stdout()))?;
+    run(&mut terminal, &mut dashboard)
+}
+
+fn run(terminal: &mut Terminal<impl Backend>, dashboard: &mut Dashboard) -> Result<Option<SessionSummary>, Box<dyn Error>> {
+    let tick_rate = Duration::from_millis(250);
+    loop {
+        terminal.draw(|f| dashboard.ui(f))?;
+        if event::poll(tick_rate)? {
+            if let Event::Key(key) = event::read()? {
+                match key.code {
+                    KeyCode::Up => dashboard.select(false),
+                    KeyCode::Down => dashboard.select(true),
+                    KeyCode::Enter => {
+                        if let Some(summary) = dashboard.summaries.get(dashboard.selected) {
+                            return Ok(Some(summary.clone()));
+                        }
+                        if let Some(membership) = dashboard.discovered.get(dashboard.selected - dashboard.summaries.len()) {
+                            dashboard.notice = Some(format!(
+                                "Not a member of {}: ask its owner for an invite and run `cli session join <token>`",
+                                membership.session_id
+                            ));
+                        }
+                    }
+                    KeyCode::Esc | KeyCode::Char('q') => return Ok(None),
+                    _ => {}
+                }
+            }
+        }
+        dashboard.refresh()?;
+    }
+}
@@ -0,0 +1,596 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io::Write;
+use std::path::Path;
+
+use clap::ValueEnum;
+
+use common::{latest_verdicts, ChatLine, Comment, DiffHunk, LineKind, ReviewSession, Side, Signable, Verdict};
+use git_integration::compute_diff;
+use network::signing::{self, Verification};
+use storage::Storage;
+
+mod pdf;
+
+pub use pdf::PdfExporter;
+
+/// Everything an exporter needs to render one session.
+pub struct Review {
+    pub session: ReviewSession,
+    pub hunks: Vec<DiffHunk>,
+    pub comments: Vec<Comment>,
+    pub chat: Vec<ChatLine>,
+    pub verdicts: Vec<Verdict>,
+}
+
+impl Review {
+    pub fn load(storage: &Storage, repo: &str, session_id: &str, target_branch: Option<&str>) -> Result<Self, Box<dyn Error>> {
+        let session = storage
+            .load_session(session_id)?
+            .ok_or_else(|| format!("session {} not found", session_id))?;
+        // Without a branch, fall back to the diff the owner shared.
+        let hunks = match target_branch {
+            Some(branch) => compute_diff(repo, branch)?,
+            None => storage.load_diff(session_id)?.map(|d| d.hunks).unwrap_or_default(),
+        };
+        Ok(Self {
+            session,
+            hunks,
+            comments: storage.load_comments(session_id)?,
+            chat: storage.load_chat(session_id)?,
+            verdicts: storage.load_verdicts(session_id)?,
+        })
+    }
+
+    /// The session's state and whichever of its author, branches, labels and
+    /// linked issues are known, as label and value.
+    pub fn details(&self) -> Vec<(&'static str, String)> {
+        let details = &self.session.details;
+        let mut rows = vec![("State", self.session.state.to_string())];
+        if !details.author.is_empty() {
+            rows.push(("Author", details.author.clone()));
+        }
+        if let Some(branches) = details.branches() {
+            rows.push(("Branches", branches));
+        }
+        if !details.labels.is_empty() {
+            rows.push(("Labels", details.labels.join(", ")));
+        }
+        if !details.issues.is_empty() {
+            rows.push(("Issues", details.issues.join(", ")));
+        }
+        rows
+    }
+
+    /// Hunks grouped by file, in diff order.
+    pub fn files(&self) -> Vec<(&str, Vec<&DiffHunk>)> {
+        let mut files: Vec<(&str, Vec<&DiffHunk>)> = vec![];
+        for hunk in &self.hunks {
+            match files.last_mut() {
+                Some((file, hunks)) if *file == hunk.file => hunks.push(hunk),
+                _ => files.push((&hunk.file, vec![hunk])),
+            }
+        }
+        files
+    }
+
+    /// Comments on `hunk` keyed by the line they are anchored to. Renderers
+    /// take each thread out as they reach its line and print the rest after
+    /// the hunk.
+    pub fn threads(&self, hunk: &DiffHunk) -> BTreeMap<(Side, usize), Vec<&Comment>> {
+        let mut threads: BTreeMap<(Side, usize), Vec<&Comment>> = BTreeMap::new();
+        for comment in self.comments.iter().filter(|c| c.hunk_id == hunk.id) {
+            threads.entry(comment.anchor()).or_default().push(comment);
+        }
+        threads
+    }
+
+    /// Open and resolved thread counts. A thread is every comment on the same
+    /// line and is resolved once all of its comments are.
+    pub fn thread_counts(&self) -> (usize, usize) {
+        let mut threads: BTreeMap<(&str, &str, (Side, usize)), bool> = BTreeMap::new();
+        for c in &self.comments {
+            let resolved = threads.entry((&c.hunk_id, &c.file, c.anchor())).or_insert(true);
+            *resolved &= c.resolved;
+        }
+        let resolved = threads.values().filter(|r| **r).count();
+        (threads.len() - resolved, resolved)
+    }
+
+    pub fn comments_by_author(&self) -> BTreeMap<&str, usize> {
+        let mut authors = BTreeMap::new();
+        for c in &self.comments {
+            *authors.entry(c.author.as_str()).or_default() += 1;
+        }
+        authors
+    }
+
+    /// Each reviewer's current verdict.
+    pub fn verdicts(&self) -> Vec<&Verdict> {
+        latest_verdicts(&self.verdicts)
+    }
+
+    /// Checked at export time rather than trusted from storage, so entries
+    /// edited in the database after they were received show up as invalid.
+    pub fn verification(&self, record: &dyn Signable) -> Verification {
+        signing::verify(record)
+    }
+
+    /// Comments that don't belong to any hunk in the exported diff.
+    pub fn general_comments(&self) -> Vec<&Comment> {
+        self.comments
+            .iter()
+            .filter(|c| !self.hunks.iter().any(|h| h.id == c.hunk_id))
+            .collect()
+    }
+}
+
+pub trait Exporter {
+    fn export(&self, review: &Review, out: &mut dyn Write) -> Result<(), Box<dyn Error>>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ExportFormat {
+    Pdf,
+    Markdown,
+    Html,
+    Json,
+}
+
+impl ExportFormat {
+    pub fn from_path(path: &str) -> Option<Self> {
+        let ext = Path::new(path).extension()?.to_str()?.to_ascii_lowercase();
+        match ext.as_str() {
+            "pdf" => Some(Self::Pdf),
+            "md" | "markdown" => Some(Self::Markdown),
+            "html" | "htm" => Some(Self::Html),
+            "json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+
+    pub fn exporter(self) -> Box<dyn Exporter> {
+        match self {
+            Self::Pdf => Box::new(PdfExporter),
+            Self::Markdown => Box::new(MarkdownExporter),
+            Self::Html => Box::new(HtmlExporter),
+            Self::Json => Box::new(JsonExporter),
+        }
+    }
+}
+
+pub struct MarkdownExporter;
+
+impl Exporter for MarkdownExporter {
+    fn export(&self, review: &Review, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+        let session = &review.session;
+        writeln!(out, "# {}", session.title)?;
+        writeln!(out)?;
+        writeln!(out, "- Session: `{}`", session.id)?;
+        writeln!(out, "- Created: {}", session.created_at.to_rfc3339())?;
+        writeln!(out, "- Participants: {}", session.participants.join(", "))?;
+        for (label, value) in review.details() {
+            writeln!(out, "- {}: {}", label, value)?;
+        }
+        for verdict in review.verdicts() {
+            write!(out, "- **{}** {} ({})", verdict.author, verdict.decision, review.verification(verdict))?;
+            if verdict.body.is_empty() {
+                writeln!(out)?;
+            } else {
+                writeln!(out, ": {}", verdict.body)?;
+            }
+        }
+        if !session.details.description.is_empty() {
+            writeln!(out)?;
+            writeln!(out, "{}", session.details.description)?;
+        }
+
+        for (file, hunks) in review.files() {
+            writeln!(out)?;
+            writeln!(out, "## {}", file)?;
+            for hunk in hunks {
+                let mut threads = review.threads(hunk);
+                let fence = fence(&hunk.content);
+                writeln!(out)?;
+                writeln!(out, "{}diff", fence)?;
+                writeln!(out, "@@ -{},{} +{},{} @@", hunk.old_start, hunk.old_lines, hunk.new_start, hunk.new_lines)?;
+                for line in hunk.lines() {
+                    writeln!(out, "{}{}", marker(line.kind), line.text)?;
+                    if let Some(thread) = threads.remove(&line.anchor()) {
+                        // Close the block so the thread renders as prose, then reopen it.
+                        writeln!(out, "{}", fence)?;
+                        writeln!(out)?;
+                        write_markdown_thread(out, review, line.anchor(), &thread)?;
+                        writeln!(out)?;
+                        writeln!(out, "{}diff", fence)?;
+                    }
+                }
+                writeln!(out, "{}", fence)?;
+                for (line, thread) in threads {
+                    writeln!(out)?;
+                    write_markdown_thread(out, review, line, &thread)?;
+                }
+            }
+        }
+
+        let general = review.general_comments();
+        if !general.is_empty() {
+            writeln!(out)?;
+            writeln!(out, "## General comments")?;
+            writeln!(out)?;
+            for comment in general {
+                writeln!(
+                    out,
+                    "- **{}** on `{}:{}` ({}, {}): {}",
+                    comment.author,
+                    comment.file,
+                    comment.line,
+                    timestamp(comment),
+                    review.verification(comment),
+                    comment.body
+                )?;
+            }
+        }
+
+        if !review.chat.is_empty() {
+            writeln!(out)?;
+            writeln!(out, "## Chat")?;
+            writeln!(out)?;
+            for chat in &review.chat {
+                writeln!(
+                    out,
+                    "- {} **{}** ({}): {}",
+                    chat.created_at.format("%Y-%m-%d %H:%M"),
+                    chat.author,
+                    review.verification(chat),
+                    chat.body
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A code fence longer than any run of backticks in `content`, so the diff
+/// can't close its own block.
+fn fence(content: &str) -> String {
+    let longest = content.split(|c| c != '`').map(str::len).max().unwrap_or(0);
+    "`".repeat(longest.max(2) + 1)
+}
+
+fn write_markdown_thread(out: &mut dyn Write, review: &Review, (side, line): (Side, usize), thread: &[&Comment]) -> std::io::Result<()> {
+    let removed = if side == Side::Old { "removed " } else { "" };
+    for comment in thread {
+        let state = if comment.resolved { ", resolved" } else { "" };
+        writeln!(
+            out,
+            "> **{}** on {}line {}, {}, {}{}",
+            comment.author,
+            removed,
+            line,
+            timestamp(comment),
+            review.verification(*comment),
+            state
+        )?;
+        writeln!(out, ">")?;
+        for body_line in comment.body.lines() {
+            writeln!(out, "> {}", body_line)?;
+        }
+    }
+    Ok(())
+}
+
+pub struct HtmlExporter;
+
+const HTML_STYLE: &str = r#"
+body { font-family: sans-serif; max-width: 960px; margin: 2em auto; color: #24292f; }
+h2 { font-family: monospace; border-bottom: 1px solid #d0d7de; padding-bottom: .3em; }
+table.diff { width: 100%; border-collapse: collapse; font-family: monospace; font-size: 13px; margin-bottom: 1em; }
+table.diff td { padding: 0 .5em; white-space: pre-wrap; vertical-align: top; }
+td.num { color: #6e7781; text-align: right; width: 3em; user-select: none; }
+tr.hunk td { background: #ddf4ff; color: #57606a; }
+tr.add td { background: #e6ffec; }
+tr.del td { background: #ffebe9; }
+tr.thread td { background: #f6f8fa; font-family: sans-serif; padding: .5em 1em; }
+.comment { border-left: 3px solid #0969da; padding: .2em .8em; margin: .4em 0; white-space: pre-wrap; }
+.comment.resolved { border-color: #8c959f; color: #57606a; }
+.meta { font-size: 12px; color: #57606a; }
+.description { white-space: pre-wrap; }
+.sig { font-size: 11px; border-radius: 3px; padding: 0 .3em; }
+.sig.verified { background: #dafbe1; color: #1a7f37; }
+.sig.unsigned { background: #eaeef2; color: #57606a; }
+.sig.invalid { background: #ffebe9; color: #cf222e; font-weight: bold; }
+"#;
+
+impl Exporter for HtmlExporter {
+    fn export(&self, review: &Review, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+        let session = &review.session;
+        writeln!(out, "<!DOCTYPE html>")?;
+        writeln!(out, "<html><head><meta charset=\"utf-8\"><title>{}</title>", escape(&session.title))?;
+        writeln!(out, "<style>{}</style></head><body>", HTML_STYLE)?;
+        writeln!(out, "<h1>{}</h1>", escape(&session.title))?;
+        writeln!(
+            out,
+            "<p class=\"meta\">Session <code>{}</code> &middot; created {} &middot; {}</p>",
+            escape(&session.id),
+            session.created_at.format("%Y-%m-%d %H:%M UTC"),
+            escape(&session.participants.join(", "))
+        )?;
+        let details: Vec<String> = review
+            .details()
+            .into_iter()
+            .map(|(label, value)| format!("{}: {}", label, escape(&value)))
+            .collect();
+        writeln!(out, "<p class=\"meta\">{}</p>", details.join(" &middot; "))?;
+        if !session.details.description.is_empty() {
+            writeln!(out, "<p class=\"description\">{}</p>", escape(&session.details.description))?;
+        }
+        let verdicts = review.verdicts();
+        if !verdicts.is_empty() {
+            writeln!(out, "<ul class=\"verdicts\">")?;
+            for verdict in verdicts {
+                writeln!(
+                    out,
+                    "<li><strong>{}</strong> {} {}{}</li>",
+                    escape(&verdict.author),
+                    verdict.decision,
+                    badge(review.verification(verdict)),
+                    if verdict.body.is_empty() { String::new() } else { format!(": {}", escape(&verdict.body)) }
+                )?;
+            }
+            writeln!(out, "</ul>")?;
+        }
+
+        for (file, hunks) in review.files() {
+            writeln!(out, "<h2>{}</h2>", escape(file))?;
+            writeln!(out, "<table class=\"diff\">")?;
+            for hunk in hunks {
+                let mut threads = review.threads(hunk);
+                writeln!(
+                    out,
+                    "<tr class=\"hunk\"><td class=\"num\"></td><td class=\"num\"></td><td>@@ -{},{} +{},{} @@</td></tr>",
+                    hunk.old_start, hunk.old_lines, hunk.new_start, hunk.new_lines
+                )?;
+                for line in hunk.lines() {
+                    let class = match line.kind {
+                        LineKind::Added => "add",
+                        LineKind::Removed => "del",
+                        LineKind::Context => "ctx",
+                    };
+                    writeln!(
+                        out,
+                        "<tr class=\"{}\"><td class=\"num\">{}</td><td class=\"num\">{}</td><td>{}{}</td></tr>",
+                        class,
+                        line.old_line.map(|n| n.to_string()).unwrap_or_default(),
+                        line.new_line.map(|n| n.to_string()).unwrap_or_default(),
+                        marker(line.kind),
+                        escape(line.text)
+                    )?;
+                    if let Some(thread) = threads.remove(&line.anchor()) {
+                        write_html_thread(out, review, &thread)?;
+                    }
+                }
+                for thread in threads.values() {
+                    write_html_thread(out, review, thread)?;
+                }
+            }
+            writeln!(out, "</table>")?;
+        }
+
+        let general = review.general_comments();
+        if !general.is_empty() {
+            writeln!(out, "<h2>General comments</h2>")?;
+            for comment in general {
+                write_html_comment(out, review, comment)?;
+            }
+        }
+
+        if !review.chat.is_empty() {
+            writeln!(out, "<h2>Chat</h2>")?;
+            for chat in &review.chat {
+                writeln!(
+                    out,
+                    "<p><span class=\"meta\">{}</span> <strong>{}</strong> {}: {}</p>",
+                    chat.created_at.format("%Y-%m-%d %H:%M"),
+                    escape(&chat.author),
+                    badge(review.verification(chat)),
+                    escape(&chat.body)
+                )?;
+            }
+        }
+        writeln!(out, "</body></html>")?;
+        Ok(())
+    }
+}
+
+fn write_html_thread(out: &mut dyn Write, review: &Review, thread: &[&Comment]) -> std::io::Result<()> {
+    writeln!(out, "<tr class=\"thread\"><td colspan=\"3\">")?;
+    for comment in thread {
+        write_html_comment(out, review, comment)?;
+    }
+    writeln!(out, "</td></tr>")
+}
+
+fn write_html_comment(out: &mut dyn Write, review: &Review, comment: &Comment) -> std::io::Result<()> {
+    let class = if comment.resolved { "comment resolved" } else { "comment" };
+    writeln!(
+        out,
+        "<div class=\"{}\"><div class=\"meta\"><strong>{}</strong> on {}:{} &middot; {} {}{}</div>{}</div>",
+        class,
+        escape(&comment.author),
+        escape(&comment.file),
+        comment.line,
+        timestamp(comment),
+        badge(review.verification(comment)),
+        if comment.resolved { " &middot; resolved" } else { "" },
+        escape(&comment.body)
+    )
+}
+
+fn badge(verification: Verification) -> String {
+    let class = match verification {
+        Verification::Verified => "verified",
+        Verification::Unsigned => "unsigned",
+        Verification::Invalid => "invalid",
+    };
+    format!("<span class=\"sig {}\">{}</span>", class, verification)
+}
+
+pub struct JsonExporter;
+
+impl Exporter for JsonExporter {
+    fn export(&self, review: &Review, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+        let mut verification = serde_json::Map::new();
+        let records = review
+            .comments
+            .iter()
+            .map(|c| (&c.id, c as &dyn Signable))
+            .chain(review.chat.iter().map(|c| (&c.id, c as &dyn Signable)))
+            .chain(review.verdicts.iter().map(|v| (&v.id, v as &dyn Signable)));
+        for (id, record) in records {
+            verification.insert(id.clone(), review.verification(record).to_string().into());
+        }
+        let value = serde_json::json!({
+            "session": review.session,
+            "hunks": review.hunks,
+            "comments": review.comments,
+            "chat": review.chat,
+            "verdicts": review.verdicts,
+            "verification": verification,
+        });
+        serde_json::to_writer_pretty(&mut *out, &value)?;
+        writeln!(out)?;
+        Ok(())
+    }
+}
+
+fn marker(kind: LineKind) -> char {
+    match kind {
+        LineKind::Added => '+',
+        LineKind::Removed => '-',
+        LineKind::Context => ' ',
+    }
+}
+
+fn timestamp(comment: &Comment) -> String {
+    comment.created_at.format("%Y-%m-%d %H:%M").to_string()
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+    use common::{SessionDetails, SessionState, Side};
+
+    pub(super) fn review() -> Review {
+        let created_at = Utc.timestamp_opt(1_600_000_000, 0).unwrap();
+        let hunk = DiffHunk {
+            id: "h1".to_string(),
+            file: "src/main.rs".to_string(),
+            old_start: 1,
+            old_lines: 1,
+            new_start: 1,
+            new_lines: 1,
+            content: "@@ -1,1 +1,1 @@\n-old <line>\n+new <line>\n".to_string(),
+        };
+        let comment = Comment {
+            id: "c1".to_string(),
+            session_id: "s".to_string(),
+            author: "alice".to_string(),
+            author_id: String::new(),
+            file: "src/main.rs".to_string(),
+            hunk_id: "h1".to_string(),
+            line: 1,
+            side: Side::New,
+            body: "Why & how?".to_string(),
+            created_at,
+            resolved: false,
+            resolved_at: None,
+            signature: String::new(),
+        };
+        Review {
+            session: ReviewSession {
+                id: "s".to_string(),
+                title: "Review".to_string(),
+                created_at,
+                participants: vec!["alice".to_string()],
+                owner: String::new(),
+                members: vec![],
+                revoked: vec![],
+                revision: 0,
+                signature: String::new(),
+                state: SessionState::Open,
+                details: SessionDetails::default(),
+            },
+            hunks: vec![hunk],
+            comments: vec![comment],
+            chat: vec![],
+            verdicts: vec![],
+        }
+    }
+
+    #[test]
+    fn format_from_extension() {
+        assert_eq!(ExportFormat::from_path("out/review.PDF"), Some(ExportFormat::Pdf));
+        assert_eq!(ExportFormat::from_path("review.md"), Some(ExportFormat::Markdown));
+        assert_eq!(ExportFormat::from_path("review.htm"), Some(ExportFormat::Html));
+        assert_eq!(ExportFormat::from_path("review.json"), Some(ExportFormat::Json));
+        assert_eq!(ExportFormat::from_path("review"), None);
+    }
+
+    #[test]
+    fn html_inlines_escaped_thread_once() {
+        let mut out = vec![];
+        HtmlExporter.export(&review(), &mut out).unwrap();
+        let html = String::from_utf8(out).unwrap();
+        assert!(html.contains("+new &lt;line&gt;"));
+        assert_eq!(html.matches("Why &amp; how?").count(), 1);
+        assert!(html.contains("<span class=\"sig unsigned\">unsigned</span>"));
+        assert!(!html.contains("General comments"));
+    }
+    #[test]
+    fn removed_and_added_lines_keep_their_own_threads() {
+        let mut review = review();
+        let mut removed = review.comments[0].clone();
+        removed.id = "c2".to_string();
+        removed.side = Side::Old;
+        removed.body = "Still needed?".to_string();
+        review.comments.push(removed);
+        assert_eq!(review.threads(&review.hunks[0]).len(), 2);
+        assert_eq!(review.thread_counts(), (2, 0));
+
+        let mut out = vec![];
+        MarkdownExporter.export(&review, &mut out).unwrap();
+        let markdown = String::from_utf8(out).unwrap();
+        let at = |text: &str| markdown.find(text).unwrap();
+        assert!(at("-old <line>") < at("Still needed?") && at("Still needed?") < at("+new <line>"));
+        assert!(at("+new <line>") < at("Why & how?"));
+        assert!(markdown.contains("on removed line 1"));
+    }
+
+    #[test]
+    fn markdown_fences_outlast_backticks_in_the_diff() {
+        let mut review = review();
+        review.hunks[0].content = "@@ -1,1 +1,1 @@\n-/// ```\n+/// ````rust\n".to_string();
+        let mut out = vec![];
+        MarkdownExporter.export(&review, &mut out).unwrap();
+        let markdown = String::from_utf8(out).unwrap();
+        assert!(markdown.contains("\n`````diff\n@@ -1,1 +1,1 @@\n-/// ```\n+/// ````rust\n"));
+        assert_eq!(fence("no backticks"), "```");
+    }
+}
@@ -0,0 +1,292 @@
+use std::cell::{Cell, RefCell};
+use std::error::Error;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use genpdf::{elements, fonts, render, style, Alignment, Element, Margins};
+
+use common::{Comment, DiffHunk, LineKind};
+use network::signing::Verification;
+
+use super::{marker, timestamp, Exporter, Review};
+
+const BODY_SIZE: u8 = 10;
+const CODE_SIZE: u8 = 8;
+
+const SANS_REGULAR: &[u8] = include_bytes!("../../../public/fonts/DejaVuSans-Regular.ttf");
+const SANS_BOLD: &[u8] = include_bytes!("../../../public/fonts/DejaVuSans-Bold.ttf");
+const MONO_REGULAR: &[u8] = include_bytes!("../../../public/fonts/DejaVuSansMono-Regular.ttf");
+const MONO_BOLD: &[u8] = include_bytes!("../../../public/fonts/DejaVuSansMono-Bold.ttf");
+
+pub struct PdfExporter;
+
+impl Exporter for PdfExporter {
+    fn export(&self, review: &Review, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+        let body = bundled_family(SANS_REGULAR, SANS_BOLD)?;
+        let mono = bundled_family(MONO_REGULAR, MONO_BOLD)?;
+
+        // genpdf can't refer forward, so lay the document out once to learn
+        // which page each file starts on, then again with those pages filled
+        // into the contents.
+        let pages = Rc::new(RefCell::new(vec![0; review.files().len()]));
+        let doc = build(review, body.clone(), mono.clone(), None, &pages);
+        doc.render(io::sink())?;
+        let toc = pages.borrow().clone();
+        let doc = build(review, body, mono, Some(&toc), &pages);
+        doc.render(out)?;
+        Ok(())
+    }
+}
+
+/// Exports never use italics, so only the upright faces are embedded and
+/// stand in for their italic counterparts.
+fn bundled_family(regular: &[u8], bold: &[u8]) -> Result<fonts::FontFamily<fonts::FontData>, String> {
+    let load = |data: &[u8]| fonts::FontData::new(data.to_vec(), None).map_err(|e| format!("failed to load bundled font: {}", e));
+    let regular = load(regular)?;
+    let bold = load(bold)?;
+    Ok(fonts::FontFamily {
+        italic: regular.clone(),
+        bold_italic: bold.clone(),
+        regular,
+        bold,
+    })
+}
+
+fn build(
+    review: &Review,
+    body: fonts::FontFamily<fonts::FontData>,
+    mono: fonts::FontFamily<fonts::FontData>,
+    toc: Option<&[usize]>,
+    pages: &Rc<RefCell<Vec<usize>>>,
+) -> genpdf::Document {
+    let session = &review.session;
+    let mut doc = genpdf::Document::new(body);
+    let mono = doc.add_font_family(mono);
+    doc.set_title(format!("ReviewMesh Export: {}", session.title));
+    doc.set_font_size(BODY_SIZE);
+
+    let page = Rc::new(Cell::new(0));
+    let mut decorator = genpdf::SimplePageDecorator::new();
+    decorator.set_margins(10);
+    let title = session.title.clone();
+    decorator.set_header(move |page| {
+        elements::Paragraph::new(format!("{} - page {}", title, page))
+            .aligned(Alignment::Right)
+            .styled(style::Style::new().with_font_size(8).with_color(grey()))
+    });
+    doc.set_page_decorator(PageCounter { inner: decorator, page: page.clone() });
+
+    doc.push(elements::Paragraph::new(format!("ReviewMesh Export: {}", session.title)).styled(style::Style::new().bold().with_font_size(18)));
+    doc.push(elements::Paragraph::new(format!("Session {} - created {}", session.id, session.created_at.format("%Y-%m-%d %H:%M UTC"))));
+    doc.push(elements::Paragraph::new(format!("Participants: {}", session.participants.join(", "))));
+    for (label, value) in review.details() {
+        doc.push(elements::Paragraph::new(format!("{}: {}", label, value)));
+    }
+    for paragraph in session.details.description.lines() {
+        doc.push(elements::Paragraph::new(paragraph));
+    }
+    doc.push(elements::Break::new(1));
+
+    push_summary(&mut doc, review);
+
+    let files = review.files();
+    doc.push(heading("Contents"));
+    for (index, (file, hunks)) in files.iter().enumerate() {
+        let comments: usize = hunks.iter().map(|h| review.comments.iter().filter(|c| c.hunk_id == h.id).count()).sum();
+        let page = toc.map(|t| t[index].to_string()).unwrap_or_default();
+        let mut table = elements::TableLayout::new(vec![8, 1]);
+        table
+            .row()
+            .element(elements::Paragraph::new(format!("{}. {} ({} hunks, {} comments)", index + 1, file, hunks.len(), comments)))
+            .element(elements::Paragraph::new(page).aligned(Alignment::Right))
+            .push()
+            .expect("contents row has one cell per column");
+        doc.push(table);
+    }
+
+    for (index, (file, hunks)) in files.iter().enumerate() {
+        doc.push(elements::PageBreak::new());
+        doc.push(heading(&format!("{}. {}", index + 1, file)));
+        doc.push(PageMarker { page: page.clone(), pages: pages.clone(), index });
+        for hunk in hunks {
+            push_hunk(&mut doc, review, hunk, mono);
+        }
+    }
+
+    let general = review.general_comments();
+    if !general.is_empty() {
+        doc.push(elements::Break::new(1));
+        doc.push(heading("General comments"));
+        for comment in general {
+            doc.push(comment_block(review, comment));
+        }
+    }
+
+    if !review.chat.is_empty() {
+        doc.push(elements::Break::new(1));
+        doc.push(heading("Chat"));
+        for chat in &review.chat {
+            let mut line = elements::Paragraph::default();
+            line.push_styled(format!("{} ", chat.created_at.format("%Y-%m-%d %H:%M")), style::Style::new().with_color(grey()));
+            line.push_styled(format!("{} ", chat.author), style::Style::new().bold());
+            line.push_styled(format!("({}) ", review.verification(chat)), signature_style(review.verification(chat)));
+            line.push(chat.body.clone());
+            doc.push(line);
+        }
+    }
+    doc
+}
+
+fn push_summary(doc: &mut genpdf::Document, review: &Review) {
+    let (open, resolved) = review.thread_counts();
+    doc.push(heading("Summary"));
+    doc.push(elements::Paragraph::new(format!(
+        "{} files, {} hunks, {} comments in {} threads, {} chat messages",
+        review.files().len(),
+        review.hunks.len(),
+        review.comments.len(),
+        open + resolved,
+        review.chat.len()
+    )));
+    let status = if open == 0 {
+        "All threads resolved".to_string()
+    } else {
+        format!("{} open thread{} need attention, {} resolved", open, if open == 1 { "" } else { "s" }, resolved)
+    };
+    doc.push(elements::Paragraph::new(status).styled(style::Style::new().bold()));
+    for verdict in review.verdicts() {
+        let mut line = elements::Paragraph::default();
+        line.push_styled(format!("{} {} ", verdict.author, verdict.decision), style::Style::new().bold());
+        line.push_styled(format!("({})", review.verification(verdict)), signature_style(review.verification(verdict)));
+        if !verdict.body.is_empty() {
+            line.push(format!(": {}", verdict.body));
+        }
+        doc.push(line);
+    }
+    for (author, count) in review.comments_by_author() {
+        doc.push(elements::Paragraph::new(format!("{}: {} comment{}", author, count, if count == 1 { "" } else { "s" })));
+    }
+    doc.push(elements::Break::new(1));
+}
+
+fn push_hunk(doc: &mut genpdf::Document, review: &Review, hunk: &DiffHunk, mono: fonts::FontFamily<fonts::Font>) {
+    let code = style::Style::new().with_font_family(mono).with_font_size(CODE_SIZE);
+    let mut threads = review.threads(hunk);
+    doc.push(elements::Break::new(0.5));
+    doc.push(
+        elements::Paragraph::new(format!("@@ -{},{} +{},{} @@", hunk.old_start, hunk.old_lines, hunk.new_start, hunk.new_lines))
+            .styled(code.with_color(grey())),
+    );
+    for line in hunk.lines() {
+        let color = match line.kind {
+            LineKind::Added => style::Color::Rgb(0, 120, 40),
+            LineKind::Removed => style::Color::Rgb(180, 20, 20),
+            LineKind::Context => style::Color::Greyscale(40),
+        };
+        let number = line.new_line.or(line.old_line).map(|n| n.to_string()).unwrap_or_default();
+        doc.push(
+            elements::Paragraph::new(format!("{:>5} {}{}", number, marker(line.kind), line.text))
+                .styled(code.with_color(color)),
+        );
+        if let Some(thread) = threads.remove(&line.anchor()) {
+            for comment in thread {
+                doc.push(comment_block(review, comment));
+            }
+        }
+    }
+    for comment in threads.into_values().flatten() {
+        doc.push(comment_block(review, comment));
+    }
+}
+
+fn comment_block(review: &Review, comment: &Comment) -> impl Element {
+    let meta = style::Style::new().bold().with_font_size(8);
+    let verification = review.verification(comment);
+    let mut header = elements::Paragraph::default();
+    header.push_styled(
+        format!("{} - {}:{} - {} - ", comment.author, comment.file, comment.line, timestamp(comment)),
+        meta.with_color(grey()),
+    );
+    header.push_styled(verification.to_string(), signature_style(verification).bold().with_font_size(8));
+    if comment.resolved {
+        header.push_styled(" - resolved", meta.with_color(grey()));
+    }
+    let mut layout = elements::LinearLayout::vertical();
+    layout.push(header);
+    layout.push(elements::Paragraph::new(comment.body.clone()));
+    layout.padded(Margins::trbl(1, 2, 1, 2)).framed().padded(Margins::trbl(1, 0, 1, 12))
+}
+
+fn heading(text: &str) -> impl Element {
+    elements::Paragraph::new(text).styled(style::Style::new().bold().with_font_size(14))
+}
+
+/// Invalid signatures are the entries a reader must not miss.
+fn signature_style(verification: Verification) -> style::Style {
+    match verification {
+        Verification::Verified => style::Style::new().with_color(style::Color::Rgb(0, 120, 40)),
+        Verification::Unsigned => style::Style::new().with_color(grey()),
+        Verification::Invalid => style::Style::new().bold().with_color(style::Color::Rgb(180, 20, 20)),
+    }
+}
+
+fn grey() -> style::Color {
+    style::Color::Greyscale(110)
+}
+
+/// Wraps the real decorator to keep track of the page being laid out.
+struct PageCounter {
+    inner: genpdf::SimplePageDecorator,
+    page: Rc<Cell<usize>>,
+}
+
+impl genpdf::PageDecorator for PageCounter {
+    fn decorate_page<'a>(
+        &mut self,
+        context: &genpdf::Context,
+        area: render::Area<'a>,
+        style: style::Style,
+    ) -> Result<render::Area<'a>, genpdf::error::Error> {
+        self.page.set(self.page.get() + 1);
+        self.inner.decorate_page(context, area, style)
+    }
+}
+
+/// Zero-sized element recording the page it lands on.
+struct PageMarker {
+    page: Rc<Cell<usize>>,
+    pages: Rc<RefCell<Vec<usize>>>,
+    index: usize,
+}
+
+impl Element for PageMarker {
+    fn render(
+        &mut self,
+        _context: &genpdf::Context,
+        _area: render::Area<'_>,
+        _style: style::Style,
+    ) -> Result<genpdf::RenderResult, genpdf::error::Error> {
+        self.pages.borrow_mut()[self.index] = self.page.get();
+        Ok(genpdf::RenderResult::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::export::tests::review;
+
+    #[test]
+    fn two_file_review_renders_to_a_pdf() {
+        let mut review = review();
+        let mut hunk = review.hunks[0].clone();
+        hunk.id = "h2".to_string();
+        hunk.file = "src/lib.rs".to_string();
+        review.hunks.push(hunk);
+        assert_eq!(review.files().len(), 2);
+
+        let mut out = vec![];
+        PdfExporter.export(&review, &mut out).unwrap();
+        assert!(out.starts_with(b"%PDF"));
+    }
+}
@@ -0,0 +1,67 @@
+use std::error::Error;
+
+use common::SessionRecords;
+use git_integration::refs::{self, ReviewFiles};
+use network::signing;
+use storage::Storage;
+
+const SESSION_FILE: &str = "session.json";
+const DIFF_FILE: &str = "diff.json";
+const COMMENTS_FILE: &str = "comments.json";
+const CHAT_FILE: &str = "chat.json";
+const VERDICTS_FILE: &str = "verdicts.json";
+
+/// Merges the copy of a session kept under `refs/reviewmesh/` into the
+/// database, then writes the merged session back to the ref. Returns whether
+/// the ref changed.
+pub fn sync(storage: &Storage, repo: &str, session_id: &str) -> Result<bool, Box<dyn Error>> {
+    if let Some(files) = refs::read_review(repo, session_id)? {
+        // Anyone who can push to the repository can write to the ref.
+        if let Some(records) = from_files(&files)? {
+            let known = storage.load_session(session_id)?;
+            if let Some(records) = signing::verify_records(records, known.as_ref()) {
+                storage.merge_records(&records)?;
+            }
+        }
+    }
+    let Some(records) = storage.load_records(session_id)? else {
+        return Ok(false);
+    };
+    Ok(refs::write_review(repo, session_id, &to_files(&records)?)?)
+}
+
+/// Every session in either the database or the repository.
+pub fn session_ids(storage: &Storage, repo: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut ids: Vec<String> = storage.list_sessions()?.into_iter().map(|s| s.id).collect();
+    for id in refs::list_reviews(repo)? {
+        if !ids.contains(&id) {
+            ids.push(id);
+        }
+    }
+    Ok(ids)
+}
+
+fn to_files(records: &SessionRecords) -> Result<ReviewFiles, Box<dyn Error>> {
+    let mut files = vec![(SESSION_FILE, serde_json::to_vec_pretty(&records.session)?)];
+    if let Some(diff) = &records.diff {
+        files.push((DIFF_FILE, serde_json::to_vec_pretty(diff)?));
+    }
+    files.push((COMMENTS_FILE, serde_json::to_vec_pretty(&records.comments)?));
+    files.push((CHAT_FILE, serde_json::to_vec_pretty(&records.chat)?));
+    files.push((VERDICTS_FILE, serde_json::to_vec_pretty(&records.verdicts)?));
+    Ok(files.into_iter().map(|(name, content)| (name.to_string(), content)).collect())
+}
+
+fn from_files(files: &[(String, Vec<u8>)]) -> Result<Option<SessionRecords>, Box<dyn Error>> {
+    let file = |name: &str| files.iter().find(|(n, _)| n == name).map(|(_, content)| content.as_slice());
+    let Some(session) = file(SESSION_FILE) else {
+        return Ok(None);
+    };
+    Ok(Some(SessionRecords {
+        session: serde_json::from_slice(session)?,
+        diff: file(DIFF_FILE).map(serde_json::from_slice).transpose()?,
+        comments: file(COMMENTS_FILE).map(serde_json::from_slice).transpose()?.unwrap_or_default(),
+        chat: file(CHAT_FILE).map(serde_json::from_slice).transpose()?.unwrap_or_default(),
+        verdicts: file(VERDICTS_FILE).map(serde_json::from_slice).transpose()?.unwrap_or_default(),
+    }))
+}
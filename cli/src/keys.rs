@@ -0,0 +1,79 @@
+use std::error::Error;
+
+use chrono::Utc;
+
+use common::{KeyGrant, ReviewSession};
+use network::encryption::{self, SessionKey};
+use network::identity::NodeIdentity;
+use network::NetworkManager;
+use storage::Storage;
+
+/// Starts a new key epoch for a session. The owner does this when creating
+/// the session and whenever a member is revoked, so removed members can't
+/// read anything published afterwards.
+pub fn rotate(storage: &Storage, session_id: &str) -> Result<(u64, SessionKey), Box<dyn Error>> {
+    let epoch = newest(storage, session_id)?.map_or(1, |(epoch, _)| epoch + 1);
+    let key = encryption::new_session_key();
+    storage.save_session_key(session_id, epoch, &key)?;
+    Ok((epoch, key))
+}
+
+pub fn newest(storage: &Storage, session_id: &str) -> Result<Option<(u64, SessionKey)>, Box<dyn Error>> {
+    Ok(load(storage, session_id)?.pop())
+}
+
+/// Hands every stored key of a session to the network so it can seal and
+/// open that session's messages.
+pub fn install(storage: &Storage, session_id: &str, network: &mut NetworkManager) -> Result<(), Box<dyn Error>> {
+    for (epoch, key) in load(storage, session_id)? {
+        network.add_session_key(session_id, epoch, key);
+    }
+    Ok(())
+}
+
+/// The newest session key sealed for each member other than the owner.
+pub fn grants(storage: &Storage, node: &NodeIdentity, session: &ReviewSession) -> Result<Vec<KeyGrant>, Box<dyn Error>> {
+    let Some((epoch, key)) = newest(storage, &session.id)? else {
+        return Ok(vec![]);
+    };
+    let mut grants = vec![];
+    for member in session.members.iter().filter(|m| **m != session.owner) {
+        let Some(wrapped) = encryption::wrap_key(&node.keypair, &member.parse()?, &session.id, epoch, &key) else {
+            continue;
+        };
+        let mut grant = KeyGrant {
+            session_id: session.id.clone(),
+            epoch,
+            recipient: member.clone(),
+            key: wrapped,
+            author_id: node.identity.peer_id.clone(),
+            created_at: Utc::now(),
+            signature: String::new(),
+        };
+        node.sign(&mut grant);
+        grants.push(grant);
+    }
+    Ok(grants)
+}
+
+/// Stores the key in `grant` if it was sealed for this node by `owner`,
+/// returning it for the running network. Callers check the grant is for a
+/// session `owner` owns.
+pub fn accept(storage: &Storage, node: &NodeIdentity, owner: &str, grant: &KeyGrant) -> Result<Option<SessionKey>, Box<dyn Error>> {
+    if grant.author_id != owner || grant.recipient != node.identity.peer_id {
+        return Ok(None);
+    }
+    let key = encryption::unwrap_key(&node.keypair, &owner.parse()?, &grant.session_id, grant.epoch, &grant.key);
+    if let Some(key) = key {
+        storage.save_session_key(&grant.session_id, grant.epoch, &key)?;
+    }
+    Ok(key)
+}
+
+fn load(storage: &Storage, session_id: &str) -> Result<Vec<(u64, SessionKey)>, Box<dyn Error>> {
+    Ok(storage
+        .load_session_keys(session_id)?
+        .into_iter()
+        .filter_map(|(epoch, key)| Some((epoch, key.try_into().ok()?)))
+        .collect())
+}
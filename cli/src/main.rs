@@ -1,33 +1,50 @@
 use clap::{self, Parser, Subcommand};
 use crossterm::{
-    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode},
+    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyModifiers, MouseButton, MouseEvent, MouseEventKind},
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
-use std::{io, time::{Duration, Instant}};
+use std::{fs, io::{self, Write}, path::{Path, PathBuf}, sync::mpsc::{self, Receiver, Sender}, time::{Duration, Instant}};
 use tui::{
     backend::{Backend, CrosstermBackend},
-    layout::{Constraint, Direction, Layout},
+    layout::{Constraint, Direction, Layout, Rect},
     style::{Color, Modifier, Style},
     text::{Span, Spans},
     widgets::{Block, Borders, List, ListItem, Paragraph},
     Frame, Terminal,
 };
 use uuid::Uuid;
-use whoami;
-use genpdf::{self, elements, style, Element};
-use futures::StreamExt;
-use serde_json;
 use chrono::Utc;
 
-use common::{ReviewSession, Comment, ChatLine, DiffHunk};
-use storage::Storage;
-use network::NetworkManager;
-use git_integration::compute_diff;
+use common::{latest_verdicts, ReviewSession, Comment, ChatLine, Decision, DiffHunk, JoinRequest, LineKind, MeshMessage, Presence, SessionState, SharedDiff, Side, Verdict};
+use storage::{HitRecord, SearchFilter, Storage};
+use network::{access, config::NetworkConfig, identity::NodeIdentity, transfer::{BundleResponse, IncomingBundleRequest}, NetworkManager};
+use git_integration::owners::{self, OwnerRules};
+use git_integration::{common_dir, compute_diff, git_user, repo_identity};
+
+mod archive;
+mod commands;
+mod dashboard;
+mod export;
+mod git_store;
+mod keys;
+mod presence;
+mod session;
+
+use commands::{ChatCommand, CommentCommand, IdentityCommand, PeerCommand, SessionCommand};
+use export::{ExportFormat, Review};
+use presence::Roster;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
+    /// Database to use instead of the repository's .git/reviewmesh/review_mesh.db
+    #[arg(long, global = true, value_name = "PATH")]
+    db: Option<PathBuf>,
+    /// Repository to review: any directory inside a working tree, a linked
+    /// worktree or a bare repository
+    #[arg(long, global = true, value_name = "PATH", default_value = ".")]
+    repo: String,
     #[command(subcommand)]
     command: Commands,
 }
@@ -38,242 +55,1319 @@ enum Commands {
         session_id: String,
         #[arg(short, long)]
         target_branch: Option<String>,
+        /// Leave the mouse to the terminal so text can be selected natively
+        #[arg(long)]
+        no_mouse: bool,
+        /// Merge the session with refs/reviewmesh/ in the repository on open
+        /// and exit
+        #[arg(long)]
+        sync_git: bool,
+        #[command(flatten)]
+        network: commands::NetworkArgs,
     },
+    /// Export a session as PDF, Markdown, HTML or JSON
     Export {
         session_id: String,
         file_path: String,
+        /// Defaults to the format matching the file extension
+        #[arg(short, long, value_enum)]
+        format: Option<ExportFormat>,
+        /// Branch to diff against so the export includes the hunks
+        #[arg(short, long)]
+        target_branch: Option<String>,
+    },
+    /// Add or list comments without opening the TUI
+    Comment {
+        #[command(subcommand)]
+        command: CommentCommand,
+    },
+    /// Send chat messages without opening the TUI
+    Chat {
+        #[command(subcommand)]
+        command: ChatCommand,
+    },
+    /// Approve a session or request changes
+    Verdict {
+        session_id: String,
+        /// approve or request-changes
+        decision: Decision,
+        #[arg(short, long, default_value = "")]
+        message: String,
+        #[command(flatten)]
+        publish: commands::PublishArgs,
+    },
+    /// Mark a comment as resolved
+    Resolve {
+        comment_id: String,
+        /// Mark the comment as unresolved again instead
+        #[arg(long)]
+        reopen: bool,
+        #[command(flatten)]
+        publish: commands::PublishArgs,
+    },
+    /// List sessions with their activity, or pick one to open with -i
+    Sessions {
+        /// Choose a session in a dashboard that also shows sessions
+        /// announced on the mesh, then open it for review
+        #[arg(short, long)]
+        interactive: bool,
+        #[arg(long, conflicts_with = "interactive")]
+        json: bool,
+        /// Include archived sessions
+        #[arg(long)]
+        all: bool,
+        #[command(flatten)]
+        network: commands::NetworkArgs,
+    },
+    /// Search comments and chat across sessions
+    Search(commands::SearchArgs),
+    /// Inspect review sessions
+    Session {
+        #[command(subcommand)]
+        command: SessionCommand,
+    },
+    /// Show or change the name and email peers see
+    Identity {
+        #[command(subcommand)]
+        command: IdentityCommand,
+    },
+    /// Import the reviewed branches from the session owner as review/<session>/<branch>
+    Fetch {
+        session_id: String,
+        /// Seconds to wait for the owner to send the commits
+        #[arg(long, default_value_t = 60)]
+        timeout: u64,
+        /// Dial this peer or relay as well as the configured ones
+        #[arg(long = "peer", value_name = "MULTIADDR")]
+        peers: Vec<network::Multiaddr>,
+    },
+    /// Manage the peers and relays dialed on startup
+    Peer {
+        #[command(subcommand)]
+        command: PeerCommand,
     },
+    /// Run a relay that reviewers outside the LAN can meet through. It keeps
+    /// its own key and network.json under the config directory's relay/
+    /// folder, and listens on /ip4/0.0.0.0/tcp/4001 unless told otherwise
+    Relay {
+        #[command(flatten)]
+        network: commands::NetworkArgs,
+    },
+}
+
+const SCROLL_STEP: usize = 3;
+const MIN_SPLIT: u16 = 20;
+const MAX_SPLIT: u16 = 80;
+/// How often presence is resent when nothing changes.
+const HEARTBEAT: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Pane {
+    Diff,
+    Comments,
 }
 
+/// One rendered line of the diff pane.
+struct DiffRow {
+    hunk: usize,
+    /// `None` for the per-hunk header row.
+    line: Option<usize>,
+    /// The file `line` counts in.
+    side: Side,
+    kind: Option<LineKind>,
+    text: String,
+}
+
+/// A member's bundle request with the bundle written for it.
+type BundleReply = (IncomingBundleRequest, BundleResponse);
+
 struct App {
     storage: Storage,
+    /// Path inside the repository under review, as given by `--repo`.
+    repo: String,
+    node: NodeIdentity,
     session: ReviewSession,
     hunks: Vec<DiffHunk>,
+    /// The owner's copy of the diff, which `hunks` mirrors when present.
+    shared_diff: Option<SharedDiff>,
     comments: Vec<Comment>,
     chat_history: Vec<ChatLine>,
+    verdicts: Vec<Verdict>,
     network: NetworkManager,
+    rows: Vec<DiffRow>,
+    selected_row: usize,
+    diff_scroll: usize,
+    selected_comment: usize,
+    comment_scroll: usize,
+    focus: Pane,
+    /// Width of the diff pane as a percentage of the screen.
+    split: u16,
+    dragging_split: bool,
+    diff_area: Rect,
+    comments_area: Rect,
+    /// Subscribed peers at the last tick, to notice newcomers.
+    peer_count: usize,
+    /// Who else is here and whether we drive or follow a walkthrough.
+    roster: Roster,
+    /// What we last announced and when, so heartbeats go out on change.
+    last_presence: Option<(Presence, Instant)>,
+    /// A `/search` narrowing the comments pane: the query and the ids of
+    /// matching comments, best first.
+    search: Option<(String, Vec<String>)>,
+    /// Who must review which files, from the target branch's rules file.
+    owners: Option<OwnerRules>,
+    /// The last publish that failed, shown in the status line.
+    notice: Option<String>,
+    /// Bundles written off the UI thread come back here to be sent.
+    bundles: (Sender<BundleReply>, Receiver<BundleReply>),
 }
 
 impl App {
-    fn new(storage: Storage, session_id: String, target_branch: Option<String>) -> Self {
-        let session = storage.get_session(&session_id).unwrap().unwrap_or_else(|| {
-            let new_session = ReviewSession {
-                id: session_id.clone(),
-                title: format!("Review for {}", session_id),
-                created_at: Utc::now(),
-                participants: vec![whoami::username()],
-            };
-            storage.save_session(&new_session).unwrap();
-            new_session
-        });
+    fn new(
+        storage: Storage,
+        repo: String,
+        session_id: String,
+        target_branch: Option<String>,
+        node: &NodeIdentity,
+        config: &NetworkConfig,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut session = session::load_or_create(&storage, &session_id, node)?;
 
-        let hunks = if let Some(branch) = target_branch {
-            compute_diff(".", &branch)
-        } else {
-            vec![]
+        // Prefer the owner's shared diff so hunk ids match across reviewers,
+        // falling back to our own checkout when none has arrived yet.
+        let shared_diff = session::load_or_share_diff(&storage, &repo, &mut session, node, target_branch.as_deref())?;
+        let merged = session::detect_merged(&storage, &repo, node, &mut session)?;
+        // Rules come from the branch being merged into; a reviewer without
+        // that branch just doesn't see owners.
+        let owners = shared_diff
+            .as_ref()
+            .map(|d| d.target_branch.clone())
+            .or_else(|| target_branch.clone())
+            .and_then(|branch| owners::load(&repo, &branch).ok().flatten());
+        let hunks = match (&shared_diff, target_branch) {
+            (Some(diff), _) => diff.hunks.clone(),
+            (None, Some(branch)) => compute_diff(&repo, &branch)?,
+            (None, None) => vec![],
         };
 
-        let comments = storage.get_comments(&session_id).unwrap();
-        let chat_history = storage.get_chat_history(&session_id).unwrap();
-        let network = NetworkManager::new(None).unwrap();
+        let comments = storage.load_comments(&session_id)?;
+        let chat_history = storage.load_chat(&session_id)?;
+        let verdicts = storage.load_verdicts(&session_id)?;
+        let mut network = NetworkManager::new(node, config)?;
+        keys::install(&storage, &session_id, &mut network)?;
+        let rows = diff_rows(&hunks);
 
-        Self {
+        let mut app = Self {
             storage,
+            repo,
+            node: node.clone(),
             session,
             hunks,
+            shared_diff,
             comments,
             chat_history,
+            verdicts,
             network,
+            rows,
+            selected_row: 0,
+            diff_scroll: 0,
+            selected_comment: 0,
+            comment_scroll: 0,
+            focus: Pane::Diff,
+            split: 50,
+            dragging_split: false,
+            diff_area: Rect::default(),
+            comments_area: Rect::default(),
+            peer_count: 0,
+            roster: Roster::default(),
+            last_presence: None,
+            search: None,
+            owners,
+            notice: None,
+            bundles: mpsc::channel(),
+        };
+        // Peers arriving later get it with the rest of the membership.
+        if merged {
+            app.publish_membership();
+        }
+        Ok(app)
+    }
+
+    fn on_tick(&mut self, typing: bool) {
+        for message in self.network.poll_messages() {
+            self.apply(message);
+        }
+        self.reload_session();
+        self.roster.expire(Instant::now());
+        self.follow_driver();
+        self.announce_presence(typing);
+        for request in self.network.bundle_requests() {
+            match self.bundle_for(&request.peer.to_base58(), &request.session_id) {
+                // Writing a bundle can take a while on a big repository.
+                Ok((diff, branch)) => {
+                    let (repo, done) = (self.repo.clone(), self.bundles.0.clone());
+                    std::thread::spawn(move || {
+                        let _ = done.send((request, commands::bundle_response(&repo, &diff, &branch)));
+                    });
+                }
+                Err(reason) => self.network.respond_bundle(request, BundleResponse::Refused(reason)),
+            }
+        }
+        while let Ok((request, response)) = self.bundles.1.try_recv() {
+            self.network.respond_bundle(request, response);
+        }
+        // Peers that were offline when the owner last published missed the
+        // current session and key, so send them again when someone arrives.
+        let peer_count = self.network.subscribed_peers().len();
+        if peer_count > self.peer_count && self.is_owner() {
+            self.publish_membership();
+        }
+        self.peer_count = peer_count;
+    }
+
+    /// Sends a heartbeat when the cursor or typing state changed, or when
+    /// the last one is about to be forgotten.
+    fn announce_presence(&mut self, typing: bool) {
+        let (file, line) = match self.rows.get(self.selected_row) {
+            Some(row) => (Some(self.hunks[row.hunk].file.clone()), row.line),
+            None => (None, None),
+        };
+        let unchanged = self.last_presence.as_ref().is_some_and(|(last, sent)| {
+            last.file == file
+                && last.line == line
+                && last.typing == typing
+                && last.driving == self.roster.driving()
+                && sent.elapsed() < HEARTBEAT
+        });
+        if unchanged {
+            return;
+        }
+        let mut presence = Presence {
+            session_id: self.session.id.clone(),
+            author: self.node.identity.name.clone(),
+            author_id: self.node.identity.peer_id.clone(),
+            file,
+            line,
+            typing,
+            driving: self.roster.driving(),
+            sent_at: Utc::now(),
+            signature: String::new(),
+        };
+        self.node.sign(&mut presence);
+        let published = self.network.publish_presence(&presence);
+        self.report(published);
+        self.last_presence = Some((presence, Instant::now()));
+    }
+
+    /// Moves our selection to where the driver is, while following them.
+    fn follow_driver(&mut self) {
+        let Some((file, line)) = self.roster.follow_target() else {
+            return;
+        };
+        let rows = self.rows.iter().map(|r| (self.hunks[r.hunk].file.as_str(), r.line));
+        if let Some(row) = presence::follow_row(rows, &file, line) {
+            self.selected_row = row;
+            self.diff_scroll = keep_visible(row, self.diff_scroll, inner_height(self.diff_area));
+        }
+    }
+
+    /// Breaks away from the driver, or goes back to following them.
+    fn toggle_following(&mut self) {
+        self.roster.toggle_following();
+        self.follow_driver();
+    }
+
+    /// What to bundle for `cli fetch` from a member: the shared diff and the
+    /// branch to name its commit, or why they can't have it.
+    fn bundle_for(&self, peer_id: &str, session_id: &str) -> Result<(SharedDiff, String), String> {
+        if session_id != self.session.id || !self.session.is_member(peer_id) {
+            return Err("not a member of this session".to_string());
+        }
+        match &self.shared_diff {
+            Some(diff) if self.is_owner() && !diff.head_commit.is_empty() => {
+                let branch = Some(&self.session.details.source_branch).filter(|b| !b.is_empty());
+                Ok((diff.clone(), branch.map_or("review", |b| b.as_str()).to_string()))
+            }
+            _ => Err("the owner has not opened this session against a branch".to_string()),
+        }
+    }
+
+    fn is_owner(&self) -> bool {
+        self.session.owner == self.node.identity.peer_id
+    }
+
+    /// Picks up a newer revision of the session saved outside this review,
+    /// such as a `session revoke` in another terminal, with any keys it
+    /// rotated. Our next reissue then builds on it instead of undoing it.
+    fn reload_session(&mut self) {
+        let Some(stored) = self.stored(self.storage.load_session(&self.session.id)).flatten() else {
+            return;
+        };
+        if stored.revision <= self.session.revision || stored.owner != self.session.owner {
+            return;
+        }
+        self.session = stored;
+        let installed = keys::install(&self.storage, &self.session.id, &mut self.network);
+        self.stored(installed);
+        if self.is_owner() {
+            self.publish_membership();
+        }
+    }
+
+    fn publish_membership(&mut self) {
+        // Archived sessions stay quiet; members already have them.
+        if self.session.state == SessionState::Archived {
+            return;
+        }
+        // Keys go first so new members can open the sealed session.
+        let mut published = Ok(());
+        let grants = self.stored(keys::grants(&self.storage, &self.node, &self.session)).unwrap_or_default();
+        for grant in grants {
+            published = published.and(self.network.publish_key_grant(&grant));
+        }
+        published = published.and(session::publish(&mut self.network, &self.node, &self.session));
+        if let Some(diff) = &self.shared_diff {
+            published = published.and(self.network.publish_diff(diff));
+        }
+        self.report(published);
+    }
+
+    /// Keeps a failed publish for the status line.
+    fn report(&mut self, published: Result<(), Box<dyn std::error::Error>>) {
+        if let Err(e) = published {
+            self.notice = Some(format!("Not sent: {}", e));
+        }
+    }
+
+    /// Keeps a failed database read or write for the status line. The review
+    /// carries on with what it holds in memory.
+    fn stored<T>(&mut self, result: Result<T, impl std::fmt::Display>) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(e) => {
+                self.notice = Some(format!("Not saved: {}", e));
+                None
+            }
+        }
+    }
+
+    fn apply(&mut self, message: MeshMessage) {
+        match message {
+            MeshMessage::Comment(comment) if comment.session_id == self.session.id && self.session.is_member(&comment.author_id) => {
+                match self.comments.iter_mut().find(|c| c.id == comment.id) {
+                    // Only the original author may edit a comment.
+                    Some(existing) if existing.author_id != comment.author_id => return,
+                    Some(existing) => *existing = comment.clone(),
+                    None => self.comments.push(comment.clone()),
+                }
+                self.stored(self.storage.save_comment(&comment));
+            }
+            MeshMessage::Chat(chat) if chat.session_id == self.session.id && self.session.is_member(&chat.author_id) => {
+                self.stored(self.storage.save_chat(&chat));
+                if !self.chat_history.iter().any(|c| c.id == chat.id) {
+                    self.chat_history.push(chat);
+                }
+            }
+            MeshMessage::Resolution(resolution) if resolution.session_id == self.session.id && self.session.is_member(&resolution.author_id) => {
+                let comment = self.comments.iter_mut().find(|c| c.id == resolution.comment_id);
+                // Resolutions can arrive out of order; the latest one counts.
+                if let Some(comment) = comment.filter(|c| c.resolved_at < Some(resolution.created_at)) {
+                    comment.resolved = resolution.resolved;
+                    comment.resolved_at = Some(resolution.created_at);
+                    let saved = self.storage.save_comment(comment);
+                    self.stored(saved);
+                }
+            }
+            MeshMessage::Verdict(verdict) if verdict.session_id == self.session.id && self.session.is_member(&verdict.author_id) => {
+                self.stored(self.storage.save_verdict(&verdict));
+                if !self.verdicts.iter().any(|v| v.id == verdict.id) {
+                    self.verdicts.push(verdict);
+                }
+            }
+            // The network layer has already checked the owner's signature.
+            MeshMessage::Session(session)
+                if session.id == self.session.id
+                    && session.owner == self.session.owner
+                    && session.revision > self.session.revision =>
+            {
+                self.stored(self.storage.save_session(&session));
+                self.session = session;
+            }
+            MeshMessage::Presence(presence)
+                if presence.session_id == self.session.id
+                    && presence.author_id != self.node.identity.peer_id
+                    && self.session.is_member(&presence.author_id) =>
+            {
+                self.roster.record(presence, Instant::now());
+            }
+            MeshMessage::Diff(diff)
+                if diff.session_id == self.session.id
+                    && diff.author_id == self.session.owner
+                    && !self.is_owner()
+                    && self.shared_diff.as_ref().is_none_or(|d| diff.created_at > d.created_at) =>
+            {
+                self.stored(self.storage.save_diff(&diff));
+                self.hunks = diff.hunks.clone();
+                self.rows = diff_rows(&self.hunks);
+                self.selected_row = self.selected_row.min(self.rows.len().saturating_sub(1));
+                self.shared_diff = Some(diff);
+            }
+            MeshMessage::Join(join) if join.session_id == self.session.id && self.is_owner() => {
+                self.admit(join);
+            }
+            MeshMessage::KeyGrant(grant) if grant.session_id == self.session.id => {
+                let accepted = keys::accept(&self.storage, &self.node, &self.session.owner, &grant);
+                if let Some(key) = self.stored(accepted).flatten() {
+                    self.network.add_session_key(&grant.session_id, grant.epoch, key);
+                }
+            }
+            _ => {}
         }
     }
 
-    fn on_tick(&mut self) {
-        // Here we can poll the network for new messages
-        while let Ok(Some(_event)) = self.network.swarm.try_poll_next() {
-            // Process network events
+    /// Adds the sender of a valid invite proof to the members and republishes the
+    /// session and keys, which is also how the joiner receives them.
+    fn admit(&mut self, join: JoinRequest) {
+        self.reload_session();
+        let invited = join
+            .author_id
+            .parse()
+            .ok()
+            .and_then(|joiner| access::check_join(&self.node.keypair, &join.proof, &joiner).ok())
+            .is_some_and(|invite| invite.session_id == self.session.id);
+        if !invited || self.session.revoked.contains(&join.author_id) {
+            return;
+        }
+        if !self.session.members.contains(&join.author_id) {
+            self.session.members.push(join.author_id);
+            if !self.session.participants.contains(&join.author) {
+                self.session.participants.push(join.author);
+            }
+            session::reissue(&mut self.session, &self.node);
+            self.stored(self.storage.save_session(&self.session));
         }
+        self.publish_membership();
     }
 
-    fn ui(&self, f: &mut Frame<impl Backend>, _input: &str) {
+    fn ui(&mut self, f: &mut Frame<impl Backend>, _input: &str) {
+        let screen = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Min(0), Constraint::Length(1)].as_ref())
+            .split(f.size());
         let chunks = Layout::default()
             .direction(Direction::Horizontal)
-            .constraints([Constraint::Percentage(50), Constraint::Percentage(50)].as_ref())
-            .split(f.size());
+            .constraints([Constraint::Percentage(self.split), Constraint::Percentage(100 - self.split)].as_ref())
+            .split(screen[0]);
+        let present = self.roster.present();
+        let side = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Min(0), Constraint::Length(present.len() as u16 + 3)].as_ref())
+            .split(chunks[1]);
+        self.diff_area = chunks[0];
+        self.comments_area = side[0];
 
-        let hunks: Vec<ListItem> = self
-            .hunks
+        let diff_height = inner_height(self.diff_area);
+        let rows: Vec<ListItem> = self
+            .rows
             .iter()
-            .map(|h| ListItem::new(Spans::from(h.content.clone())))
+            .enumerate()
+            .skip(self.diff_scroll)
+            .take(diff_height)
+            .map(|(i, row)| {
+                let mut style = match row.kind {
+                    Some(LineKind::Added) => Style::default().fg(Color::Green),
+                    Some(LineKind::Removed) => Style::default().fg(Color::Red),
+                    Some(LineKind::Context) => Style::default(),
+                    None => Style::default().fg(Color::Cyan).add_modifier(Modifier::BOLD),
+                };
+                if i == self.selected_row {
+                    style = style.add_modifier(Modifier::REVERSED);
+                }
+                let file = &self.hunks[row.hunk].file;
+                let here: Vec<&str> = present
+                    .iter()
+                    .filter(|p| p.file.as_ref() == Some(file) && p.line == row.line)
+                    .map(|p| p.author.as_str())
+                    .collect();
+                let mut spans = vec![Span::styled(row.text.clone(), style)];
+                if row.line.is_none() {
+                    spans.extend(self.owners_span(file));
+                }
+                if !here.is_empty() {
+                    spans.push(Span::styled(format!("  <- {}", here.join(", ")), Style::default().fg(Color::Magenta)));
+                }
+                ListItem::new(Spans::from(spans))
+            })
             .collect();
-        let hunks_list = List::new(hunks)
-            .block(Block::default().borders(Borders::ALL).title("Diff Hunks"))
-            .highlight_style(Style::default().add_modifier(Modifier::BOLD))
-            .highlight_symbol("> ");
-        f.render_widget(hunks_list, chunks[0]);
+        let details = &self.session.details;
+        let mut title = self.session.title.clone();
+        if let Some(branches) = details.branches() {
+            title = format!("{} - {}", title, branches);
+        }
+        let tags: Vec<&str> = details.labels.iter().chain(&details.issues).map(String::as_str).collect();
+        if !tags.is_empty() {
+            title = format!("{} [{}]", title, tags.join(", "));
+        }
+        let hunks_list = List::new(rows)
+            .block(pane_block(&title, self.focus == Pane::Diff));
+        f.render_widget(hunks_list, self.diff_area);
 
+        let comments_height = inner_height(self.comments_area);
         let comments: Vec<ListItem> = self
-            .comments
+            .shown_comments()
+            .into_iter()
+            .enumerate()
+            .skip(self.comment_scroll)
+            .take(comments_height)
+            .map(|(i, c)| {
+                let style = if i == self.selected_comment {
+                    Style::default().add_modifier(Modifier::REVERSED)
+                } else {
+                    Style::default()
+                };
+                ListItem::new(Spans::from(Span::styled(
+                    format!("{}:{} {}: {}", c.file, c.line, c.author, c.body),
+                    style,
+                )))
+            })
+            .collect();
+        let mut title = match &self.search {
+            Some((query, ids)) => format!("Search \"{}\": {} found (/search to clear)", query, ids.len()),
+            None => "Comments".to_string(),
+        };
+        let verdicts: Vec<String> = latest_verdicts(&self.verdicts)
             .iter()
-            .map(|c| ListItem::new(Spans::from(format!("{}: {}", c.author, c.body))))
+            .map(|v| format!("{}: {}", v.author, v.decision))
             .collect();
+        if !verdicts.is_empty() {
+            title = format!("{} - {}", title, verdicts.join(", "));
+        }
         let comments_list = List::new(comments)
-            .block(Block::default().borders(Borders::ALL).title("Comments"));
-        f.render_widget(comments_list, chunks[1]);
+            .block(pane_block(&title, self.focus == Pane::Comments));
+        f.render_widget(comments_list, self.comments_area);
+
+        let mut participants = vec![ListItem::new(format!("{} (you)", self.node.identity.name))];
+        participants.extend(present.iter().map(|p| {
+            let mut text = p.author.clone();
+            if let (Some(file), Some(line)) = (&p.file, p.line) {
+                text = format!("{}  {}:{}", text, file, line);
+            }
+            if p.typing {
+                text.push_str("  typing...");
+            }
+            ListItem::new(Spans::from(Span::styled(text, Style::default().fg(Color::Magenta))))
+        }));
+        f.render_widget(List::new(participants).block(pane_block("Participants", false)), side[1]);
+
+        f.render_widget(Paragraph::new(self.status_line()), screen[1]);
     }
 
-    fn export_to_pdf(&self, file_path: &str) -> Result<(), Box<dyn std::error::Error>> {
-        let font_family = genpdf::fonts::from_files("./fonts", "LiberationSans", None)
-            .expect("Failed to load font family");
-        let mut doc = genpdf::Document::new(font_family);
-        doc.set_title("ReviewMesh Export");
+    /// The owners of `file` for its hunk headers, each with a tick once
+    /// their latest verdict approves.
+    fn owners_span(&self, file: &str) -> Option<Span<'static>> {
+        let owners = self.owners.as_ref()?.owners(file);
+        if owners.is_empty() {
+            return None;
+        }
+        let verdicts = session::member_verdicts(&self.session, &self.verdicts);
+        let approvers = owners::approvers(&verdicts);
+        let names: Vec<String> = owners
+            .iter()
+            .map(|owner| {
+                if approvers.contains(&owner.as_str()) { format!("{} ✓", owner) } else { owner.clone() }
+            })
+            .collect();
+        Some(Span::styled(format!("  owners: {}", names.join(", ")), Style::default().fg(Color::Yellow)))
+    }
 
-        let mut decorator = genpdf::style::Style::new();
-        decorator.set_bold();
+    /// Where peers can reach this node and how many are listening.
+    fn status_line(&self) -> String {
+        let addresses: Vec<String> = self.network.listen_addresses().iter().map(|a| a.to_string()).collect();
+        let listening = if addresses.is_empty() {
+            "Not listening yet".to_string()
+        } else {
+            format!("Listening on {}", addresses.join("  "))
+        };
+        let mut parts: Vec<String> = self.notice.iter().cloned().collect();
+        if self.session.state != SessionState::Open {
+            parts.push(format!("Session {}", self.session.state));
+        }
+        if self.roster.driving() {
+            parts.push("Driving (/drive to stop)".to_string());
+        } else if let Some(driver) = self.roster.driver() {
+            parts.push(if self.roster.following() {
+                format!("Following {} (Ctrl+F to break away)", driver.author)
+            } else {
+                format!("{} is driving (Ctrl+F to follow)", driver.author)
+            });
+        }
+        let typing: Vec<String> = self.roster.present().into_iter().filter(|p| p.typing).map(|p| p.author).collect();
+        match typing.as_slice() {
+            [] => {}
+            [name] => parts.push(format!("{} is typing...", name)),
+            names => parts.push(format!("{} are typing...", names.join(", "))),
+        }
+        parts.push(listening);
+        parts.push(format!("{} peer(s)", self.peer_count));
+        parts.join(" | ")
+    }
 
-        doc.push(elements::Paragraph::new(format!("ReviewMesh Export: {}", self.session.title)).with_style(decorator));
+    fn on_mouse(&mut self, mouse: MouseEvent) {
+        let (col, row) = (mouse.column, mouse.row);
+        match mouse.kind {
+            MouseEventKind::Down(MouseButton::Left) => {
+                if self.on_splitter(col, row) {
+                    self.dragging_split = true;
+                } else if let Some(index) = row_at(self.diff_area, col, row, self.diff_scroll) {
+                    self.focus = Pane::Diff;
+                    if index < self.rows.len() {
+                        self.roster.break_away();
+                        self.selected_row = index;
+                    }
+                } else if let Some(index) = row_at(self.comments_area, col, row, self.comment_scroll) {
+                    self.focus = Pane::Comments;
+                    if index < self.shown_comments().len() {
+                        self.select_comment(index);
+                    }
+                }
+            }
+            MouseEventKind::Drag(MouseButton::Left) if self.dragging_split => {
+                let total = self.diff_area.width + self.comments_area.width;
+                if total > 0 {
+                    let offset = col.saturating_sub(self.diff_area.x) as u32 * 100 / total as u32;
+                    self.split = (offset as u16).clamp(MIN_SPLIT, MAX_SPLIT);
+                }
+            }
+            MouseEventKind::Up(MouseButton::Left) => {
+                self.dragging_split = false;
+            }
+            MouseEventKind::ScrollDown => self.scroll_pane_at(col, row, true),
+            MouseEventKind::ScrollUp => self.scroll_pane_at(col, row, false),
+            _ => {}
+        }
+    }
 
-        for (file_name, hunks) in self.group_hunks_by_file() {
-            let mut decorator = genpdf::style::Style::new();
-            decorator.set_bold();
-            doc.push(elements::Paragraph::new(format!("File: {}", file_name)).with_style(decorator));
+    /// The splitter is the pair of adjoining borders between the two panes,
+    /// from their top to their bottom edge.
+    fn on_splitter(&self, col: u16, row: u16) -> bool {
+        let (area, border) = (self.diff_area, self.comments_area.x);
+        area.width > 0 && row >= area.y && row < area.y + area.height && (col == border || col + 1 == border)
+    }
 
-            for hunk in hunks {
-                doc.push(elements::Paragraph::new(hunk.content.clone()));
+    fn scroll_pane_at(&mut self, col: u16, row: u16, down: bool) {
+        let comment_count = self.shown_comments().len();
+        let (scroll, len, area) = if contains(self.diff_area, col, row) {
+            (&mut self.diff_scroll, self.rows.len(), self.diff_area)
+        } else if contains(self.comments_area, col, row) {
+            (&mut self.comment_scroll, comment_count, self.comments_area)
+        } else {
+            return;
+        };
+        let max = len.saturating_sub(inner_height(area));
+        *scroll = if down {
+            (*scroll + SCROLL_STEP).min(max)
+        } else {
+            scroll.saturating_sub(SCROLL_STEP)
+        };
+    }
 
-                let comments_for_hunk: Vec<&Comment> = self.comments.iter().filter(|c| c.hunk_id == hunk.id).collect();
-                for comment in comments_for_hunk {
-                    doc.push(elements::Paragraph::new(format!("> {}", comment.body)).with_style(style::Style::new().with_font_size(10)));
-                }
+    fn move_selection(&mut self, down: bool) {
+        match self.focus {
+            Pane::Diff => {
+                self.roster.break_away();
+                self.selected_row = step(self.selected_row, self.rows.len(), down);
+                self.diff_scroll = keep_visible(self.selected_row, self.diff_scroll, inner_height(self.diff_area));
+            }
+            Pane::Comments => {
+                let index = step(self.selected_comment, self.shown_comments().len(), down);
+                self.select_comment(index);
             }
         }
+    }
 
-        let mut decorator = genpdf::style::Style::new();
-        decorator.set_bold();
-        doc.push(elements::Paragraph::new("Comments:").with_style(decorator));
-        for comment in &self.comments {
-            doc.push(elements::Paragraph::new(format!("{}: {}", comment.author, comment.body)));
+    /// Selects a comment and jumps the diff pane to the line it refers to.
+    fn select_comment(&mut self, index: usize) {
+        self.selected_comment = index;
+        self.comment_scroll = keep_visible(index, self.comment_scroll, inner_height(self.comments_area));
+        let Some(comment) = self.shown_comments().get(index).map(|c| (*c).clone()) else { return };
+        let target = self.rows.iter().position(|r| {
+            self.hunks[r.hunk].id == comment.hunk_id && r.line == Some(comment.line) && r.side == comment.side
+        });
+        if let Some(row) = target {
+            self.roster.break_away();
+            self.selected_row = row;
+            self.diff_scroll = keep_visible(row, self.diff_scroll, inner_height(self.diff_area));
         }
+    }
 
-        let mut decorator = genpdf::style::Style::new();
-        decorator.set_bold();
-        doc.push(elements::Paragraph::new("Chat:").with_style(decorator));
-        for chat in &self.chat_history {
-            doc.push(elements::Paragraph::new(format!("{}: {}", chat.author, chat.body)));
+    /// The comments in the pane: the `/search` hits, or all of them.
+    fn shown_comments(&self) -> Vec<&Comment> {
+        match &self.search {
+            Some((_, ids)) => ids.iter().filter_map(|id| self.comments.iter().find(|c| c.id == *id)).collect(),
+            None => self.comments.iter().collect(),
         }
+    }
 
-        doc.render_to_file(file_path)?;
-        Ok(())
+    /// Narrows the comments pane to matches of `query`, or shows every
+    /// comment again when it is empty.
+    fn search(&mut self, query: &str) {
+        self.search = None;
+        if !query.is_empty() {
+            let filter = SearchFilter { session_id: Some(self.session.id.clone()), comments_only: true, ..Default::default() };
+            let ids = self
+                .storage
+                .search(query, &filter)
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|hit| match hit.record {
+                    HitRecord::Comment(c) => Some(c.id),
+                    HitRecord::Chat(_) => None,
+                })
+                .collect();
+            self.search = Some((query.to_string(), ids));
+            self.focus = Pane::Comments;
+        }
+        self.comment_scroll = 0;
+        self.select_comment(0);
     }
 
-    fn group_hunks_by_file(&self) -> std::collections::HashMap<String, Vec<&DiffHunk>> {
-        let mut map = std::collections::HashMap::new();
-        for hunk in &self.hunks {
-            map.entry(hunk.file.clone()).or_default().push(hunk);
+    /// Applies the owner's change to the session and announces the new
+    /// revision. Other members can't change it, so nothing happens for them.
+    fn edit_session(&mut self, edit: impl FnOnce(&mut ReviewSession)) {
+        if !self.is_owner() {
+            return;
         }
-        map
+        self.reload_session();
+        edit(&mut self.session);
+        session::reissue(&mut self.session, &self.node);
+        self.stored(self.storage.save_session(&self.session));
+        let published = session::publish(&mut self.network, &self.node, &self.session);
+        self.report(published);
     }
 
     fn handle_input(&mut self, input: &str) {
-        if let Some(comment) = input.strip_prefix("/comment ") {
-            if let Some(selected_hunk) = self.hunks.get(0) { // Simple: comment on the first hunk
-                let new_comment = Comment {
+        if input == "/drive" {
+            self.roster.toggle_driving();
+        } else if let Some(query) = input.strip_prefix("/search").filter(|q| q.is_empty() || q.starts_with(' ')) {
+            self.search(query.trim());
+        } else if let Some(title) = input.strip_prefix("/title ") {
+            self.edit_session(|s| s.title = title.trim().to_string());
+        } else if let Some(description) = input.strip_prefix("/describe").filter(|d| d.is_empty() || d.starts_with(' ')) {
+            self.edit_session(|s| s.details.description = description.trim().to_string());
+        } else if let Some(label) = input.strip_prefix("/label ") {
+            self.edit_session(|s| toggle(&mut s.details.labels, label.trim()));
+        } else if let Some(issue) = input.strip_prefix("/issue ") {
+            self.edit_session(|s| toggle(&mut s.details.issues, issue.trim()));
+        } else if let Some(comment) = input.strip_prefix("/comment ") {
+            if let Some(row) = self.rows.get(self.selected_row) {
+                let selected_hunk = &self.hunks[row.hunk];
+                let mut new_comment = Comment {
                     id: Uuid::new_v4().to_string(),
                     session_id: self.session.id.clone(),
-                    author: whoami::username(),
+                    author: self.node.identity.name.clone(),
+                    author_id: self.node.identity.peer_id.clone(),
                     file: selected_hunk.file.clone(),
                     hunk_id: selected_hunk.id.clone(),
-                    line: 0, // Placeholder
+                    line: row.line.unwrap_or(selected_hunk.new_start),
+                    side: row.side,
                     body: comment.to_string(),
                     created_at: Utc::now(),
                     resolved: false,
+                    resolved_at: None,
+                    signature: String::new(),
                 };
-                self.storage.save_comment(&new_comment).unwrap();
-                self.network.publish_comment(&new_comment);
+                self.node.sign(&mut new_comment);
+                self.stored(self.storage.save_comment(&new_comment));
+                let published = self.network.publish_comment(&new_comment);
+                self.report(published);
                 self.comments.push(new_comment);
             }
+        } else if let Some((decision, body)) = parse_verdict(input) {
+            let mut verdict = Verdict {
+                id: Uuid::new_v4().to_string(),
+                session_id: self.session.id.clone(),
+                author: self.node.identity.name.clone(),
+                author_id: self.node.identity.peer_id.clone(),
+                decision,
+                body: body.to_string(),
+                created_at: Utc::now(),
+                signature: String::new(),
+            };
+            self.node.sign(&mut verdict);
+            self.stored(self.storage.save_verdict(&verdict));
+            let published = self.network.publish_verdict(&verdict);
+            self.report(published);
+            self.verdicts.push(verdict);
         } else {
-            let chat_line = ChatLine {
+            let mut chat_line = ChatLine {
                 id: Uuid::new_v4().to_string(),
                 session_id: self.session.id.clone(),
-                author: whoami::username(),
+                author: self.node.identity.name.clone(),
+                author_id: self.node.identity.peer_id.clone(),
                 body: input.to_string(),
                 created_at: Utc::now(),
+                signature: String::new(),
             };
-            self.storage.save_chat_line(&chat_line).unwrap();
-            // self.network.publish_chat(&chat_line);
+            self.node.sign(&mut chat_line);
+            self.stored(self.storage.save_chat(&chat_line));
+            let published = self.network.publish_chat(&chat_line);
+            self.report(published);
             self.chat_history.push(chat_line);
         }
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let cli = Cli::parse();
+/// Adds `value` to `list`, or takes it out if it is already there.
+fn toggle(list: &mut Vec<String>, value: &str) {
+    if value.is_empty() {
+        return;
+    }
+    match list.iter().position(|v| v == value) {
+        Some(index) => {
+            list.remove(index);
+        }
+        None => list.push(value.to_string()),
+    }
+}
 
-    match cli.command {
-        Commands::Review { session_id, target_branch } => {
-            let storage = Storage::new("review_mesh.db")?;
-            let mut app = App::new(storage, session_id, target_branch);
+/// Splits `/approve [message]` and `/request-changes [message]`.
+fn parse_verdict(input: &str) -> Option<(Decision, &str)> {
+    let rest = input.strip_prefix('/')?;
+    let (command, body) = rest.split_once(' ').unwrap_or((rest, ""));
+    Some((command.parse().ok()?, body.trim()))
+}
 
-            enable_raw_mode()?;
-            let mut stdout = io::stdout();
-            execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
-            let backend = CrosstermBackend::new(stdout);
-            let mut terminal = Terminal::new(backend)?;
+/// The persistent node key plus the reviewer name, defaulting to git's
+/// `user.name`/`user.email`.
+fn node_identity(repo: &str) -> Result<NodeIdentity, Box<dyn std::error::Error>> {
+    let (name, email) = git_user(repo);
+    let name = name.unwrap_or_else(whoami::username);
+    Ok(NodeIdentity::load(&network::identity::config_dir(), name, email)?)
+}
 
-            let tick_rate = Duration::from_millis(250);
-            let mut last_tick = Instant::now();
-            let mut input = String::new();
+const DB_FILE: &str = "review_mesh.db";
 
-            loop {
-                terminal.draw(|f| app.ui(f, &input))?;
-
-                let timeout = tick_rate
-                    .checked_sub(last_tick.elapsed())
-                    .unwrap_or_else(|| Duration::from_secs(0));
-
-                if crossterm::event::poll(timeout)? {
-                    if let Event::Key(key) = event::read()? {
-                        match key.code {
-                            KeyCode::Enter => {
-                                app.handle_input(&input);
-                                input.clear();
-                            }
-                            KeyCode::Char(c) => {
-                                input.push(c);
-                            }
-                            KeyCode::Backspace => {
-                                input.pop();
-                            }
-                            KeyCode::Esc => {
-                                break;
-                            }
-                            _ => {}
-                        }
-                    }
-                }
+/// Opens `db`, or else the database under the repository's git directory so
+/// it is shared by every worktree and never committed. Outside a repository
+/// it lives in the user's data directory. Sessions are filed under the
+/// repository's identity, so one database can serve several.
+fn open_storage(db: Option<&Path>, repo: &str) -> Result<Storage, Box<dyn std::error::Error>> {
+    let path = match db {
+        Some(path) => path.to_path_buf(),
+        None => {
+            let dir = match common_dir(repo) {
+                Ok(git_dir) => git_dir.join("reviewmesh"),
+                Err(_) => dirs::data_dir().unwrap_or_else(|| PathBuf::from(".")).join("reviewmesh"),
+            };
+            fs::create_dir_all(&dir)?;
+            let path = dir.join(DB_FILE);
+            // Earlier versions kept the database in the working directory.
+            if !path.exists() && Path::new(DB_FILE).exists() {
+                fs::copy(DB_FILE, &path)?;
+                eprintln!("Copied {} to {}; the old file can be deleted", DB_FILE, path.display());
+            }
+            path
+        }
+    };
+    let storage = Storage::new(path.to_str().ok_or("database path is not valid UTF-8")?)?;
+    Ok(match repo_identity(repo) {
+        Ok(Some(repo)) => storage.for_repo(&repo),
+        _ => storage,
+    })
+}
 
-                if last_tick.elapsed() >= tick_rate {
-                    app.on_tick();
-                    last_tick = Instant::now();
-                }
+fn diff_rows(hunks: &[DiffHunk]) -> Vec<DiffRow> {
+    let mut rows = vec![];
+    for (index, hunk) in hunks.iter().enumerate() {
+        rows.push(DiffRow {
+            hunk: index,
+            line: None,
+            side: Side::New,
+            kind: None,
+            text: format!(
+                "{} @@ -{},{} +{},{} @@",
+                hunk.file, hunk.old_start, hunk.old_lines, hunk.new_start, hunk.new_lines
+            ),
+        });
+        for line in hunk.lines() {
+            let marker = match line.kind {
+                LineKind::Added => '+',
+                LineKind::Removed => '-',
+                LineKind::Context => ' ',
+            };
+            let (side, number) = line.anchor();
+            rows.push(DiffRow {
+                hunk: index,
+                line: Some(number),
+                side,
+                kind: Some(line.kind),
+                text: format!("{:>5} {}{}", number, marker, line.text),
+            });
+        }
+    }
+    rows
+}
+
+fn pane_block(title: &str, focused: bool) -> Block<'_> {
+    let style = if focused {
+        Style::default().fg(Color::Yellow)
+    } else {
+        Style::default()
+    };
+    Block::default().borders(Borders::ALL).border_style(style).title(title)
+}
+
+fn inner_height(area: Rect) -> usize {
+    area.height.saturating_sub(2) as usize
+}
+
+fn contains(area: Rect, col: u16, row: u16) -> bool {
+    col >= area.x && col < area.x + area.width && row >= area.y && row < area.y + area.height
+}
+
+/// Maps a click inside a bordered list to the index of the item under it.
+fn row_at(area: Rect, col: u16, row: u16, scroll: usize) -> Option<usize> {
+    if !contains(area, col, row) || row == area.y || row + 1 >= area.y + area.height {
+        return None;
+    }
+    Some(scroll + (row - area.y - 1) as usize)
+}
+
+fn step(index: usize, len: usize, down: bool) -> usize {
+    if down {
+        (index + 1).min(len.saturating_sub(1))
+    } else {
+        index.saturating_sub(1)
+    }
+}
+
+fn keep_visible(index: usize, scroll: usize, height: usize) -> usize {
+    if index < scroll {
+        index
+    } else if height > 0 && index >= scroll + height {
+        index + 1 - height
+    } else {
+        scroll
+    }
+}
+
+/// Raw mode and the alternate screen for a TUI, left again on drop so an
+/// error or panic doesn't leave the shell unusable.
+struct TerminalGuard {
+    mouse: bool,
+}
+
+impl TerminalGuard {
+    fn enter(mouse: bool) -> io::Result<Self> {
+        enable_raw_mode()?;
+        let guard = TerminalGuard { mouse };
+        execute!(io::stdout(), EnterAlternateScreen)?;
+        if mouse {
+            execute!(io::stdout(), EnableMouseCapture)?;
+        }
+        Ok(guard)
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        let mut stdout = io::stdout();
+        if self.mouse {
+            let _ = execute!(stdout, DisableMouseCapture);
+        }
+        let _ = execute!(stdout, LeaveAlternateScreen, crossterm::cursor::Show);
+        let _ = disable_raw_mode();
+    }
+}
+
+/// Runs the review TUI until Esc, syncing with the repository's refs on
+/// exit when `sync_git` is set.
+fn run_review(mut app: App, no_mouse: bool, sync_git: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let guard = TerminalGuard::enter(!no_mouse)?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(io::stdout()))?;
+
+    let tick_rate = Duration::from_millis(250);
+    let mut last_tick = Instant::now();
+    let mut input = String::new();
+
+    loop {
+        terminal.draw(|f| app.ui(f, &input))?;
+
+        let timeout = tick_rate
+            .checked_sub(last_tick.elapsed())
+            .unwrap_or_else(|| Duration::from_secs(0));
+
+        if crossterm::event::poll(timeout)? {
+            match event::read()? {
+                Event::Key(key) => match key.code {
+                    KeyCode::Char('f') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                        app.toggle_following();
+                    }
+                    KeyCode::Enter => {
+                        app.handle_input(&input);
+                        input.clear();
+                    }
+                    KeyCode::Char(c) => {
+                        input.push(c);
+                    }
+                    KeyCode::Backspace => {
+                        input.pop();
+                    }
+                    KeyCode::Up => app.move_selection(false),
+                    KeyCode::Down => app.move_selection(true),
+                    KeyCode::Tab => {
+                        app.focus = match app.focus {
+                            Pane::Diff => Pane::Comments,
+                            Pane::Comments => Pane::Diff,
+                        };
+                    }
+                    KeyCode::Esc => {
+                        break;
+                    }
+                    _ => {}
+                },
+                Event::Mouse(mouse) => app.on_mouse(mouse),
+                _ => {}
             }
+        }
+
+        if last_tick.elapsed() >= tick_rate {
+            // Anything but a slash command in the input box is an
+            // unsent chat message.
+            app.on_tick(!input.is_empty() && !input.starts_with('/'));
+            last_tick = Instant::now();
+        }
+    }
+
+    drop(guard);
+    if sync_git {
+        git_store::sync(&app.storage, &app.repo, &app.session.id)?;
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    // Printed with Display rather than the Debug output `main` returning an
+    // error would give, so git and storage errors read as sentences.
+    if let Err(e) = run(Cli::parse()).await {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
 
-            disable_raw_mode()?;
-            execute!(
-                terminal.backend_mut(),
-                LeaveAlternateScreen,
-                DisableMouseCapture
-            )?;
-            terminal.show_cursor()?;
-        }
-        Commands::Export { session_id, file_path } => {
-            let storage = Storage::new("review_mesh.db")?;
-            let app = App::new(storage, session_id, None);
-            app.export_to_pdf(&file_path)?;
+async fn run(cli: Cli) -> Result<(), Box<dyn std::error::Error>> {
+    match cli.command {
+        Commands::Review { session_id, target_branch, no_mouse, sync_git, network: args } => {
+            let storage = open_storage(cli.db.as_deref(), &cli.repo)?;
+            if sync_git {
+                git_store::sync(&storage, &cli.repo, &session_id)?;
+            }
+            let node = node_identity(&cli.repo)?;
+            let config = args.config(&network::identity::config_dir())?;
+            let app = App::new(storage, cli.repo.clone(), session_id, target_branch, &node, &config)?;
+            run_review(app, no_mouse, sync_git)?;
+        }
+        Commands::Export { session_id, file_path, format, target_branch } => {
+            let format = format
+                .or_else(|| ExportFormat::from_path(&file_path))
+                .ok_or_else(|| format!("cannot tell the export format of {}; pass --format", file_path))?;
+            let storage = open_storage(cli.db.as_deref(), &cli.repo)?;
+            let review = Review::load(&storage, &cli.repo, &session_id, target_branch.as_deref())?;
+            let mut file = std::io::BufWriter::new(std::fs::File::create(&file_path)?);
+            format.exporter().export(&review, &mut file)?;
+            file.flush()?;
             println!("Exported to {}", file_path);
         }
+        Commands::Comment { command } => {
+            let storage = open_storage(cli.db.as_deref(), &cli.repo)?;
+            commands::comment(&storage, &node_identity(&cli.repo)?, &cli.repo, command).await?;
+        }
+        Commands::Chat { command } => {
+            let storage = open_storage(cli.db.as_deref(), &cli.repo)?;
+            commands::chat(&storage, &node_identity(&cli.repo)?, command).await?;
+        }
+        Commands::Verdict { session_id, decision, message, publish } => {
+            let storage = open_storage(cli.db.as_deref(), &cli.repo)?;
+            commands::verdict(&storage, &node_identity(&cli.repo)?, &session_id, decision, message, publish).await?;
+        }
+        Commands::Resolve { comment_id, reopen, publish } => {
+            let storage = open_storage(cli.db.as_deref(), &cli.repo)?;
+            commands::resolve(&storage, &node_identity(&cli.repo)?, &comment_id, !reopen, publish).await?;
+        }
+        Commands::Sessions { interactive, json, all, network: args } => {
+            let storage = open_storage(cli.db.as_deref(), &cli.repo)?;
+            if !interactive {
+                let mut summaries = storage.session_summaries()?;
+                summaries.retain(|s| all || s.session.state != SessionState::Archived);
+                if json {
+                    let summaries: Vec<_> = summaries
+                        .iter()
+                        .map(|s| {
+                            serde_json::json!({
+                                "session": s.session,
+                                "target_branch": s.target_branch,
+                                "open_comments": s.open_comments,
+                                "resolved_comments": s.resolved_comments,
+                                "last_activity": s.last_activity,
+                            })
+                        })
+                        .collect();
+                    println!("{}", serde_json::to_string_pretty(&summaries)?);
+                } else {
+                    for summary in &summaries {
+                        println!("{}", dashboard::summary_line(summary));
+                    }
+                }
+                return Ok(());
+            }
+            let node = node_identity(&cli.repo)?;
+            let config = args.config(&network::identity::config_dir())?;
+            let picked = dashboard::pick(&storage, NetworkManager::new(&node, &config)?, all)?;
+            // The dashboard's node is gone by now, so the review can bind
+            // the same listen addresses.
+            if let Some(summary) = picked {
+                let app = App::new(storage, cli.repo.clone(), summary.session.id, summary.target_branch, &node, &config)?;
+                run_review(app, false, false)?;
+            }
+        }
+        Commands::Search(args) => {
+            let storage = open_storage(cli.db.as_deref(), &cli.repo)?;
+            commands::search(&storage, args)?;
+        }
+        Commands::Session { command } => {
+            let storage = open_storage(cli.db.as_deref(), &cli.repo)?;
+            commands::session(&storage, &node_identity(&cli.repo)?, &cli.repo, command).await?;
+        }
+        Commands::Identity { command } => {
+            commands::identity(&node_identity(&cli.repo)?, command)?;
+        }
+        Commands::Fetch { session_id, timeout, peers } => {
+            let storage = open_storage(cli.db.as_deref(), &cli.repo)?;
+            commands::fetch(&storage, &node_identity(&cli.repo)?, &cli.repo, &session_id, &peers, timeout).await?;
+        }
+        Commands::Peer { command } => {
+            commands::peer(command)?;
+        }
+        Commands::Relay { network: args } => {
+            // The relay gets its own key so running one next to a review
+            // node doesn't present the same PeerId twice.
+            let dir = network::identity::config_dir().join("relay");
+            let node = NodeIdentity::load(&dir, "relay".to_string(), None)?;
+            let mut config = args.config(&dir)?;
+            if config.listen.is_empty() {
+                config.listen.push("/ip4/0.0.0.0/tcp/4001".parse()?);
+            }
+            let mut network = NetworkManager::relay(&node, &config)?;
+            println!("Relay {} running; add it on each reviewer with `cli peer add <address>`", node.identity.peer_id);
+            loop {
+                network.run_for(Duration::from_secs(1)).await;
+                for address in network.new_listen_addresses() {
+                    println!("Listening on {}", address);
+                }
+            }
+        }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::testing::TempDir;
+
+    /// An app on a one-hunk diff that removes line 1 and adds a new line 1,
+    /// drawn as a 40-column diff pane beside the comments, above a status line.
+    fn app(dir: &TempDir) -> App {
+        let storage = Storage::new(dir.join("reviews.db").to_str().unwrap()).unwrap();
+        let node = NodeIdentity::load(dir.path(), "alice".to_string(), None).unwrap();
+        let config = NetworkConfig { listen: vec!["/ip4/127.0.0.1/tcp/0".parse().unwrap()], bootstrap: vec![] };
+        let mut app = App::new(storage, dir.path().display().to_string(), "s1".to_string(), None, &node, &config).unwrap();
+        app.hunks = vec![DiffHunk {
+            id: "h1".to_string(),
+            file: "src/login.rs".to_string(),
+            old_start: 1,
+            old_lines: 1,
+            new_start: 1,
+            new_lines: 1,
+            content: "@@ -1,1 +1,1 @@\n-old\n+new\n".to_string(),
+        }];
+        app.rows = diff_rows(&app.hunks);
+        app.diff_area = Rect::new(0, 0, 40, 10);
+        app.comments_area = Rect::new(40, 0, 40, 10);
+        app
+    }
+
+    fn click(kind: MouseEventKind, column: u16, row: u16) -> MouseEvent {
+        MouseEvent { kind, column, row, modifiers: KeyModifiers::NONE }
+    }
+
+    #[test]
+    fn clicks_map_to_rows_inside_the_borders() {
+        let area = Rect::new(0, 0, 40, 10);
+        assert_eq!(row_at(area, 5, 0, 0), None, "top border");
+        assert_eq!(row_at(area, 5, 1, 0), Some(0));
+        assert_eq!(row_at(area, 5, 8, 3), Some(10));
+        assert_eq!(row_at(area, 5, 9, 0), None, "bottom border");
+        assert_eq!(row_at(area, 40, 5, 0), None, "beside the pane");
+    }
+
+    #[tokio::test]
+    async fn clicking_a_diff_row_selects_it_and_stops_following() {
+        let dir = TempDir::new("tui-click");
+        let mut app = app(&dir);
+        app.focus = Pane::Comments;
+        app.on_mouse(click(MouseEventKind::Down(MouseButton::Left), 5, 3));
+        assert_eq!((app.focus, app.selected_row), (Pane::Diff, 2));
+        assert!(!app.roster.following());
+
+        // Below the last row nothing changes.
+        app.on_mouse(click(MouseEventKind::Down(MouseButton::Left), 5, 6));
+        assert_eq!(app.selected_row, 2);
+    }
+
+    #[tokio::test]
+    async fn the_splitter_only_drags_between_the_panes() {
+        let dir = TempDir::new("tui-splitter");
+        let mut app = app(&dir);
+        for (col, row) in [(39, 0), (40, 9)] {
+            assert!(app.on_splitter(col, row));
+        }
+        for (col, row) in [(38, 5), (41, 5), (39, 10), (40, 12)] {
+            assert!(!app.on_splitter(col, row), "{},{}", col, row);
+        }
+
+        // The status line under the panes isn't part of the splitter.
+        app.on_mouse(click(MouseEventKind::Down(MouseButton::Left), 40, 10));
+        assert!(!app.dragging_split);
+
+        app.on_mouse(click(MouseEventKind::Down(MouseButton::Left), 40, 5));
+        app.on_mouse(click(MouseEventKind::Drag(MouseButton::Left), 20, 5));
+        assert_eq!(app.split, 25);
+        app.on_mouse(click(MouseEventKind::Drag(MouseButton::Left), 0, 5));
+        assert_eq!(app.split, MIN_SPLIT);
+        app.on_mouse(click(MouseEventKind::Up(MouseButton::Left), 0, 5));
+        app.on_mouse(click(MouseEventKind::Drag(MouseButton::Left), 60, 5));
+        assert_eq!(app.split, MIN_SPLIT, "released");
+    }
+
+    #[tokio::test]
+    async fn comments_anchor_to_the_side_of_the_selected_line() {
+        let dir = TempDir::new("tui-anchor");
+        let mut app = app(&dir);
+        app.selected_row = 1;
+        app.handle_input("/comment Still needed?");
+        app.selected_row = 2;
+        app.handle_input("/comment Why rename it?");
+        let anchors: Vec<(Side, usize)> = app.comments.iter().map(|c| (c.side, c.line)).collect();
+        assert_eq!(anchors, [(Side::Old, 1), (Side::New, 1)]);
+        assert_eq!(app.storage.load_comments("s1").unwrap().len(), 2);
+
+        // Both are on line 1; selecting each jumps to its own side.
+        app.select_comment(0);
+        assert_eq!(app.selected_row, 1);
+        app.select_comment(1);
+        assert_eq!(app.selected_row, 2);
+    }
+}
@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use common::Presence;
+
+/// How long a peer stays listed after their last heartbeat.
+pub const PRESENCE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The other reviewers in a session, from their latest heartbeats, and our
+/// part in a walkthrough: leading it, following the driver or neither.
+pub struct Roster {
+    /// Latest heartbeat from each other reviewer by PeerId, with when it arrived.
+    seen: HashMap<String, (Presence, Instant)>,
+    /// Leading a walkthrough: our selection is what followers see.
+    driving: bool,
+    /// Tracking the driver's selection. Cleared by moving the selection
+    /// ourselves, restored with Ctrl+F.
+    following: bool,
+}
+
+impl Default for Roster {
+    fn default() -> Self {
+        Roster { seen: HashMap::new(), driving: false, following: true }
+    }
+}
+
+impl Roster {
+    pub fn record(&mut self, presence: Presence, now: Instant) {
+        self.seen.insert(presence.author_id.clone(), (presence, now));
+    }
+
+    /// Forgets reviewers whose last heartbeat is older than `PRESENCE_TIMEOUT`.
+    pub fn expire(&mut self, now: Instant) {
+        self.seen.retain(|_, (_, seen)| now.duration_since(*seen) < PRESENCE_TIMEOUT);
+    }
+
+    /// Other reviewers currently in the session, by name.
+    pub fn present(&self) -> Vec<Presence> {
+        let mut present: Vec<Presence> = self.seen.values().map(|(p, _)| p.clone()).collect();
+        present.sort_by(|a, b| a.author.cmp(&b.author));
+        present
+    }
+
+    /// The reviewer leading a walkthrough, if anyone but us is.
+    pub fn driver(&self) -> Option<Presence> {
+        if self.driving {
+            return None;
+        }
+        self.present().into_iter().find(|p| p.driving)
+    }
+
+    pub fn driving(&self) -> bool {
+        self.driving
+    }
+
+    pub fn following(&self) -> bool {
+        self.following
+    }
+
+    pub fn toggle_driving(&mut self) {
+        self.driving = !self.driving;
+    }
+
+    /// Breaks away from the driver, or goes back to following them.
+    pub fn toggle_following(&mut self) {
+        self.following = !self.following;
+    }
+
+    /// We moved the selection ourselves, so stop tracking the driver.
+    pub fn break_away(&mut self) {
+        self.following = false;
+    }
+
+    /// The file and line to move our selection to, while following a driver
+    /// who has one selected.
+    pub fn follow_target(&self) -> Option<(String, Option<usize>)> {
+        if !self.following {
+            return None;
+        }
+        let driver = self.driver()?;
+        Some((driver.file?, driver.line))
+    }
+}
+
+/// The row showing `line` of `file`, or the first row of `file` when that
+/// line isn't in our diff. `rows` gives the file and line of each row.
+pub fn follow_row<'a>(rows: impl IntoIterator<Item = (&'a str, Option<usize>)>, file: &str, line: Option<usize>) -> Option<usize> {
+    let mut first = None;
+    for (index, (row_file, row_line)) in rows.into_iter().enumerate() {
+        if row_file != file {
+            continue;
+        }
+        if row_line == line {
+            return Some(index);
+        }
+        first.get_or_insert(index);
+    }
+    first
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn presence(author: &str, file: Option<&str>, line: Option<usize>, driving: bool) -> Presence {
+        Presence {
+            session_id: "s1".to_string(),
+            author: author.to_string(),
+            author_id: format!("12D3KooW{}", author),
+            file: file.map(str::to_string),
+            line,
+            typing: false,
+            driving,
+            sent_at: Utc::now(),
+            signature: String::new(),
+        }
+    }
+
+    #[test]
+    fn reviewers_are_forgotten_after_the_timeout() {
+        let mut roster = Roster::default();
+        let now = Instant::now();
+        let later = now + Duration::from_secs(9);
+        roster.record(presence("bob", None, None, false), now);
+        roster.record(presence("carol", None, None, false), later);
+        roster.expire(now + PRESENCE_TIMEOUT);
+        let names: Vec<String> = roster.present().into_iter().map(|p| p.author).collect();
+        assert_eq!(names, ["carol"]);
+
+        roster.expire(later + PRESENCE_TIMEOUT);
+        assert!(roster.present().is_empty());
+    }
+
+    #[test]
+    fn following_tracks_the_driver_until_we_break_away() {
+        let mut roster = Roster::default();
+        let now = Instant::now();
+        roster.record(presence("bob", Some("src/lib.rs"), Some(4), false), now);
+        assert_eq!(roster.follow_target(), None, "nobody is driving");
+
+        roster.record(presence("carol", Some("src/main.rs"), Some(7), true), now);
+        assert_eq!(roster.driver().map(|d| d.author).as_deref(), Some("carol"));
+        assert_eq!(roster.follow_target(), Some(("src/main.rs".to_string(), Some(7))));
+
+        roster.break_away();
+        assert_eq!(roster.follow_target(), None);
+        roster.toggle_following();
+        assert_eq!(roster.follow_target(), Some(("src/main.rs".to_string(), Some(7))));
+
+        // Driving ourselves, there is nobody to follow.
+        roster.toggle_driving();
+        assert_eq!(roster.driver(), None);
+        assert_eq!(roster.follow_target(), None);
+        roster.toggle_driving();
+
+        roster.expire(now + PRESENCE_TIMEOUT);
+        assert_eq!(roster.follow_target(), None, "the driver left");
+    }
+
+    #[test]
+    fn followers_land_on_the_drivers_line_or_file() {
+        let rows = [("src/lib.rs", None), ("src/lib.rs", Some(3)), ("src/main.rs", None), ("src/main.rs", Some(7))];
+        assert_eq!(follow_row(rows, "src/main.rs", Some(7)), Some(3));
+        assert_eq!(follow_row(rows, "src/main.rs", Some(99)), Some(2));
+        assert_eq!(follow_row(rows, "README.md", Some(1)), None);
+    }
+}
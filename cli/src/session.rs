@@ -0,0 +1,136 @@
+use std::error::Error;
+
+use chrono::Utc;
+
+use common::{ReviewSession, SessionDetails, SessionState, SharedDiff, Verdict};
+use git_integration::{branch_id, compute_diff, head_branch, head_id, is_merged};
+use network::identity::NodeIdentity;
+use network::signing::{self, Verification};
+use network::NetworkManager;
+use storage::Storage;
+
+use crate::keys;
+
+/// Loads a session, creating it on first use with this node as its owner.
+/// Sessions from before access control are claimed by the first node to
+/// open them. Only the owner records itself as a participant here; members
+/// are added when the owner admits them.
+pub fn load_or_create(storage: &Storage, session_id: &str, node: &NodeIdentity) -> Result<ReviewSession, Box<dyn Error>> {
+    let mut session = storage.load_session(session_id)?.unwrap_or_else(|| ReviewSession {
+        id: session_id.to_string(),
+        title: format!("Review for {}", session_id),
+        created_at: Utc::now(),
+        participants: vec![],
+        owner: String::new(),
+        members: vec![],
+        revoked: vec![],
+        revision: 0,
+        signature: String::new(),
+        state: SessionState::Open,
+        details: SessionDetails::default(),
+    });
+    let me = &node.identity;
+    if session.owner.is_empty() {
+        session.owner = me.peer_id.clone();
+        session.members = vec![me.peer_id.clone()];
+        session.details.author = me.name.clone();
+        keys::rotate(storage, &session.id)?;
+    } else if session.owner != me.peer_id || session.participants.contains(&me.name) {
+        return Ok(session);
+    }
+    if !session.participants.contains(&me.name) {
+        session.participants.push(me.name.clone());
+    }
+    reissue(&mut session, node);
+    storage.save_session(&session)?;
+    Ok(session)
+}
+
+/// The diff shared for a session. When the owner opens it against a branch,
+/// the diff is recomputed and, if it changed, signed and stored as the new
+/// shared copy that reviewers receive, and the session records the branches
+/// and commits it was taken from.
+pub fn load_or_share_diff(
+    storage: &Storage,
+    repo: &str,
+    session: &mut ReviewSession,
+    node: &NodeIdentity,
+    target_branch: Option<&str>,
+) -> Result<Option<SharedDiff>, Box<dyn Error>> {
+    let stored = storage.load_diff(&session.id)?;
+    let Some(branch) = target_branch.filter(|_| session.owner == node.identity.peer_id) else {
+        return Ok(stored);
+    };
+    let hunks = compute_diff(repo, branch)?;
+    let head_commit = head_id(repo)?;
+    let unchanged = stored
+        .as_ref()
+        .is_some_and(|d| d.target_branch == branch && d.head_commit == head_commit && d.hunks == hunks);
+    if unchanged || hunks.is_empty() {
+        return Ok(stored);
+    }
+    let details = &mut session.details;
+    details.source_branch = head_branch(repo)?.unwrap_or_default();
+    details.source_commit = head_commit.clone();
+    details.target_branch = branch.to_string();
+    details.target_commit = branch_id(repo, branch)?;
+    reissue(session, node);
+    storage.save_session(session)?;
+
+    let mut diff = SharedDiff {
+        session_id: session.id.clone(),
+        target_branch: branch.to_string(),
+        head_commit,
+        hunks,
+        author_id: node.identity.peer_id.clone(),
+        created_at: Utc::now(),
+        signature: String::new(),
+    };
+    node.sign(&mut diff);
+    storage.save_diff(&diff)?;
+    Ok(Some(diff))
+}
+
+/// Marks a session we own as merged once its target branch contains the
+/// commit its diff was taken at. Returns whether the session changed.
+pub fn detect_merged(storage: &Storage, repo: &str, node: &NodeIdentity, session: &mut ReviewSession) -> Result<bool, Box<dyn Error>> {
+    if session.owner != node.identity.peer_id || !session.state.can_become(SessionState::Merged) {
+        return Ok(false);
+    }
+    let Some(diff) = storage.load_diff(&session.id)?.filter(|d| !d.head_commit.is_empty()) else {
+        return Ok(false);
+    };
+    // A target branch this clone doesn't have can't tell us anything.
+    if !is_merged(repo, &diff.head_commit, &diff.target_branch).unwrap_or(false) {
+        return Ok(false);
+    }
+    session.state = SessionState::Merged;
+    reissue(session, node);
+    storage.save_session(session)?;
+    Ok(true)
+}
+
+/// The verdicts that can approve a file: signed by the node they name, from
+/// a member of the session.
+pub fn member_verdicts(session: &ReviewSession, verdicts: &[Verdict]) -> Vec<Verdict> {
+    verdicts
+        .iter()
+        .filter(|v| signing::verify(*v) == Verification::Verified && session.is_member(&v.author_id))
+        .cloned()
+        .collect()
+}
+
+/// Signs the owner's new revision of a session.
+pub fn reissue(session: &mut ReviewSession, node: &NodeIdentity) {
+    session.revision += 1;
+    node.sign(session);
+}
+
+/// Publishes a session we own: sealed for its members, with its membership
+/// in the clear for peers that are joining.
+pub fn publish(network: &mut NetworkManager, node: &NodeIdentity, session: &ReviewSession) -> Result<(), Box<dyn Error>> {
+    let mut membership = session.membership();
+    node.sign(&mut membership);
+    network.publish_membership(&membership)?;
+    network.publish_review_session(session)
+}
@@ -1,12 +1,186 @@
 use serde::{Serialize, Deserialize};
 use chrono::{DateTime, Utc};
 
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct ReviewSession {
     pub id: String,
     pub title: String,
     pub created_at: DateTime<Utc>,
     pub participants: Vec<String>,
+    /// PeerId of the node that created the session and admits members;
+    /// empty for sessions that predate access control, which stay open.
+    #[serde(default)]
+    pub owner: String,
+    /// PeerIds allowed to post, the owner included.
+    #[serde(default)]
+    pub members: Vec<String>,
+    /// PeerIds the owner removed; their invites no longer admit them.
+    #[serde(default)]
+    pub revoked: Vec<String>,
+    /// Bumped by the owner on every change so peers keep the newest copy.
+    #[serde(default)]
+    pub revision: u64,
+    #[serde(default)]
+    pub state: SessionState,
+    #[serde(default)]
+    pub details: SessionDetails,
+    #[serde(default)]
+    pub signature: String,
+}
+
+impl ReviewSession {
+    pub fn is_member(&self, peer_id: &str) -> bool {
+        self.owner.is_empty() || self.owner == peer_id || self.members.iter().any(|m| m == peer_id)
+    }
+
+    /// Who is in the session, for the owner to sign and publish.
+    pub fn membership(&self) -> Membership {
+        Membership {
+            session_id: self.id.clone(),
+            owner: self.owner.clone(),
+            members: self.members.clone(),
+            revision: self.revision,
+            signature: String::new(),
+        }
+    }
+}
+
+/// The part of a session that nodes outside it may read. The session itself
+/// carries review content and is sealed with the session key, so the owner
+/// publishes this beside it for peers that are joining or looking around.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Membership {
+    pub session_id: String,
+    /// PeerId of the session owner, who signs this.
+    pub owner: String,
+    pub members: Vec<String>,
+    /// The revision of the session this was taken from.
+    pub revision: u64,
+    #[serde(default)]
+    pub signature: String,
+}
+
+/// What a session reviews, kept up to date by its owner. Empty fields
+/// aren't known, e.g. the branches before the owner shares a diff.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct SessionDetails {
+    pub description: String,
+    /// Display name of the owner when the session was created.
+    pub author: String,
+    /// Branch under review and the commit it was at.
+    pub source_branch: String,
+    pub source_commit: String,
+    /// Branch the changes are compared with and the commit it was at.
+    pub target_branch: String,
+    pub target_commit: String,
+    pub labels: Vec<String>,
+    /// Issue references such as `#42` or a tracker URL.
+    pub issues: Vec<String>,
+}
+
+impl SessionDetails {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// `feature (1a2b3c4) into main (5d6e7f8)`, once the target is known.
+    pub fn branches(&self) -> Option<String> {
+        let side = |branch: &str, commit: &str| {
+            let branch = if branch.is_empty() { "HEAD" } else { branch };
+            match commit.get(..7) {
+                Some(short) => format!("{} ({})", branch, short),
+                None => branch.to_string(),
+            }
+        };
+        (!self.target_branch.is_empty()).then(|| {
+            format!("{} into {}", side(&self.source_branch, &self.source_commit), side(&self.target_branch, &self.target_commit))
+        })
+    }
+}
+
+/// Where a session is in its life. Only the owner moves it along, through
+/// the transitions [`SessionState::can_become`] allows.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionState {
+    #[default]
+    Open,
+    Approved,
+    Merged,
+    Abandoned,
+    /// Done with: left out of listings and no longer announced.
+    Archived,
+}
+
+impl SessionState {
+    /// The spelling used on the command line and in storage.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SessionState::Open => "open",
+            SessionState::Approved => "approved",
+            SessionState::Merged => "merged",
+            SessionState::Abandoned => "abandoned",
+            SessionState::Archived => "archived",
+        }
+    }
+
+    /// Open sessions get approved, approved ones reopen on new changes,
+    /// either ends merged or abandoned, an abandoned one can be picked up
+    /// again, and finished sessions are archived.
+    pub fn can_become(self, next: SessionState) -> bool {
+        use SessionState::*;
+        matches!(
+            (self, next),
+            (Open, Approved)
+                | (Approved, Open)
+                | (Open | Approved, Merged | Abandoned)
+                | (Abandoned, Open)
+                | (Merged | Abandoned, Archived)
+        )
+    }
+}
+
+impl std::fmt::Display for SessionState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for SessionState {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "open" => Ok(SessionState::Open),
+            "approved" => Ok(SessionState::Approved),
+            "merged" => Ok(SessionState::Merged),
+            "abandoned" => Ok(SessionState::Abandoned),
+            "archived" => Ok(SessionState::Archived),
+            _ => Err(format!("unknown state {}; expected open, approved, merged, abandoned or archived", s)),
+        }
+    }
+}
+
+/// A reviewer as peers see them: a display name bound to the node's PeerId.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Identity {
+    pub name: String,
+    pub email: Option<String>,
+    /// Base58 PeerId of the reviewer's persistent node key.
+    pub peer_id: String,
+}
+
+impl std::fmt::Display for Identity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.email {
+            Some(email) => write!(f, "{} <{}>", self.name, email),
+            None => write!(f, "{}", self.name),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -14,12 +188,33 @@ pub struct Comment {
     pub id: String,
     pub session_id: String,
     pub author: String,
+    /// PeerId of the node that wrote the comment; empty for records that
+    /// predate node identities.
+    #[serde(default)]
+    pub author_id: String,
     pub file: String,
     pub hunk_id: String,
     pub line: usize,
+    /// Which file `line` counts in: the old one for removed lines.
+    #[serde(default)]
+    pub side: Side,
     pub body: String,
     pub created_at: DateTime<Utc>,
     pub resolved: bool,
+    /// When `resolved` last changed, so copies merge on the newest change.
+    /// `None` if it never has.
+    #[serde(default)]
+    pub resolved_at: Option<DateTime<Utc>>,
+    /// Base58 signature by the author's node key over `signing_payload`.
+    #[serde(default)]
+    pub signature: String,
+}
+
+impl Comment {
+    /// The diff line the comment is on, as [`HunkLine::anchor`] gives it.
+    pub fn anchor(&self) -> (Side, usize) {
+        (self.side, self.line)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -27,8 +222,12 @@ pub struct ChatLine {
     pub id: String,
     pub session_id: String,
     pub author: String,
+    #[serde(default)]
+    pub author_id: String,
     pub body: String,
     pub created_at: DateTime<Utc>,
+    #[serde(default)]
+    pub signature: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -42,6 +241,546 @@ pub struct DiffHunk {
     pub content: String,
 }
 
+/// The diff a session reviews, published by its owner so every reviewer sees
+/// the same hunks and ids whatever they have checked out.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SharedDiff {
+    pub session_id: String,
+    /// Branch the owner diffed against.
+    pub target_branch: String,
+    /// Commit the owner had checked out, used to notice when the target
+    /// branch has merged it. Empty in diffs shared by older versions.
+    #[serde(default)]
+    pub head_commit: String,
+    pub hunks: Vec<DiffHunk>,
+    pub author_id: String,
+    pub created_at: DateTime<Utc>,
+    #[serde(default)]
+    pub signature: String,
+}
+
+/// A reviewer marking a comment resolved, or reopening it.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Resolution {
+    pub comment_id: String,
+    pub session_id: String,
+    pub resolved: bool,
+    pub author: String,
+    pub author_id: String,
+    pub created_at: DateTime<Utc>,
+    #[serde(default)]
+    pub signature: String,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Decision {
+    Approve,
+    RequestChanges,
+}
+
+impl Decision {
+    /// The spelling used on the command line and in storage.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Decision::Approve => "approve",
+            Decision::RequestChanges => "request-changes",
+        }
+    }
+}
+
+impl std::fmt::Display for Decision {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Decision::Approve => "approved",
+            Decision::RequestChanges => "changes requested",
+        })
+    }
+}
+
+impl std::str::FromStr for Decision {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "approve" => Ok(Decision::Approve),
+            "request-changes" => Ok(Decision::RequestChanges),
+            _ => Err(format!("unknown decision {}; expected approve or request-changes", s)),
+        }
+    }
+}
+
+/// Asks a session's owner to admit the sending node.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct JoinRequest {
+    pub session_id: String,
+    /// Proof of holding the owner's invite token, bound to `author_id`.
+    /// The token itself stays off the mesh.
+    pub proof: String,
+    pub author: String,
+    pub author_id: String,
+    pub created_at: DateTime<Utc>,
+    #[serde(default)]
+    pub signature: String,
+}
+
+/// A session key sealed by the owner for a single member.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct KeyGrant {
+    pub session_id: String,
+    /// Keys are replaced whenever a member is revoked; each gets a new epoch.
+    pub epoch: u64,
+    /// PeerId of the member who can open `key`.
+    pub recipient: String,
+    pub key: String,
+    /// PeerId of the session owner.
+    pub author_id: String,
+    pub created_at: DateTime<Utc>,
+    #[serde(default)]
+    pub signature: String,
+}
+
+/// One piece of a message too large for a single floodsub packet. Peers
+/// reassemble the pieces with the same `id` from the same sender.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Fragment {
+    pub id: String,
+    pub index: u32,
+    pub total: u32,
+    /// Base64 of this piece of the serialized message.
+    pub data: String,
+}
+
+/// A message encrypted with a session key. Only members holding the key
+/// for `epoch` can read what is inside.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Sealed {
+    pub session_id: String,
+    pub epoch: u64,
+    pub nonce: String,
+    pub ciphertext: String,
+}
+
+/// A reviewer's overall decision on a session.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Verdict {
+    pub id: String,
+    pub session_id: String,
+    pub author: String,
+    pub author_id: String,
+    pub decision: Decision,
+    pub body: String,
+    pub created_at: DateTime<Utc>,
+    #[serde(default)]
+    pub signature: String,
+}
+
+/// Heartbeat a reviewer's node sends while a session is open, saying where
+/// they are looking. Never stored; peers forget it once heartbeats stop.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Presence {
+    pub session_id: String,
+    pub author: String,
+    pub author_id: String,
+    /// File and line under the reviewer's cursor in the diff pane.
+    pub file: Option<String>,
+    pub line: Option<usize>,
+    /// Whether they have an unsent chat message in the input box.
+    pub typing: bool,
+    /// Whether they are leading a walkthrough that others' diff panes follow.
+    #[serde(default)]
+    pub driving: bool,
+    pub sent_at: DateTime<Utc>,
+    #[serde(default)]
+    pub signature: String,
+}
+
+/// Everything recorded about a session, as moved between stores.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SessionRecords {
+    pub session: ReviewSession,
+    pub diff: Option<SharedDiff>,
+    pub comments: Vec<Comment>,
+    pub chat: Vec<ChatLine>,
+    pub verdicts: Vec<Verdict>,
+}
+
+/// Each reviewer's most recent verdict, in the order they were given.
+/// `verdicts` must be sorted oldest first.
+pub fn latest_verdicts(verdicts: &[Verdict]) -> Vec<&Verdict> {
+    let mut latest: Vec<&Verdict> = vec![];
+    for verdict in verdicts {
+        latest.retain(|v| v.author_id != verdict.author_id);
+        latest.push(verdict);
+    }
+    latest
+}
+
+/// A record signed with its author's node key.
+pub trait Signable {
+    /// The bytes the signature covers: every field fixed when the record is
+    /// created. Mutable state such as `Comment::resolved` is left out and
+    /// changes through its own signed records.
+    fn signing_payload(&self) -> Vec<u8>;
+    fn author_id(&self) -> &str;
+    fn signature(&self) -> &str;
+    fn set_signature(&mut self, signature: String);
+}
+
+impl Signable for ReviewSession {
+    fn signing_payload(&self) -> Vec<u8> {
+        let mut fields = serde_json::json!([
+            "session", self.id, self.title, self.created_at.to_rfc3339(), self.participants,
+            self.owner, self.members, self.revoked, self.revision,
+        ]);
+        // Fields added later are signed only when set, so signatures made
+        // before they existed still verify.
+        let array = fields.as_array_mut().expect("array");
+        if self.state != SessionState::Open {
+            array.push(self.state.as_str().into());
+        }
+        if !self.details.is_empty() {
+            array.push(serde_json::to_value(&self.details).expect("details serialize"));
+        }
+        payload(fields)
+    }
+    /// Only the owner publishes a session.
+    fn author_id(&self) -> &str {
+        &self.owner
+    }
+    fn signature(&self) -> &str {
+        &self.signature
+    }
+    fn set_signature(&mut self, signature: String) {
+        self.signature = signature;
+    }
+}
+
+impl Signable for JoinRequest {
+    fn signing_payload(&self) -> Vec<u8> {
+        payload(serde_json::json!([
+            "join", self.session_id, self.proof, self.author, self.author_id,
+            self.created_at.to_rfc3339(),
+        ]))
+    }
+    fn author_id(&self) -> &str {
+        &self.author_id
+    }
+    fn signature(&self) -> &str {
+        &self.signature
+    }
+    fn set_signature(&mut self, signature: String) {
+        self.signature = signature;
+    }
+}
+
+impl Signable for Membership {
+    fn signing_payload(&self) -> Vec<u8> {
+        payload(serde_json::json!(["membership", self.session_id, self.owner, self.members, self.revision]))
+    }
+    fn author_id(&self) -> &str {
+        &self.owner
+    }
+    fn signature(&self) -> &str {
+        &self.signature
+    }
+    fn set_signature(&mut self, signature: String) {
+        self.signature = signature;
+    }
+}
+
+impl Signable for KeyGrant {
+    fn signing_payload(&self) -> Vec<u8> {
+        payload(serde_json::json!([
+            "key_grant", self.session_id, self.epoch, self.recipient, self.key,
+            self.author_id, self.created_at.to_rfc3339(),
+        ]))
+    }
+    fn author_id(&self) -> &str {
+        &self.author_id
+    }
+    fn signature(&self) -> &str {
+        &self.signature
+    }
+    fn set_signature(&mut self, signature: String) {
+        self.signature = signature;
+    }
+}
+
+impl Signable for Comment {
+    fn signing_payload(&self) -> Vec<u8> {
+        let mut fields = serde_json::json!([
+            "comment", self.id, self.session_id, self.author, self.author_id,
+            self.file, self.hunk_id, self.line, self.body, self.created_at.to_rfc3339(),
+        ]);
+        // Signed only when set, like the session's later fields.
+        if self.side != Side::New {
+            fields.as_array_mut().expect("array").push(self.side.as_str().into());
+        }
+        payload(fields)
+    }
+    fn author_id(&self) -> &str {
+        &self.author_id
+    }
+    fn signature(&self) -> &str {
+        &self.signature
+    }
+    fn set_signature(&mut self, signature: String) {
+        self.signature = signature;
+    }
+}
+
+impl Signable for ChatLine {
+    fn signing_payload(&self) -> Vec<u8> {
+        payload(serde_json::json!([
+            "chat", self.id, self.session_id, self.author, self.author_id,
+            self.body, self.created_at.to_rfc3339(),
+        ]))
+    }
+    fn author_id(&self) -> &str {
+        &self.author_id
+    }
+    fn signature(&self) -> &str {
+        &self.signature
+    }
+    fn set_signature(&mut self, signature: String) {
+        self.signature = signature;
+    }
+}
+
+impl Signable for Resolution {
+    fn signing_payload(&self) -> Vec<u8> {
+        payload(serde_json::json!([
+            "resolution", self.comment_id, self.session_id, self.resolved,
+            self.author, self.author_id, self.created_at.to_rfc3339(),
+        ]))
+    }
+    fn author_id(&self) -> &str {
+        &self.author_id
+    }
+    fn signature(&self) -> &str {
+        &self.signature
+    }
+    fn set_signature(&mut self, signature: String) {
+        self.signature = signature;
+    }
+}
+
+impl Signable for Verdict {
+    fn signing_payload(&self) -> Vec<u8> {
+        payload(serde_json::json!([
+            "verdict", self.id, self.session_id, self.author, self.author_id,
+            self.decision, self.body, self.created_at.to_rfc3339(),
+        ]))
+    }
+    fn author_id(&self) -> &str {
+        &self.author_id
+    }
+    fn signature(&self) -> &str {
+        &self.signature
+    }
+    fn set_signature(&mut self, signature: String) {
+        self.signature = signature;
+    }
+}
+
+impl Signable for SharedDiff {
+    fn signing_payload(&self) -> Vec<u8> {
+        let mut fields = serde_json::json!([
+            "diff", self.session_id, self.target_branch, self.hunks, self.author_id,
+            self.created_at.to_rfc3339(),
+        ]);
+        if !self.head_commit.is_empty() {
+            fields.as_array_mut().expect("array").push(self.head_commit.clone().into());
+        }
+        payload(fields)
+    }
+    fn author_id(&self) -> &str {
+        &self.author_id
+    }
+    fn signature(&self) -> &str {
+        &self.signature
+    }
+    fn set_signature(&mut self, signature: String) {
+        self.signature = signature;
+    }
+}
+
+impl Signable for Presence {
+    fn signing_payload(&self) -> Vec<u8> {
+        payload(serde_json::json!([
+            "presence", self.session_id, self.author, self.author_id,
+            self.file, self.line, self.typing, self.driving, self.sent_at.to_rfc3339(),
+        ]))
+    }
+    fn author_id(&self) -> &str {
+        &self.author_id
+    }
+    fn signature(&self) -> &str {
+        &self.signature
+    }
+    fn set_signature(&mut self, signature: String) {
+        self.signature = signature;
+    }
+}
+
+fn payload(fields: serde_json::Value) -> Vec<u8> {
+    serde_json::to_vec(&fields).expect("JSON arrays always serialize")
+}
+
+/// Everything published on the review topic.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MeshMessage {
+    Session(ReviewSession),
+    Membership(Membership),
+    Comment(Comment),
+    Chat(ChatLine),
+    Resolution(Resolution),
+    Verdict(Verdict),
+    Join(JoinRequest),
+    KeyGrant(KeyGrant),
+    Presence(Presence),
+    Diff(SharedDiff),
+    Sealed(Sealed),
+    Fragment(Fragment),
+}
+
+impl MeshMessage {
+    /// The signed record carried by the message, if it is authored content.
+    pub fn signed(&self) -> Option<&dyn Signable> {
+        match self {
+            MeshMessage::Session(s) if s.owner.is_empty() => None,
+            MeshMessage::Session(s) => Some(s),
+            MeshMessage::Membership(m) => Some(m),
+            MeshMessage::Comment(c) => Some(c),
+            MeshMessage::Chat(c) => Some(c),
+            MeshMessage::Resolution(r) => Some(r),
+            MeshMessage::Verdict(v) => Some(v),
+            MeshMessage::Join(j) => Some(j),
+            MeshMessage::KeyGrant(k) => Some(k),
+            MeshMessage::Presence(p) => Some(p),
+            MeshMessage::Diff(d) => Some(d),
+            MeshMessage::Sealed(_) | MeshMessage::Fragment(_) => None,
+        }
+    }
+
+    /// The session whose members may read the message, if it carries review
+    /// content that should be sealed with the session key. The session is,
+    /// as its title and details are review content. Memberships, join
+    /// requests and key grants must stay readable to non-members.
+    pub fn sealed_session(&self) -> Option<&str> {
+        match self {
+            MeshMessage::Session(s) => Some(&s.id),
+            MeshMessage::Comment(c) => Some(&c.session_id),
+            MeshMessage::Chat(c) => Some(&c.session_id),
+            MeshMessage::Resolution(r) => Some(&r.session_id),
+            MeshMessage::Verdict(v) => Some(&v.session_id),
+            MeshMessage::Presence(p) => Some(&p.session_id),
+            MeshMessage::Diff(d) => Some(&d.session_id),
+            MeshMessage::Membership(_)
+            | MeshMessage::Join(_)
+            | MeshMessage::KeyGrant(_)
+            | MeshMessage::Sealed(_)
+            | MeshMessage::Fragment(_) => None,
+        }
+    }
+
+    /// PeerId the message claims to come from, if it is authored content.
+    pub fn author_id(&self) -> Option<&str> {
+        self.signed().map(|s| s.author_id())
+    }
+}
+
+/// The file a diff line number counts in.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Side {
+    Old,
+    #[default]
+    New,
+}
+
+impl Side {
+    /// The spelling used in storage.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Side::Old => "old",
+            Side::New => "new",
+        }
+    }
+}
+
+impl std::str::FromStr for Side {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "old" => Ok(Side::Old),
+            "new" => Ok(Side::New),
+            _ => Err(format!("unknown side {}; expected old or new", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineKind {
+    Context,
+    Added,
+    Removed,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HunkLine<'a> {
+    pub kind: LineKind,
+    pub old_line: Option<usize>,
+    pub new_line: Option<usize>,
+    pub text: &'a str,
+}
+
+impl HunkLine<'_> {
+    /// Where a comment on this line is anchored: the new side when the line
+    /// still exists, the old side for removals.
+    pub fn anchor(&self) -> (Side, usize) {
+        match (self.new_line, self.old_line) {
+            (Some(line), _) => (Side::New, line),
+            (None, Some(line)) => (Side::Old, line),
+            (None, None) => (Side::New, 0),
+        }
+    }
+}
+
+impl DiffHunk {
+    /// Splits `content` into its diff lines, skipping the `@@` header.
+    pub fn lines(&self) -> Vec<HunkLine<'_>> {
+        let mut old_line = self.old_start;
+        let mut new_line = self.new_start;
+        let mut lines = vec![];
+        for raw in self.content.lines().filter(|l| !l.starts_with("@@")) {
+            let (kind, text) = match raw.chars().next() {
+                Some('+') => (LineKind::Added, &raw[1..]),
+                Some('-') => (LineKind::Removed, &raw[1..]),
+                Some(' ') => (LineKind::Context, &raw[1..]),
+                // "\ No newline at end of file" and friends
+                _ => continue,
+            };
+            let (old, new) = match kind {
+                LineKind::Context => (Some(old_line), Some(new_line)),
+                LineKind::Added => (None, Some(new_line)),
+                LineKind::Removed => (Some(old_line), None),
+            };
+            if old.is_some() {
+                old_line += 1;
+            }
+            if new.is_some() {
+                new_line += 1;
+            }
+            lines.push(HunkLine { kind, old_line: old, new_line: new, text });
+        }
+        lines
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -53,11 +792,104 @@ mod tests {
             id: "1".to_string(),
             session_id: "sess1".to_string(),
             author: "alice".to_string(),
+            author_id: "12D3KooWalice".to_string(),
             body: "Hello!".to_string(),
             created_at: Utc.timestamp_opt(1_600_000_000, 0).unwrap(),
+            signature: String::new(),
         };
         let json = serde_json::to_string(&chat).unwrap();
         let de: ChatLine = serde_json::from_str(&json).unwrap();
         assert_eq!(chat, de);
     }
+
+    #[test]
+    fn mesh_message_is_tagged() {
+        let chat = ChatLine {
+            id: "1".to_string(),
+            session_id: "sess1".to_string(),
+            author: "alice".to_string(),
+            author_id: "12D3KooWalice".to_string(),
+            body: "Hello!".to_string(),
+            created_at: Utc.timestamp_opt(1_600_000_000, 0).unwrap(),
+            signature: String::new(),
+        };
+        let json = serde_json::to_value(MeshMessage::Chat(chat.clone())).unwrap();
+        assert_eq!(json["type"], "chat");
+        let de: MeshMessage = serde_json::from_value(json).unwrap();
+        assert_eq!(de.author_id(), Some("12D3KooWalice"));
+        assert_eq!(de, MeshMessage::Chat(chat));
+    }
+
+    #[test]
+    fn session_payload_signs_later_fields_only_when_set() {
+        let mut session = ReviewSession {
+            id: "sess1".to_string(),
+            title: "Login".to_string(),
+            created_at: Utc.timestamp_opt(1_600_000_000, 0).unwrap(),
+            participants: vec!["alice".to_string()],
+            owner: "12D3KooWalice".to_string(),
+            members: vec![],
+            revoked: vec![],
+            revision: 1,
+            state: SessionState::Open,
+            details: SessionDetails::default(),
+            signature: String::new(),
+        };
+        let original = payload(serde_json::json!([
+            "session", "sess1", "Login", "2020-09-13T12:26:40+00:00", ["alice"], "12D3KooWalice", [], [], 1,
+        ]));
+        assert_eq!(session.signing_payload(), original);
+
+        session.details.labels.push("security".to_string());
+        let labelled = session.signing_payload();
+        session.state = SessionState::Approved;
+        assert_ne!(labelled, original);
+        assert_ne!(session.signing_payload(), labelled);
+    }
+
+    #[test]
+    fn sessions_are_sealed_but_their_membership_is_not() {
+        let session = ReviewSession {
+            id: "sess1".to_string(),
+            title: "Login".to_string(),
+            created_at: Utc.timestamp_opt(1_600_000_000, 0).unwrap(),
+            participants: vec!["alice".to_string()],
+            owner: "12D3KooWalice".to_string(),
+            members: vec!["12D3KooWalice".to_string(), "12D3KooWbob".to_string()],
+            revoked: vec![],
+            revision: 2,
+            state: SessionState::Open,
+            details: SessionDetails::default(),
+            signature: String::new(),
+        };
+        let membership = MeshMessage::Membership(session.membership());
+        assert_eq!(MeshMessage::Session(session.clone()).sealed_session(), Some("sess1"));
+        assert_eq!(membership.sealed_session(), None);
+        assert_eq!(membership.author_id(), Some("12D3KooWalice"));
+
+        let json = serde_json::to_value(&membership).unwrap();
+        assert_eq!(json["type"], "membership");
+        assert!(json.get("title").is_none() && json.get("details").is_none());
+    }
+
+    #[test]
+    fn hunk_lines_track_line_numbers() {
+        let hunk = DiffHunk {
+            id: "h".to_string(),
+            file: "src/lib.rs".to_string(),
+            old_start: 10,
+            old_lines: 3,
+            new_start: 10,
+            new_lines: 3,
+            content: "@@ -10,3 +10,3 @@\n a\n-b\n+c\n d\n".to_string(),
+        };
+        let lines = hunk.lines();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[1].kind, LineKind::Removed);
+        assert_eq!((lines[1].old_line, lines[1].new_line), (Some(11), None));
+        assert_eq!((lines[2].old_line, lines[2].new_line), (None, Some(11)));
+        assert_eq!(lines[1].anchor(), (Side::Old, 11));
+        assert_eq!(lines[2].anchor(), (Side::New, 11));
+        assert_eq!(lines[3].anchor(), (Side::New, 12));
+    }
 }
@@ -0,0 +1,114 @@
+use std::error::Error;
+use std::path::Path;
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use git2::Oid;
+
+use crate::error::{branch_commit, discover};
+
+/// Where `create_bundle` points a temporary ref at the reviewed commit, one
+/// directory per call so concurrent bundles don't trip over each other.
+const BUNDLE_REFS: &str = "refs/reviewmesh-bundle/";
+
+/// Writes a git bundle of the reviewed range to `out`: `head`, the commit
+/// that was reviewed, as `branch`, and `target_branch`, with the commits
+/// since their merge base. Whatever is checked out now doesn't matter.
+/// libgit2 can't write bundles, so this runs the `git` executable.
+pub fn create_bundle(repo_path: &str, head: &str, branch: &str, target_branch: &str, out: &Path) -> Result<(), Box<dyn Error>> {
+    static BUNDLES: AtomicUsize = AtomicUsize::new(0);
+    let repo = discover(repo_path)?;
+    let head_commit = repo
+        .find_commit(Oid::from_str(head)?)
+        .map_err(|_| format!("the reviewed commit {} is no longer in the repository", head))?
+        .id();
+    let target_commit = branch_commit(&repo, target_branch)?.id();
+    let target = repo.resolve_reference_from_short_name(target_branch)?;
+    let target_ref = target.name().ok_or("target branch is not valid UTF-8")?.to_string();
+
+    // Bundles carry refs, not bare commits, so the commit needs a name.
+    let head_ref = format!("{}{}-{}/{}", BUNDLE_REFS, std::process::id(), BUNDLES.fetch_add(1, Ordering::Relaxed), branch);
+    let mut reference = repo.reference(&head_ref, head_commit, true, "reviewmesh: bundle")?;
+    let mut args = vec!["bundle".to_string(), "create".to_string(), out.display().to_string(), head_ref, target_ref];
+    // Reviewers already have the history both sides share.
+    if let Ok(base) = repo.merge_base(head_commit, target_commit) {
+        args.push(format!("^{}", base));
+    }
+    let created = git(repo_path, &args);
+    reference.delete()?;
+    created?;
+    Ok(())
+}
+
+/// Fetches the branches in `bundle` into `refs/heads/<prefix>/...`, returning
+/// the local branch names created or updated.
+pub fn import_bundle(repo_path: &str, bundle: &Path, prefix: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let bundle = bundle.display().to_string();
+    // Fails with a readable message when the prerequisite commits are missing.
+    git(repo_path, &["bundle", "verify", &bundle])?;
+    let heads = git(repo_path, &["bundle", "list-heads", &bundle])?;
+
+    let mut branches = vec![];
+    let mut args = vec!["fetch".to_string(), bundle];
+    for reference in heads.lines().filter_map(|line| line.split_whitespace().nth(1)) {
+        let name = reference
+            .strip_prefix("refs/heads/")
+            .or_else(|| reference.strip_prefix("refs/remotes/"))
+            .or_else(|| reference.strip_prefix(BUNDLE_REFS).and_then(|r| r.split_once('/')).map(|(_, name)| name))
+            .unwrap_or(reference);
+        if name == "HEAD" {
+            continue;
+        }
+        let branch = format!("{}/{}", prefix, name);
+        args.push(format!("+{}:refs/heads/{}", reference, branch));
+        branches.push(branch);
+    }
+    if branches.is_empty() {
+        return Err("the bundle has no branches".into());
+    }
+    git(repo_path, &args)?;
+    Ok(branches)
+}
+
+fn git<S: AsRef<str>>(repo_path: &str, args: &[S]) -> Result<String, Box<dyn Error>> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(repo_path)
+        .args(args.iter().map(AsRef::as_ref))
+        .output()
+        .map_err(|e| format!("could not run git: {}", e))?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string().into());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::testing::{self, init_repo, TempDir};
+
+    #[test]
+    fn bundle_carries_branch_to_repo_without_it() {
+        let root = TempDir::new("bundle");
+        let (author, reviewer) = (root.join("author"), root.join("reviewer"));
+        init_repo(&author);
+        testing::git(&author, &["checkout", "-b", "feature"]);
+        testing::git(&author, &["commit", "--allow-empty", "-m", "change"]);
+        let reviewed = testing::git(&author, &["rev-parse", "HEAD"]);
+        // The owner has moved on since opening the review.
+        testing::git(&author, &["commit", "--allow-empty", "-m", "later"]);
+        testing::git(&author, &["checkout", "main"]);
+        testing::git(root.path(), &["clone", "--single-branch", "-b", "main", author.to_str().unwrap(), "reviewer"]);
+
+        let bundle = root.join("review.bundle");
+        create_bundle(author.to_str().unwrap(), reviewed.trim(), "feature", "main", &bundle).unwrap();
+        let leftover = testing::git(&author, &["for-each-ref", BUNDLE_REFS]);
+        let branches = import_bundle(reviewer.to_str().unwrap(), &bundle, "review/s1").unwrap();
+        let log = testing::git(&reviewer, &["log", "--format=%s", "review/s1/feature"]);
+
+        assert!(branches.contains(&"review/s1/feature".to_string()));
+        assert_eq!(log, "change\nbase\n");
+        assert!(leftover.is_empty());
+    }
+}
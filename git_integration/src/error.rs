@@ -0,0 +1,157 @@
+use std::fmt;
+
+use git2::{BranchType, ErrorCode, Repository};
+
+/// Why a git operation failed, worded for the person running the command.
+#[derive(Debug)]
+pub enum GitError {
+    /// No repository at or above the path.
+    NotARepository(String),
+    /// The repository has no commits yet.
+    NoCommits,
+    /// No branch, tag or commit goes by this name. Carries similar ref names.
+    BranchNotFound { branch: String, suggestions: Vec<String> },
+    Git(git2::Error),
+}
+
+impl fmt::Display for GitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GitError::NotARepository(path) => write!(f, "no git repository found at or above {}", path),
+            GitError::NoCommits => write!(f, "the repository has no commits yet"),
+            GitError::BranchNotFound { branch, suggestions } => {
+                write!(f, "branch {} not found", branch)?;
+                match suggestions.as_slice() {
+                    [] => Ok(()),
+                    [only] => write!(f, "; did you mean {}?", only),
+                    many => write!(f, "; did you mean one of {}?", many.join(", ")),
+                }
+            }
+            GitError::Git(e) => write!(f, "{}", e.message()),
+        }
+    }
+}
+
+impl std::error::Error for GitError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            GitError::Git(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<git2::Error> for GitError {
+    fn from(e: git2::Error) -> Self {
+        GitError::Git(e)
+    }
+}
+
+/// The repository containing `path`.
+pub(crate) fn discover(path: &str) -> Result<Repository, GitError> {
+    Repository::discover(path).map_err(|e| match e.code() {
+        ErrorCode::NotFound => GitError::NotARepository(path.to_string()),
+        _ => GitError::Git(e),
+    })
+}
+
+/// The commit HEAD points at.
+pub(crate) fn head_commit(repo: &Repository) -> Result<git2::Commit<'_>, GitError> {
+    match repo.head() {
+        Ok(head) => Ok(head.peel_to_commit()?),
+        Err(e) if matches!(e.code(), ErrorCode::UnbornBranch | ErrorCode::NotFound) => Err(GitError::NoCommits),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The commit `branch` names, which may also be a tag, remote branch or
+/// any other revision git understands.
+pub(crate) fn branch_commit<'r>(repo: &'r Repository, branch: &str) -> Result<git2::Commit<'r>, GitError> {
+    match repo.revparse_single(branch) {
+        Ok(object) => Ok(object.peel_to_commit()?),
+        Err(e) if matches!(e.code(), ErrorCode::NotFound | ErrorCode::InvalidSpec | ErrorCode::Ambiguous) => {
+            Err(GitError::BranchNotFound { branch: branch.to_string(), suggestions: suggestions(repo, branch) })
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+const MAX_SUGGESTIONS: usize = 3;
+
+/// Branch and tag names close to `wanted`: the same branch on a remote
+/// first, then names a few typos away.
+fn suggestions(repo: &Repository, wanted: &str) -> Vec<String> {
+    let mut names = vec![];
+    if let Ok(branches) = repo.branches(None) {
+        for (branch, kind) in branches.flatten() {
+            if let Ok(Some(name)) = branch.name() {
+                // A remote's HEAD is an alias, not a branch worth suggesting.
+                if !(kind == BranchType::Remote && name.ends_with("/HEAD")) {
+                    names.push(name.to_string());
+                }
+            }
+        }
+    }
+    if let Ok(tags) = repo.tag_names(None) {
+        names.extend(tags.iter().flatten().map(str::to_string));
+    }
+
+    let wanted_lower = wanted.to_lowercase();
+    let limit = (wanted.len() / 3).max(2);
+    let mut ranked: Vec<(usize, String)> = names
+        .into_iter()
+        .filter_map(|name| {
+            let rank = if name.ends_with(&format!("/{}", wanted)) {
+                0
+            } else {
+                let distance = edit_distance(&name.to_lowercase(), &wanted_lower);
+                if distance > limit {
+                    return None;
+                }
+                distance + 1
+            };
+            Some((rank, name))
+        })
+        .collect();
+    ranked.sort();
+    ranked.into_iter().take(MAX_SUGGESTIONS).map(|(_, name)| name).collect()
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = if ca == *cb { diagonal } else { 1 + diagonal.min(above).min(row[j]) };
+            diagonal = above;
+        }
+    }
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::testing::{git, init_repo, TempDir};
+
+    #[test]
+    fn missing_branch_suggests_remote_and_near_names() {
+        let root = TempDir::new("suggest");
+        let origin = root.join("origin");
+        init_repo(&origin);
+        git(&origin, &["branch", "feature/login"]);
+        git(root.path(), &["clone", "-q", origin.to_str().unwrap(), "clone"]);
+        let repo = Repository::open(root.join("clone")).unwrap();
+
+        let remote = branch_commit(&repo, "feature/login").map(|_| ()).unwrap_err().to_string();
+        let typo = branch_commit(&repo, "mian").map(|_| ()).unwrap_err().to_string();
+        let unrelated = branch_commit(&repo, "release-2024").map(|_| ()).unwrap_err().to_string();
+
+        assert_eq!(remote, "branch feature/login not found; did you mean origin/feature/login?");
+        assert_eq!(typo, "branch mian not found; did you mean main?");
+        assert_eq!(unrelated, "branch release-2024 not found");
+    }
+}
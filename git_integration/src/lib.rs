@@ -1,31 +1,27 @@
-use git2::{Repository, DiffOptions, DiffHunk as Git2Hunk, DiffLine, Oid};
+use git2::{Repository, DiffOptions, Oid};
 use common::DiffHunk;
 use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
 use std::cell::RefCell;
+use std::path::PathBuf;
 
-pub fn compute_diff(repo_path: &str, target_branch: &str) -> Vec<DiffHunk> {
-    let repo = match Repository::open(repo_path) {
-        Ok(r) => r,
-        Err(_) => return vec![],
-    };
-    let head = match repo.head().and_then(|h| h.peel_to_commit()) {
-        Ok(c) => c,
-        Err(_) => return vec![],
-    };
-    let target = match repo.revparse_single(target_branch).and_then(|o| o.peel_to_commit()) {
-        Ok(c) => c,
-        Err(_) => return vec![],
-    };
+pub mod bundle;
+mod error;
+pub mod owners;
+pub mod refs;
+
+pub use error::GitError;
+use error::{branch_commit, discover, head_commit};
+
+/// The changes on HEAD since `target_branch`, found by discovering the
+/// repository from `repo_path`, so any subdirectory, linked worktree or bare
+/// repository works.
+pub fn compute_diff(repo_path: &str, target_branch: &str) -> Result<Vec<DiffHunk>, GitError> {
+    let repo = discover(repo_path)?;
+    let head = head_commit(&repo)?;
+    let target = branch_commit(&repo, target_branch)?;
     let mut diff_opts = DiffOptions::new();
-    let diff = match repo.diff_tree_to_tree(
-        Some(&target.tree().unwrap()),
-        Some(&head.tree().unwrap()),
-        Some(&mut diff_opts),
-    ) {
-        Ok(d) => d,
-        Err(_) => return vec![],
-    };
+    let diff = repo.diff_tree_to_tree(Some(&target.tree()?), Some(&head.tree()?), Some(&mut diff_opts))?;
     struct State {
         file_path: String,
         hunk_content: String,
@@ -47,13 +43,9 @@ pub fn compute_diff(repo_path: &str, target_branch: &str) -> Vec<DiffHunk> {
         hunks: vec![],
     });
     diff.foreach(
-        &mut |file, _| {
-            let mut s = state.borrow_mut();
-            s.file_path = file.new_file().path().map(|p| p.to_string_lossy().to_string()).unwrap_or_default();
-            true
-        },
+        &mut |_, _| true,
         None,
-        Some(&mut |_, hunk| {
+        Some(&mut |file, hunk| {
             let mut s = state.borrow_mut();
             if s.in_hunk {
                 let id = hunk_id(&s.file_path, s.old_start, s.new_start, &s.hunk_content);
@@ -75,6 +67,9 @@ pub fn compute_diff(repo_path: &str, target_branch: &str) -> Vec<DiffHunk> {
                 });
                 s.hunk_content.clear();
             }
+            // Only now, with the previous file's last hunk pushed, is it
+            // safe to move on to this hunk's file.
+            s.file_path = file.new_file().path().map(|p| p.to_string_lossy().to_string()).unwrap_or_default();
             s.in_hunk = true;
             s.old_start = hunk.old_start() as usize;
             s.old_lines = hunk.old_lines() as usize;
@@ -90,12 +85,12 @@ pub fn compute_diff(repo_path: &str, target_branch: &str) -> Vec<DiffHunk> {
         Some(&mut |_, _, line| {
             let mut s = state.borrow_mut();
             if s.in_hunk {
-                s.hunk_content.push(line.origin() as char);
+                s.hunk_content.push(line.origin());
                 s.hunk_content.push_str(&String::from_utf8_lossy(line.content()));
             }
             true
         }),
-    ).unwrap_or(());
+    )?;
     let mut s = state.borrow_mut();
     if s.in_hunk && !s.hunk_content.is_empty() {
         let id = hunk_id(&s.file_path, s.old_start, s.new_start, &s.hunk_content);
@@ -116,7 +111,7 @@ pub fn compute_diff(repo_path: &str, target_branch: &str) -> Vec<DiffHunk> {
             content,
         });
     }
-    s.hunks.clone()
+    Ok(s.hunks.clone())
 }
 
 pub fn hunk_id(file: &str, old_start: usize, new_start: usize, content: &str) -> String {
@@ -127,3 +122,190 @@ pub fn hunk_id(file: &str, old_start: usize, new_start: usize, content: &str) ->
     content.hash(&mut hasher);
     format!("{:x}", hasher.finish())
 }
+
+/// `user.name` and `user.email` from the repository's git config, falling back
+/// to the global config outside a repository.
+pub fn git_user(repo_path: &str) -> (Option<String>, Option<String>) {
+    let config = Repository::discover(repo_path)
+        .and_then(|repo| repo.config())
+        .or_else(|_| git2::Config::open_default());
+    match config.and_then(|mut c| c.snapshot()) {
+        Ok(config) => (
+            config.get_string("user.name").ok(),
+            config.get_string("user.email").ok(),
+        ),
+        Err(_) => (None, None),
+    }
+}
+
+/// The git directory shared by every worktree of the repository containing
+/// `path`; the repository itself when it is bare.
+pub fn common_dir(path: &str) -> Result<PathBuf, GitError> {
+    let repo = discover(path)?;
+    let git_dir = repo.path();
+    // A linked worktree's git directory names the main one in `commondir`.
+    match std::fs::read_to_string(git_dir.join("commondir")) {
+        Ok(common) if repo.is_worktree() => Ok(git_dir.join(common.trim())),
+        _ => Ok(git_dir.to_path_buf()),
+    }
+}
+
+/// Where [`repo_identity`] keeps its answer, beside ReviewMesh's database:
+/// the HEAD it was worked out at and the root commit found from there.
+const IDENTITY_FILE: &str = "reviewmesh/repository";
+
+/// A name for the repository containing `path` that every clone agrees on:
+/// the root commit reached from HEAD. Walking the history is slow on large
+/// repositories, so the answer is cached under the git directory and reused
+/// while HEAD still descends from where it was found. A rewritten or
+/// re-rooted history doesn't, so it is walked again. `None` before the
+/// first commit.
+pub fn repo_identity(path: &str) -> Result<Option<String>, GitError> {
+    let repo = discover(path)?;
+    let head = match head_commit(&repo) {
+        Ok(commit) => commit,
+        Err(GitError::NoCommits) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let cache = common_dir(path)?.join(IDENTITY_FILE);
+    let cached = std::fs::read_to_string(&cache).ok().and_then(|text| {
+        let (seen, root) = text.trim().split_once(' ')?;
+        Some((Oid::from_str(seen).ok()?, root.to_string()))
+    });
+    if let Some((seen, root)) = cached {
+        if seen == head.id() || repo.graph_descendant_of(head.id(), seen).unwrap_or(false) {
+            return Ok(Some(root));
+        }
+    }
+    let seen = head.id();
+    let mut commit = head;
+    while let Ok(parent) = commit.parent(0) {
+        commit = parent;
+    }
+    let id = commit.id().to_string();
+    // A read-only repository still gets an answer, just not a cached one.
+    let _ = std::fs::create_dir_all(cache.parent().expect("cache has a parent"))
+        .and_then(|_| std::fs::write(&cache, format!("{} {}\n", seen, id)));
+    Ok(Some(id))
+}
+
+/// The commit HEAD points at in the repository containing `repo_path`.
+pub fn head_id(repo_path: &str) -> Result<String, GitError> {
+    let repo = discover(repo_path)?;
+    let id = head_commit(&repo)?.id();
+    Ok(id.to_string())
+}
+
+/// The branch checked out in the repository containing `repo_path`, or
+/// `None` on a detached HEAD.
+pub fn head_branch(repo_path: &str) -> Result<Option<String>, GitError> {
+    let repo = discover(repo_path)?;
+    head_commit(&repo)?;
+    let head = repo.head()?;
+    Ok(head.is_branch().then(|| head.shorthand().map(str::to_string)).flatten())
+}
+
+/// The commit `branch` points at.
+pub fn branch_id(repo_path: &str, branch: &str) -> Result<String, GitError> {
+    let repo = discover(repo_path)?;
+    let id = branch_commit(&repo, branch)?.id();
+    Ok(id.to_string())
+}
+
+/// Whether `target_branch` contains `commit`. A commit this clone hasn't
+/// fetched can't be merged as far as it knows, so that is `false` too.
+pub fn is_merged(repo_path: &str, commit: &str, target_branch: &str) -> Result<bool, GitError> {
+    let repo = discover(repo_path)?;
+    let target = branch_commit(&repo, target_branch)?.id();
+    let commit = match Oid::from_str(commit).and_then(|id| repo.find_commit(id)) {
+        Ok(commit) => commit.id(),
+        Err(_) => return Ok(false),
+    };
+    Ok(target == commit || repo.graph_descendant_of(target, commit)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::testing::{git, init_repo, TempDir};
+
+    #[test]
+    fn diff_is_found_from_subdirectories_and_worktrees() {
+        let root = TempDir::new("discover");
+        let (main, worktree) = (root.join("main"), root.join("worktree"));
+        std::fs::create_dir_all(main.join("src")).unwrap();
+        git(&main, &["init", "-b", "main"]);
+        std::fs::write(main.join("src/lib.rs"), "old\n").unwrap();
+        git(&main, &["add", "."]);
+        git(&main, &["commit", "-m", "base"]);
+        git(&main, &["worktree", "add", "-b", "feature", worktree.to_str().unwrap()]);
+        std::fs::write(worktree.join("src/lib.rs"), "new\n").unwrap();
+        git(&worktree, &["commit", "-am", "change"]);
+
+        let from_worktree = compute_diff(worktree.join("src").to_str().unwrap(), "main");
+        let from_main = compute_diff(main.join("src").to_str().unwrap(), "feature");
+        let missing = compute_diff(main.to_str().unwrap(), "no-such-branch");
+
+        assert_eq!(from_worktree.unwrap().len(), 1);
+        assert_eq!(from_main.unwrap().len(), 1);
+        assert!(missing.is_err());
+    }
+
+    #[test]
+    fn merged_once_the_target_branch_contains_the_commit() {
+        let root = TempDir::new("merged");
+        init_repo(root.path());
+        git(root.path(), &["checkout", "-b", "feature"]);
+        git(root.path(), &["commit", "--allow-empty", "-m", "change"]);
+        let path = root.path().to_str().unwrap();
+        let head = head_id(path).unwrap();
+
+        let before = is_merged(path, &head, "main").unwrap();
+        git(root.path(), &["checkout", "main"]);
+        git(root.path(), &["merge", "--no-ff", "-m", "merge", "feature"]);
+        let after = is_merged(path, &head, "main").unwrap();
+        let unknown = is_merged(path, &"0".repeat(40), "main").unwrap();
+
+        assert!(!before);
+        assert!(after);
+        assert!(!unknown);
+    }
+
+    #[test]
+    fn identity_follows_a_rewritten_history() {
+        let root = TempDir::new("identity");
+        init_repo(root.path());
+        let path = root.path().to_str().unwrap();
+        let first = git(root.path(), &["rev-parse", "HEAD"]).trim().to_string();
+        assert_eq!(repo_identity(path).unwrap(), Some(first.clone()));
+        git(root.path(), &["commit", "--allow-empty", "-m", "next"]);
+        assert_eq!(repo_identity(path).unwrap(), Some(first.clone()));
+
+        // A new root, as after a history rewrite.
+        git(root.path(), &["checkout", "--orphan", "rewritten"]);
+        git(root.path(), &["commit", "--allow-empty", "-m", "base again"]);
+        let second = git(root.path(), &["rev-parse", "HEAD"]).trim().to_string();
+        assert_ne!(first, second);
+        assert_eq!(repo_identity(path).unwrap(), Some(second));
+        assert!(git(root.path(), &["config", "--local", "--list"]).lines().all(|l| !l.starts_with("reviewmesh.")));
+    }
+
+    #[test]
+    fn each_hunk_keeps_its_own_file() {
+        let root = TempDir::new("files");
+        init_repo(root.path());
+        std::fs::write(root.join("a.txt"), "a\n").unwrap();
+        std::fs::write(root.join("b.txt"), "b\n").unwrap();
+        git(root.path(), &["add", "."]);
+        git(root.path(), &["commit", "-m", "files"]);
+        git(root.path(), &["checkout", "-b", "feature"]);
+        std::fs::write(root.join("a.txt"), "a2\n").unwrap();
+        std::fs::write(root.join("b.txt"), "b2\n").unwrap();
+        git(root.path(), &["commit", "-am", "change"]);
+
+        let hunks = compute_diff(root.path().to_str().unwrap(), "main").unwrap();
+        let files: Vec<&str> = hunks.iter().map(|h| h.file.as_str()).collect();
+        assert_eq!(files, ["a.txt", "b.txt"]);
+        assert!(hunks[0].content.contains("+a2"));
+    }
+}
@@ -0,0 +1,195 @@
+use std::path::Path;
+
+use common::{latest_verdicts, Decision, DiffHunk, Verdict};
+
+use crate::error::{branch_commit, discover, GitError};
+
+/// Where the rules live in the repository. The format follows CODEOWNERS:
+/// each line is a path pattern followed by the reviewers who own matching
+/// files, and the last matching line wins. Reviewers are PeerIds, with an
+/// optional leading `@`; display names are whatever a node calls itself, so
+/// they can't say who approved.
+///
+/// ```text
+/// # Everything else
+/// *               12D3KooWAlice...
+/// /src/net/       12D3KooWBob... 12D3KooWCarol...
+/// /docs/          # nobody needs to approve docs
+/// ```
+pub const RULES_FILE: &str = ".reviewmesh/CODEOWNERS";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OwnerRules {
+    rules: Vec<(String, Vec<String>)>,
+}
+
+impl OwnerRules {
+    pub fn parse(text: &str) -> Self {
+        let rules = text
+            .lines()
+            .filter_map(|line| {
+                let line = line.split('#').next().unwrap_or("").trim();
+                let mut words = line.split_whitespace();
+                let pattern = words.next()?.to_string();
+                let owners = words.map(|w| w.trim_start_matches('@').to_string()).collect();
+                Some((pattern, owners))
+            })
+            .collect();
+        OwnerRules { rules }
+    }
+
+    /// Who must review `path`. Empty when no rule matches or the matching
+    /// rule names nobody.
+    pub fn owners(&self, path: &str) -> &[String] {
+        self.rules
+            .iter()
+            .rev()
+            .find(|(pattern, _)| matches(pattern, path))
+            .map(|(_, owners)| owners.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// Each file touched by `hunks` that has owners, with them, in diff order.
+    pub fn required_reviewers<'h>(&self, hunks: &'h [DiffHunk]) -> Vec<(&'h str, &[String])> {
+        let mut files: Vec<(&str, &[String])> = vec![];
+        for hunk in hunks {
+            if !files.iter().any(|(file, _)| *file == hunk.file) {
+                let owners = self.owners(&hunk.file);
+                if !owners.is_empty() {
+                    files.push((&hunk.file, owners));
+                }
+            }
+        }
+        files
+    }
+
+    /// The files in `hunks` that no owner has approved yet, going by each
+    /// reviewer's latest verdict. A file needs one of its owners, not all.
+    /// `verdicts` should already be checked to be signed by their authors.
+    pub fn awaiting_approval<'h>(&self, hunks: &'h [DiffHunk], verdicts: &[Verdict]) -> Vec<(&'h str, &[String])> {
+        let approvers = approvers(verdicts);
+        self.required_reviewers(hunks)
+            .into_iter()
+            .filter(|(_, owners)| !owners.iter().any(|o| approvers.contains(&o.as_str())))
+            .collect()
+    }
+}
+
+/// The PeerIds whose latest verdict approves.
+pub fn approvers(verdicts: &[Verdict]) -> Vec<&str> {
+    latest_verdicts(verdicts)
+        .into_iter()
+        .filter(|v| v.decision == Decision::Approve)
+        .map(|v| v.author_id.as_str())
+        .collect()
+}
+
+/// The rules as committed on `revision`, usually the target branch, so a
+/// change under review can't rewrite who reviews it. `None` when that
+/// revision has no rules file.
+pub fn load(repo_path: &str, revision: &str) -> Result<Option<OwnerRules>, GitError> {
+    let repo = discover(repo_path)?;
+    let tree = branch_commit(&repo, revision)?.tree()?;
+    let entry = match tree.get_path(Path::new(RULES_FILE)) {
+        Ok(entry) => entry,
+        Err(e) if e.code() == git2::ErrorCode::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let blob = entry.to_object(&repo)?.peel_to_blob()?;
+    Ok(Some(OwnerRules::parse(&String::from_utf8_lossy(blob.content()))))
+}
+
+/// Matches `path` the way git matches ignore patterns: a pattern without a
+/// slash matches at any depth, one with a slash is relative to the root,
+/// a trailing slash only matches directories, and a directory covers
+/// everything beneath it.
+fn matches(pattern: &str, path: &str) -> bool {
+    let (pattern, directory) = match pattern.strip_suffix('/') {
+        Some(pattern) => (pattern, true),
+        None => (pattern, false),
+    };
+    let pattern = match pattern.strip_prefix('/') {
+        Some(anchored) => anchored.to_string(),
+        None if pattern.contains('/') => pattern.to_string(),
+        None => format!("**/{}", pattern),
+    };
+    let pattern: Vec<&str> = pattern.split('/').collect();
+    let path: Vec<&str> = path.split('/').collect();
+    // Files are never directories, so only a strict prefix can match one.
+    let prefixes = if directory { 1..path.len() } else { 1..path.len() + 1 };
+    prefixes.into_iter().any(|end| match_segments(&pattern, &path[..end]))
+}
+
+fn match_segments(pattern: &[&str], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((&"**", rest)) => (0..=path.len()).any(|skip| match_segments(rest, &path[skip..])),
+        Some((first, rest)) => {
+            !path.is_empty() && match_wildcards(first.as_bytes(), path[0].as_bytes()) && match_segments(rest, &path[1..])
+        }
+    }
+}
+
+/// `*` and `?` within one path segment.
+fn match_wildcards(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|skip| match_wildcards(rest, &name[skip..])),
+        Some((b'?', rest)) => !name.is_empty() && match_wildcards(rest, &name[1..]),
+        Some((c, rest)) => name.first() == Some(c) && match_wildcards(rest, &name[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn last_matching_rule_decides_and_one_owner_approves_a_file() {
+        let rules = OwnerRules::parse(
+            "# default\n\
+             *             @peer-alice\n\
+             /src/net/     @peer-bob peer-carol\n\
+             *.sql         peer-carol\n\
+             docs/         # unowned\n",
+        );
+        assert_eq!(rules.owners("README.md"), ["peer-alice"]);
+        assert_eq!(rules.owners("src/net/dial.rs"), ["peer-bob", "peer-carol"]);
+        assert_eq!(rules.owners("lib/src/net/dial.rs"), ["peer-alice"], "anchored to the root");
+        assert_eq!(rules.owners("src/net/schema.sql"), ["peer-carol"]);
+        assert!(rules.owners("docs/guide.md").is_empty());
+        assert_eq!(rules.owners("docs"), ["peer-alice"], "a trailing slash only matches directories");
+
+        let hunk = |file: &str| DiffHunk {
+            id: file.to_string(),
+            file: file.to_string(),
+            old_start: 1,
+            old_lines: 1,
+            new_start: 1,
+            new_lines: 1,
+            content: String::new(),
+        };
+        let hunks = [hunk("src/net/dial.rs"), hunk("src/net/dial.rs"), hunk("docs/guide.md"), hunk("main.rs")];
+        let verdict = |author: &str, author_id: &str, decision| Verdict {
+            id: format!("{}-{}", author, decision),
+            session_id: "s".to_string(),
+            author: author.to_string(),
+            author_id: author_id.to_string(),
+            decision,
+            body: String::new(),
+            created_at: "2020-09-13T12:26:40Z".parse().unwrap(),
+            signature: String::new(),
+        };
+
+        let waiting = |verdicts: &[Verdict]| -> Vec<String> {
+            rules.awaiting_approval(&hunks, verdicts).into_iter().map(|(file, _)| file.to_string()).collect()
+        };
+        assert_eq!(waiting(&[]), ["src/net/dial.rs", "main.rs"]);
+        let approvals = [verdict("carol", "peer-carol", Decision::Approve), verdict("alice", "peer-alice", Decision::Approve)];
+        assert!(waiting(&approvals).is_empty());
+        let withdrawn = [approvals[0].clone(), approvals[1].clone(), verdict("alice", "peer-alice", Decision::RequestChanges)];
+        assert_eq!(waiting(&withdrawn), ["main.rs"]);
+        // Anyone can call themselves alice; only her PeerId counts.
+        assert_eq!(waiting(&[verdict("peer-alice", "peer-mallory", Decision::Approve)]), ["src/net/dial.rs", "main.rs"]);
+    }
+}
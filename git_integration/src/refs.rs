@@ -0,0 +1,64 @@
+use git2::Signature;
+
+use crate::error::{discover, GitError};
+
+/// Namespace holding one ref per review session. Share it with
+/// `git push origin 'refs/reviewmesh/*'` and
+/// `git fetch origin '+refs/reviewmesh/*:refs/reviewmesh/*'`.
+pub const REVIEW_REFS: &str = "refs/reviewmesh/";
+
+/// Named files making up a stored session.
+pub type ReviewFiles = Vec<(String, Vec<u8>)>;
+
+/// The files stored for `session_id`, or `None` if the repository has no
+/// ref for it.
+pub fn read_review(repo_path: &str, session_id: &str) -> Result<Option<ReviewFiles>, GitError> {
+    let repo = discover(repo_path)?;
+    let reference = match repo.find_reference(&format!("{}{}", REVIEW_REFS, session_id)) {
+        Ok(reference) => reference,
+        Err(e) if e.code() == git2::ErrorCode::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let tree = reference.peel_to_tree()?;
+    let mut files = vec![];
+    for entry in tree.iter() {
+        let (Some(name), Ok(blob)) = (entry.name(), entry.to_object(&repo).and_then(|o| o.peel_to_blob())) else {
+            continue;
+        };
+        files.push((name.to_string(), blob.content().to_vec()));
+    }
+    Ok(Some(files))
+}
+
+/// Commits `files` as the new state of `session_id` on top of its ref.
+/// Returns false when nothing changed and no commit was made.
+pub fn write_review(repo_path: &str, session_id: &str, files: &[(String, Vec<u8>)]) -> Result<bool, GitError> {
+    let repo = discover(repo_path)?;
+    let mut builder = repo.treebuilder(None)?;
+    for (name, content) in files {
+        builder.insert(name, repo.blob(content)?, 0o100644)?;
+    }
+    let tree = repo.find_tree(builder.write()?)?;
+
+    let ref_name = format!("{}{}", REVIEW_REFS, session_id);
+    let parent = repo.find_reference(&ref_name).ok().and_then(|r| r.peel_to_commit().ok());
+    if parent.as_ref().is_some_and(|p| p.tree_id() == tree.id()) {
+        return Ok(false);
+    }
+    let signature = repo.signature().or_else(|_| Signature::now("ReviewMesh", "reviewmesh@localhost"))?;
+    let parents: Vec<_> = parent.iter().collect();
+    repo.commit(Some(&ref_name), &signature, &signature, &format!("Update review {}", session_id), &tree, &parents)?;
+    Ok(true)
+}
+
+/// Ids of every session stored in the repository.
+pub fn list_reviews(repo_path: &str) -> Result<Vec<String>, GitError> {
+    let repo = discover(repo_path)?;
+    let mut ids = vec![];
+    for reference in repo.references_glob(&format!("{}*", REVIEW_REFS))? {
+        if let Some(id) = reference?.name().and_then(|n| n.strip_prefix(REVIEW_REFS)) {
+            ids.push(id.to_string());
+        }
+    }
+    Ok(ids)
+}
@@ -0,0 +1,177 @@
+use std::error::Error;
+use std::fmt;
+
+use base58::{FromBase58, ToBase58};
+use chrono::{DateTime, TimeZone, Utc};
+use hmac::{Hmac, Mac};
+use libp2p::identity::Keypair;
+use libp2p::PeerId;
+use sha2::{Digest, Sha256};
+
+type HmacSha256 = Hmac<Sha256>;
+
+const MAC_LEN: usize = 32;
+const EXPIRY_LEN: usize = 8;
+
+/// What an invite token grants: joining `session_id` until `expires_at`.
+/// `owner` lets the joiner tell the real session from an impostor's.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Invite {
+    pub session_id: String,
+    pub owner: PeerId,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InviteError {
+    Malformed,
+    Forged,
+    Expired,
+}
+
+impl fmt::Display for InviteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            InviteError::Malformed => "malformed invite token",
+            InviteError::Forged => "invite token was not issued by the session owner",
+            InviteError::Expired => "invite token has expired",
+        })
+    }
+}
+
+impl Error for InviteError {}
+
+/// Issues a token for `session_id`. Only the session owner can issue or
+/// check tokens: the HMAC key is derived from the owner's signature over the
+/// session id, so nothing secret has to be stored alongside the session.
+pub fn create_invite(owner: &Keypair, session_id: &str, expires_at: DateTime<Utc>) -> String {
+    let peer_id = PeerId::from(owner.public()).to_bytes();
+    let mut token = expires_at.timestamp().to_be_bytes().to_vec();
+    token.push(peer_id.len() as u8);
+    token.extend_from_slice(&peer_id);
+    token.extend_from_slice(session_id.as_bytes());
+    let tag = mac(owner, session_id, &token).finalize().into_bytes();
+    token.extend_from_slice(&tag);
+    token.to_base58()
+}
+
+/// Reads a token without checking who issued it, e.g. to learn which
+/// session a joiner is asking for.
+pub fn parse_invite(token: &str) -> Result<Invite, InviteError> {
+    let bytes = token.from_base58().map_err(|_| InviteError::Malformed)?;
+    if bytes.len() <= EXPIRY_LEN + 1 + MAC_LEN {
+        return Err(InviteError::Malformed);
+    }
+    let (expiry, rest) = bytes.split_at(EXPIRY_LEN);
+    let (owner_len, rest) = (rest[0] as usize, &rest[1..rest.len() - MAC_LEN]);
+    if rest.len() <= owner_len {
+        return Err(InviteError::Malformed);
+    }
+    let (owner, session_id) = rest.split_at(owner_len);
+    Ok(Invite {
+        session_id: std::str::from_utf8(session_id).map_err(|_| InviteError::Malformed)?.to_string(),
+        owner: PeerId::from_bytes(owner).map_err(|_| InviteError::Malformed)?,
+        expires_at: Utc
+            .timestamp_opt(i64::from_be_bytes(expiry.try_into().unwrap()), 0)
+            .single()
+            .ok_or(InviteError::Malformed)?,
+    })
+}
+
+/// Turns `token` into proof that `joiner` holds it, for a join request. The
+/// token itself is a secret between the owner and the invitee and never goes
+/// on the mesh; the proof replaces its tag with one keyed by that tag over
+/// the joiner's PeerId, so a copied proof admits nobody else.
+pub fn prove_invite(token: &str, joiner: &PeerId) -> Result<String, InviteError> {
+    parse_invite(token)?;
+    let mut bytes = token.from_base58().map_err(|_| InviteError::Malformed)?;
+    let tag = bytes.split_off(bytes.len() - MAC_LEN);
+    let proof = bound_mac(&tag, joiner).finalize().into_bytes();
+    bytes.extend_from_slice(&proof);
+    Ok(bytes.to_base58())
+}
+
+/// Checks that `proof`, from [`prove_invite`], shows `joiner` holds a token
+/// `owner` issued that is still valid.
+pub fn check_join(owner: &Keypair, proof: &str, joiner: &PeerId) -> Result<Invite, InviteError> {
+    let invite = parse_invite(proof)?;
+    let bytes = proof.from_base58().map_err(|_| InviteError::Malformed)?;
+    let (body, proof) = bytes.split_at(bytes.len() - MAC_LEN);
+    let tag = mac(owner, &invite.session_id, body).finalize().into_bytes();
+    bound_mac(&tag, joiner)
+        .verify_slice(proof)
+        .map_err(|_| InviteError::Forged)?;
+    if invite.expires_at < Utc::now() {
+        return Err(InviteError::Expired);
+    }
+    Ok(invite)
+}
+
+/// Checks that `token` was issued by `owner` and is still valid.
+pub fn check_invite(owner: &Keypair, token: &str) -> Result<Invite, InviteError> {
+    let invite = parse_invite(token)?;
+    let bytes = token.from_base58().map_err(|_| InviteError::Malformed)?;
+    let (body, tag) = bytes.split_at(bytes.len() - MAC_LEN);
+    mac(owner, &invite.session_id, body)
+        .verify_slice(tag)
+        .map_err(|_| InviteError::Forged)?;
+    if invite.expires_at < Utc::now() {
+        return Err(InviteError::Expired);
+    }
+    Ok(invite)
+}
+
+fn bound_mac(tag: &[u8], joiner: &PeerId) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(tag).expect("HMAC accepts any key length");
+    mac.update(&joiner.to_bytes());
+    mac
+}
+
+fn mac(owner: &Keypair, session_id: &str, body: &[u8]) -> HmacSha256 {
+    // Ed25519 signatures are deterministic, so this yields the same key on
+    // every run without ever leaving the owner's node.
+    let seed = owner
+        .sign(format!("reviewmesh-invite:{}", session_id).as_bytes())
+        .expect("ed25519 signing cannot fail");
+    let mut mac = HmacSha256::new_from_slice(&Sha256::digest(seed)).expect("HMAC accepts any key length");
+    mac.update(body);
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    #[test]
+    fn only_owner_tokens_admit() {
+        let owner = Keypair::generate_ed25519();
+        let expires_at = Utc.timestamp_opt(Utc::now().timestamp(), 0).unwrap() + Duration::hours(1);
+        let token = create_invite(&owner, "sess1", expires_at);
+        let invite = Invite { session_id: "sess1".to_string(), owner: PeerId::from(owner.public()), expires_at };
+        assert_eq!(check_invite(&owner, &token), Ok(invite));
+
+        let stranger = Keypair::generate_ed25519();
+        assert_eq!(check_invite(&stranger, &token), Err(InviteError::Forged));
+        assert_eq!(check_invite(&owner, &create_invite(&stranger, "sess1", expires_at)), Err(InviteError::Forged));
+
+        let expired = create_invite(&owner, "sess1", expires_at - Duration::hours(2));
+        assert_eq!(check_invite(&owner, &expired), Err(InviteError::Expired));
+        assert_eq!(parse_invite("not a token"), Err(InviteError::Malformed));
+    }
+
+    #[test]
+    fn a_join_proof_only_admits_its_joiner() {
+        let owner = Keypair::generate_ed25519();
+        let expires_at = Utc.timestamp_opt(Utc::now().timestamp(), 0).unwrap() + Duration::hours(1);
+        let token = create_invite(&owner, "sess1", expires_at);
+        let (joiner, eavesdropper) = (PeerId::random(), PeerId::random());
+        let proof = prove_invite(&token, &joiner).unwrap();
+
+        assert_eq!(check_join(&owner, &proof, &joiner).map(|i| i.session_id), Ok("sess1".to_string()));
+        assert_eq!(check_join(&owner, &proof, &eavesdropper), Err(InviteError::Forged));
+        assert_eq!(check_join(&owner, &token, &joiner), Err(InviteError::Forged), "the token itself is not a proof");
+        let expired = create_invite(&owner, "sess1", expires_at - Duration::hours(2));
+        assert_eq!(check_join(&owner, &prove_invite(&expired, &joiner).unwrap(), &joiner), Err(InviteError::Expired));
+    }
+}
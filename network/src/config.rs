@@ -0,0 +1,55 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use libp2p::multiaddr::Protocol;
+use libp2p::{Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+
+const CONFIG_FILE: &str = "network.json";
+
+/// Network settings kept next to the node key in `network.json`. Bootstrap
+/// peers are edited with `cli peer`; flags override both lists per run.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct NetworkConfig {
+    /// Addresses to listen on, e.g. `/ip4/0.0.0.0/tcp/4002`,
+    /// `/ip6/::/tcp/4002` or `/ip4/0.0.0.0/tcp/4003/ws`. A random TCP port
+    /// on every IPv4 interface when empty.
+    #[serde(default)]
+    pub listen: Vec<Multiaddr>,
+    /// Peers and relays dialed on startup, e.g.
+    /// `/ip4/203.0.113.7/tcp/4001/p2p/12D3KooW...`.
+    #[serde(default)]
+    pub bootstrap: Vec<Multiaddr>,
+}
+
+impl NetworkConfig {
+    pub fn load(dir: &Path) -> io::Result<Self> {
+        match fs::read_to_string(dir.join(CONFIG_FILE)) {
+            Ok(json) => serde_json::from_str(&json).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save(&self, dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(dir)?;
+        fs::write(dir.join(CONFIG_FILE), serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn listen_addresses(&self) -> Vec<Multiaddr> {
+        if self.listen.is_empty() {
+            vec!["/ip4/0.0.0.0/tcp/0".parse().expect("valid multiaddr")]
+        } else {
+            self.listen.clone()
+        }
+    }
+}
+
+/// The peer an address names with a trailing `/p2p/<id>`, if any.
+pub fn peer_of(address: &Multiaddr) -> Option<PeerId> {
+    match address.iter().last()? {
+        Protocol::P2p(peer_id) => Some(peer_id),
+        _ => None,
+    }
+}
@@ -0,0 +1,104 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use libp2p::identity::Keypair;
+use libp2p::PeerId;
+use sha2::{Digest, Sha256, Sha512};
+
+use common::Sealed;
+
+use crate::signing::public_key_of;
+
+/// Symmetric key shared by the members of a session.
+pub type SessionKey = [u8; 32];
+
+pub fn new_session_key() -> SessionKey {
+    ChaCha20Poly1305::generate_key(&mut OsRng).into()
+}
+
+pub fn seal(key: &SessionKey, session_id: &str, epoch: u64, plaintext: &[u8]) -> Sealed {
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let aad = associated_data(session_id, epoch);
+    let ciphertext = ChaCha20Poly1305::new(key.into())
+        .encrypt(&nonce, Payload { msg: plaintext, aad: &aad })
+        .expect("ChaCha20-Poly1305 encryption cannot fail");
+    Sealed {
+        session_id: session_id.to_string(),
+        epoch,
+        nonce: BASE64.encode(nonce),
+        ciphertext: BASE64.encode(ciphertext),
+    }
+}
+
+/// Decrypts `sealed`, or returns `None` if it was not sealed with `key` or
+/// was altered on the way.
+pub fn open(key: &SessionKey, sealed: &Sealed) -> Option<Vec<u8>> {
+    let nonce = BASE64.decode(&sealed.nonce).ok()?;
+    if nonce.len() != 12 {
+        return None;
+    }
+    let ciphertext = BASE64.decode(&sealed.ciphertext).ok()?;
+    let aad = associated_data(&sealed.session_id, sealed.epoch);
+    ChaCha20Poly1305::new(key.into())
+        .decrypt(Nonce::from_slice(&nonce), Payload { msg: &ciphertext, aad: &aad })
+        .ok()
+}
+
+/// Encrypts `key` so only `recipient` can read it. The wrapping key comes
+/// from an X25519 agreement between the two nodes' Ed25519 identities, so no
+/// extra key material has to be exchanged.
+pub fn wrap_key(owner: &Keypair, recipient: &PeerId, session_id: &str, epoch: u64, key: &SessionKey) -> Option<String> {
+    let sealed = seal(&shared_key(owner, recipient)?, session_id, epoch, key);
+    Some(format!("{}.{}", sealed.nonce, sealed.ciphertext))
+}
+
+/// Opens a key wrapped for this node by `owner`.
+pub fn unwrap_key(me: &Keypair, owner: &PeerId, session_id: &str, epoch: u64, wrapped: &str) -> Option<SessionKey> {
+    let (nonce, ciphertext) = wrapped.split_once('.')?;
+    let sealed = Sealed {
+        session_id: session_id.to_string(),
+        epoch,
+        nonce: nonce.to_string(),
+        ciphertext: ciphertext.to_string(),
+    };
+    open(&shared_key(me, owner)?, &sealed)?.try_into().ok()
+}
+
+fn shared_key(me: &Keypair, other: &PeerId) -> Option<SessionKey> {
+    // An Ed25519 secret scalar is the first half of the SHA-512 of its seed,
+    // and the public point maps onto Curve25519 for the matching X25519 key.
+    let seed = me.clone().try_into_ed25519().ok()?.secret();
+    let scalar: [u8; 32] = Sha512::digest(seed.as_ref())[..32].try_into().ok()?;
+    let public = public_key_of(other)?.try_into_ed25519().ok()?.to_bytes();
+    let point = CompressedEdwardsY(public).decompress()?.to_montgomery();
+    Some(Sha256::digest(point.mul_clamped(scalar).as_bytes()).into())
+}
+
+fn associated_data(session_id: &str, epoch: u64) -> Vec<u8> {
+    format!("reviewmesh:{}:{}", session_id, epoch).into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_recipient_unwraps_session_key() {
+        let owner = Keypair::generate_ed25519();
+        let member = Keypair::generate_ed25519();
+        let outsider = Keypair::generate_ed25519();
+        let owner_id = PeerId::from(owner.public());
+        let key = new_session_key();
+
+        let wrapped = wrap_key(&owner, &PeerId::from(member.public()), "s", 1, &key).unwrap();
+        assert_eq!(unwrap_key(&member, &owner_id, "s", 1, &wrapped), Some(key));
+        assert_eq!(unwrap_key(&outsider, &owner_id, "s", 1, &wrapped), None);
+        assert_eq!(unwrap_key(&member, &owner_id, "s", 2, &wrapped), None);
+
+        let sealed = seal(&key, "s", 1, b"secret diff");
+        assert_eq!(open(&key, &sealed).as_deref(), Some(&b"secret diff"[..]));
+        assert_eq!(open(&new_session_key(), &sealed), None);
+    }
+}
@@ -0,0 +1,128 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use libp2p::identity::Keypair;
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+
+use common::{Identity, Signable};
+
+const KEY_FILE: &str = "identity.key";
+const PROFILE_FILE: &str = "profile.json";
+
+/// Where the node key and profile live, e.g. `~/.config/reviewmesh`.
+pub fn config_dir() -> PathBuf {
+    dirs::config_dir()
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("reviewmesh")
+}
+
+/// Name and email overrides saved with `cli identity set`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Profile {
+    pub name: Option<String>,
+    pub email: Option<String>,
+}
+
+impl Profile {
+    pub fn load(dir: &Path) -> io::Result<Self> {
+        match fs::read_to_string(dir.join(PROFILE_FILE)) {
+            Ok(json) => serde_json::from_str(&json).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save(&self, dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(dir)?;
+        fs::write(dir.join(PROFILE_FILE), serde_json::to_string_pretty(self)?)
+    }
+}
+
+/// The local node's persistent key together with the reviewer it speaks for.
+#[derive(Clone)]
+pub struct NodeIdentity {
+    pub keypair: Keypair,
+    pub identity: Identity,
+}
+
+impl NodeIdentity {
+    /// Loads the node key from `dir`, generating it on first use. The saved
+    /// profile takes precedence over `default_name` and `default_email`.
+    pub fn load(dir: &Path, default_name: String, default_email: Option<String>) -> io::Result<Self> {
+        let keypair = load_or_create_keypair(dir)?;
+        let profile = Profile::load(dir)?;
+        let identity = Identity {
+            name: profile.name.unwrap_or(default_name),
+            email: profile.email.or(default_email),
+            peer_id: PeerId::from(keypair.public()).to_base58(),
+        };
+        Ok(Self { keypair, identity })
+    }
+
+    pub fn peer_id(&self) -> PeerId {
+        PeerId::from(self.keypair.public())
+    }
+
+    /// Signs a record authored on this node.
+    pub fn sign(&self, record: &mut dyn Signable) {
+        crate::signing::sign(record, &self.keypair);
+    }
+}
+
+pub fn load_or_create_keypair(dir: &Path) -> io::Result<Keypair> {
+    let path = dir.join(KEY_FILE);
+    match fs::read(&path) {
+        Ok(bytes) => Keypair::from_protobuf_encoding(&bytes).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e))
+        }),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let keypair = Keypair::generate_ed25519();
+            let bytes = keypair
+                .to_protobuf_encoding()
+                .map_err(io::Error::other)?;
+            fs::create_dir_all(dir)?;
+            write_private(&path, &bytes)?;
+            Ok(keypair)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(unix)]
+fn write_private(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(bytes)
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    fs::write(path, bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::testing::TempDir;
+
+    #[test]
+    fn keypair_persists_across_loads() {
+        let dir = TempDir::new("identity");
+        let first = NodeIdentity::load(dir.path(), "alice".to_string(), None).unwrap();
+        Profile { name: Some("Alice".to_string()), email: Some("alice@example.com".to_string()) }
+            .save(dir.path())
+            .unwrap();
+        let second = NodeIdentity::load(dir.path(), "alice".to_string(), None).unwrap();
+
+        assert_eq!(first.peer_id(), second.peer_id());
+        assert_eq!(second.identity.name, "Alice");
+        assert_eq!(second.identity.to_string(), "Alice <alice@example.com>");
+    }
+}
@@ -1,36 +1,88 @@
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::error::Error;
 use std::future::Future;
 use std::pin::Pin;
 use std::task::{Context, Poll};
-use tokio;
+use std::time::{Duration, Instant};
+
+use base58::ToBase58;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use sha2::{Digest, Sha256};
 
 use libp2p::{
     core::upgrade,
-    identity,
-    noise,
-    swarm::{SwarmBuilder, SwarmEvent},
-    tcp,
+    identify, kad,
+    multiaddr::Protocol,
+    noise, relay, request_response,
+    swarm::{self, behaviour::toggle::Toggle, SwarmEvent},
+    tcp, websocket,
     yamux,
-    PeerId, Transport,
+    StreamProtocol, Transport,
 };
 use libp2p::futures::StreamExt;
 use libp2p_mdns::{tokio::Behaviour as Mdns, Config as MdnsConfig};
 use libp2p::floodsub::{self, Floodsub, FloodsubEvent, Topic};
 use libp2p::swarm::NetworkBehaviour;
-use common::{ReviewSession, Comment};
+use common::{ReviewSession, Comment, ChatLine, Fragment, JoinRequest, KeyGrant, Membership, MeshMessage, Presence, Resolution, SharedDiff, Verdict};
+
+pub use libp2p::{Multiaddr, PeerId};
+
+pub mod access;
+pub mod config;
+pub mod encryption;
+pub mod identity;
+pub mod signing;
+pub mod transfer;
+
+use config::NetworkConfig;
+use encryption::SessionKey;
+use identity::NodeIdentity;
+use signing::Verification;
+use transfer::{BundleRequest, BundleResponse, IncomingBundleRequest};
+
+const KAD_PROTOCOL: StreamProtocol = StreamProtocol::new("/reviewmesh/kad/1.0.0");
+const IDENTIFY_PROTOCOL: &str = "/reviewmesh/1.0.0";
+
+/// Floodsub drops packets over 2 KiB, so larger messages are sent in
+/// fragments. These leave room for base64 and the floodsub envelope.
+const MAX_MESSAGE_LEN: usize = 1536;
+const FRAGMENT_LEN: usize = 1024;
+/// Bounds on reassembly so a peer can't make us buffer without limit. The
+/// largest message is a session's shared diff; git bundles go over
+/// request-response instead, so 1 MiB leaves plenty of room.
+const MAX_FRAGMENTS: u32 = 1024;
+const MAX_PARTIAL_MESSAGES: usize = 64;
+const MAX_BUFFERED_PER_PEER: usize = 2 * MAX_FRAGMENTS as usize * FRAGMENT_LEN;
+const MAX_BUFFERED: usize = 8 * MAX_FRAGMENTS as usize * FRAGMENT_LEN;
+const FRAGMENT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A message being reassembled: when its first fragment arrived, how many
+/// bytes have arrived and the pieces received so far.
+type Partial = (Instant, usize, Vec<Option<Vec<u8>>>);
 
 #[derive(NetworkBehaviour)]
 #[behaviour(to_swarm = "ReviewMeshBehaviourEvent")]
 pub struct ReviewMeshBehaviour {
     pub floodsub: Floodsub,
     pub mdns: Mdns,
+    pub kademlia: kad::Behaviour<kad::store::MemoryStore>,
+    pub identify: identify::Behaviour,
+    pub relay_client: relay::client::Behaviour,
+    /// Only enabled on nodes started with `cli relay`.
+    pub relay: Toggle<relay::Behaviour>,
+    pub bundles: transfer::Behaviour,
 }
 
 #[allow(clippy::large_enum_variant)]
 pub enum ReviewMeshBehaviourEvent {
     Floodsub(FloodsubEvent),
     Mdns(libp2p_mdns::Event),
+    Kademlia(kad::Event),
+    Identify(identify::Event),
+    Relay(relay::Event),
+    RelayClient(relay::client::Event),
+    Bundles(transfer::Event),
 }
 
 impl From<FloodsubEvent> for ReviewMeshBehaviourEvent {
@@ -45,26 +97,85 @@ impl From<libp2p_mdns::Event> for ReviewMeshBehaviourEvent {
     }
 }
 
+impl From<kad::Event> for ReviewMeshBehaviourEvent {
+    fn from(event: kad::Event) -> Self {
+        ReviewMeshBehaviourEvent::Kademlia(event)
+    }
+}
+
+impl From<identify::Event> for ReviewMeshBehaviourEvent {
+    fn from(event: identify::Event) -> Self {
+        ReviewMeshBehaviourEvent::Identify(event)
+    }
+}
+
+impl From<relay::Event> for ReviewMeshBehaviourEvent {
+    fn from(event: relay::Event) -> Self {
+        ReviewMeshBehaviourEvent::Relay(event)
+    }
+}
+
+impl From<relay::client::Event> for ReviewMeshBehaviourEvent {
+    fn from(event: relay::client::Event) -> Self {
+        ReviewMeshBehaviourEvent::RelayClient(event)
+    }
+}
+
+impl From<transfer::Event> for ReviewMeshBehaviourEvent {
+    fn from(event: transfer::Event) -> Self {
+        ReviewMeshBehaviourEvent::Bundles(event)
+    }
+}
+
 pub struct NetworkManager {
     pub swarm: libp2p::Swarm<ReviewMeshBehaviour>,
     topic: Topic,
+    subscribers: HashSet<PeerId>,
+    inbox: Vec<MeshMessage>,
+    /// Session keys by session and epoch. Content for these sessions is
+    /// sealed with the newest key and only accepted when sealed.
+    keys: HashMap<String, BTreeMap<u64, SessionKey>>,
+    /// Addresses of the peers dialed on startup, kept so the connection is
+    /// retried and, for relays, so we can listen through them.
+    bootstrap: HashMap<PeerId, Multiaddr>,
+    /// Relays we already hold a circuit listener on.
+    reservations: HashSet<PeerId>,
+    bundle_requests: Vec<IncomingBundleRequest>,
+    bundle_responses: HashMap<request_response::RequestId, Result<BundleResponse, String>>,
+    /// Fragmented messages still missing pieces, by sender and message id.
+    partial: HashMap<(PeerId, String), Partial>,
+    relay_server: bool,
+    /// Addresses a relay started listening on, for it to announce.
+    new_listen_addresses: Vec<Multiaddr>,
 }
 
 impl NetworkManager {
-    pub fn new(secret_key_seed: Option<u8>) -> Result<Self, Box<dyn Error>> {
-        let id_keys = match secret_key_seed {
-            Some(seed) => {
-                let mut sk_bytes = [0u8; 32];
-                sk_bytes[0] = seed;
-                identity::Keypair::ed25519_from_bytes(&mut sk_bytes).unwrap()
-            }
-            None => identity::Keypair::generate_ed25519(),
-        };
+    /// Starts a review node listening on the addresses in `config`. It finds
+    /// peers on the LAN over mDNS and beyond it through the bootstrap peers,
+    /// which also serve as relays when they run `cli relay`.
+    pub fn new(node: &NodeIdentity, config: &NetworkConfig) -> Result<Self, Box<dyn Error>> {
+        Self::build(node, config, false)
+    }
+
+    /// Starts a relay node for members who can't reach each other directly.
+    /// It forwards the review topic but holds no session keys, so sealed
+    /// content stays unreadable to it.
+    pub fn relay(node: &NodeIdentity, config: &NetworkConfig) -> Result<Self, Box<dyn Error>> {
+        Self::build(node, config, true)
+    }
+
+    fn build(node: &NodeIdentity, config: &NetworkConfig, relay_server: bool) -> Result<Self, Box<dyn Error>> {
+        let id_keys = node.keypair.clone();
         let peer_id = PeerId::from(id_keys.public());
 
         let noise_config = noise::Config::new(&id_keys).unwrap();
 
-        let transport = tcp::tokio::Transport::new(tcp::Config::default().nodelay(true))
+        // TCP for direct connections, WebSocket for networks that only let
+        // HTTP-like traffic through, and circuits through relays.
+        let (relay_transport, relay_client) = relay::client::new(peer_id);
+        let transport = relay_transport
+            .or_transport(tcp::tokio::Transport::new(tcp::Config::default().nodelay(true)))
+            .or_transport(websocket::WsConfig::new(tcp::tokio::Transport::new(tcp::Config::default().nodelay(true))))
             .upgrade(upgrade::Version::V1)
             .authenticate(noise_config)
             .multiplex(yamux::Config::default())
@@ -75,74 +186,506 @@ impl NetworkManager {
 
         let mut swarm = {
             let mdns = Mdns::new(MdnsConfig::default(), peer_id)?;
+            let mut kad_config = kad::Config::default();
+            kad_config.set_protocol_names(vec![KAD_PROTOCOL]);
+            let mut kademlia = kad::Behaviour::with_config(peer_id, kad::store::MemoryStore::new(peer_id), kad_config);
+            // Review meshes are small and every node should be able to answer
+            // lookups, not only the publicly reachable ones.
+            kademlia.set_mode(Some(kad::Mode::Server));
             let mut behaviour = ReviewMeshBehaviour {
                 floodsub: Floodsub::new(peer_id),
                 mdns,
+                kademlia,
+                identify: identify::Behaviour::new(identify::Config::new(IDENTIFY_PROTOCOL.to_string(), id_keys.public())),
+                relay_client,
+                relay: relay_server.then(|| relay::Behaviour::new(peer_id, relay::Config::default())).into(),
+                bundles: transfer::behaviour(),
             };
             behaviour.floodsub.subscribe(topic.clone());
-            SwarmBuilder::with_executor(transport, behaviour, peer_id, Box::new(|fut| { tokio::spawn(fut); })).build()
+            libp2p::Swarm::new(transport, behaviour, peer_id, swarm::Config::with_executor(|fut| { tokio::spawn(fut); }))
         };
 
-        swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
+        for address in config.listen_addresses() {
+            swarm.listen_on(address)?;
+        }
+
+        let mut bootstrap = HashMap::new();
+        for address in &config.bootstrap {
+            if let Some(peer) = config::peer_of(address) {
+                swarm.behaviour_mut().kademlia.add_address(&peer, address.clone());
+                bootstrap.insert(peer, address.clone());
+            }
+            swarm.dial(address.clone())?;
+        }
+
+        Ok(Self {
+            swarm,
+            topic,
+            subscribers: HashSet::new(),
+            inbox: vec![],
+            keys: HashMap::new(),
+            bootstrap,
+            reservations: HashSet::new(),
+            bundle_requests: vec![],
+            bundle_responses: HashMap::new(),
+            partial: HashMap::new(),
+            relay_server,
+            new_listen_addresses: vec![],
+        })
+    }
+
+    pub fn publish_review_session(&mut self, review: &ReviewSession) -> Result<(), Box<dyn Error>> {
+        self.publish(&MeshMessage::Session(review.clone()))
+    }
+
+    pub fn publish_membership(&mut self, membership: &Membership) -> Result<(), Box<dyn Error>> {
+        self.publish(&MeshMessage::Membership(membership.clone()))
+    }
+
+    pub fn publish_comment(&mut self, comment: &Comment) -> Result<(), Box<dyn Error>> {
+        self.publish(&MeshMessage::Comment(comment.clone()))
+    }
+
+    pub fn publish_chat(&mut self, chat: &ChatLine) -> Result<(), Box<dyn Error>> {
+        self.publish(&MeshMessage::Chat(chat.clone()))
+    }
 
-        Ok(Self { swarm, topic })
+    pub fn publish_resolution(&mut self, resolution: &Resolution) -> Result<(), Box<dyn Error>> {
+        self.publish(&MeshMessage::Resolution(resolution.clone()))
     }
 
-    pub fn publish_review_session(&mut self, review: &ReviewSession) {
-        let review_json = serde_json::to_string(review).unwrap();
-        self.swarm.behaviour_mut().floodsub.publish(self.topic.clone(), review_json.as_bytes());
+    pub fn publish_verdict(&mut self, verdict: &Verdict) -> Result<(), Box<dyn Error>> {
+        self.publish(&MeshMessage::Verdict(verdict.clone()))
     }
 
-    pub fn publish_comment(&mut self, comment: &Comment) {
-        let comment_json = serde_json::to_string(comment).unwrap();
-        self.swarm.behaviour_mut().floodsub.publish(self.topic.clone(), comment_json.as_bytes());
+    pub fn publish_diff(&mut self, diff: &SharedDiff) -> Result<(), Box<dyn Error>> {
+        self.publish(&MeshMessage::Diff(diff.clone()))
+    }
+
+    pub fn publish_presence(&mut self, presence: &Presence) -> Result<(), Box<dyn Error>> {
+        self.publish(&MeshMessage::Presence(presence.clone()))
+    }
+
+    pub fn publish_join(&mut self, join: &JoinRequest) -> Result<(), Box<dyn Error>> {
+        self.publish(&MeshMessage::Join(join.clone()))
+    }
+
+    pub fn publish_key_grant(&mut self, grant: &KeyGrant) -> Result<(), Box<dyn Error>> {
+        self.publish(&MeshMessage::KeyGrant(grant.clone()))
+    }
+
+    pub fn add_session_key(&mut self, session_id: &str, epoch: u64, key: SessionKey) {
+        self.keys.entry(session_id.to_string()).or_default().insert(epoch, key);
+    }
+
+    /// Sends `message` to the topic, sealed when we hold its session's key
+    /// and split into fragments when large. Fails when it is too big for
+    /// receivers to reassemble.
+    fn publish(&mut self, message: &MeshMessage) -> Result<(), Box<dyn Error>> {
+        let mut json = serde_json::to_vec(message).unwrap();
+        let newest = message
+            .sealed_session()
+            .and_then(|id| Some((id, self.keys.get(id)?.last_key_value()?)));
+        if let Some((session_id, (epoch, key))) = newest {
+            let sealed = encryption::seal(key, session_id, *epoch, &json);
+            json = serde_json::to_vec(&MeshMessage::Sealed(sealed)).unwrap();
+        }
+        if json.len() <= MAX_MESSAGE_LEN {
+            self.swarm.behaviour_mut().floodsub.publish(self.topic.clone(), json);
+            return Ok(());
+        }
+        if json.len() > MAX_FRAGMENTS as usize * FRAGMENT_LEN {
+            return Err(format!(
+                "the message is {} KiB, over the {} KiB peers accept",
+                json.len() / 1024,
+                MAX_FRAGMENTS as usize * FRAGMENT_LEN / 1024
+            )
+            .into());
+        }
+        let id = Sha256::digest(&json)[..12].to_base58();
+        let total = json.len().div_ceil(FRAGMENT_LEN) as u32;
+        for (index, piece) in json.chunks(FRAGMENT_LEN).enumerate() {
+            let fragment = MeshMessage::Fragment(Fragment {
+                id: id.clone(),
+                index: index as u32,
+                total,
+                data: BASE64.encode(piece),
+            });
+            self.swarm.behaviour_mut().floodsub.publish(self.topic.clone(), serde_json::to_vec(&fragment).unwrap());
+        }
+        Ok(())
+    }
+
+    /// Collects a fragment, returning the whole message once every piece
+    /// from that sender has arrived. Pieces that would take the bytes
+    /// buffered for the sender, or for everyone, over their bounds are
+    /// dropped.
+    fn reassemble(&mut self, source: PeerId, fragment: Fragment) -> Option<Vec<u8>> {
+        if fragment.total == 0 || fragment.total > MAX_FRAGMENTS || fragment.index >= fragment.total {
+            return None;
+        }
+        let piece = BASE64.decode(&fragment.data).ok().filter(|p| p.len() <= FRAGMENT_LEN)?;
+        self.partial.retain(|_, (started, _, _)| started.elapsed() < FRAGMENT_TIMEOUT);
+        let key = (source, fragment.id);
+        if !self.partial.contains_key(&key) && self.partial.len() >= MAX_PARTIAL_MESSAGES {
+            return None;
+        }
+        let buffered: usize = self.partial.values().map(|(_, len, _)| len).sum();
+        let from_source: usize = self.partial.iter().filter(|((peer, _), _)| *peer == source).map(|(_, (_, len, _))| len).sum();
+        if buffered + piece.len() > MAX_BUFFERED || from_source + piece.len() > MAX_BUFFERED_PER_PEER {
+            return None;
+        }
+        let (_, len, pieces) = self
+            .partial
+            .entry(key.clone())
+            .or_insert_with(|| (Instant::now(), 0, vec![None; fragment.total as usize]));
+        if pieces.len() != fragment.total as usize {
+            return None;
+        }
+        let slot = &mut pieces[fragment.index as usize];
+        *len = *len - slot.as_ref().map_or(0, Vec::len) + piece.len();
+        *slot = Some(piece);
+        if pieces.iter().any(Option::is_none) {
+            return None;
+        }
+        let (_, _, pieces) = self.partial.remove(&key)?;
+        Some(pieces.into_iter().flatten().flatten().collect())
+    }
+
+    /// Turns a floodsub payload into a message we can act on: reassembled,
+    /// opened and, for authored content, checked against its sender.
+    fn receive(&mut self, source: PeerId, data: &[u8]) -> Option<MeshMessage> {
+        let message = match serde_json::from_slice::<MeshMessage>(data).ok()? {
+            MeshMessage::Fragment(fragment) => {
+                let whole = self.reassemble(source, fragment)?;
+                match serde_json::from_slice::<MeshMessage>(&whole).ok()? {
+                    MeshMessage::Fragment(_) => return None,
+                    message => message,
+                }
+            }
+            message => message,
+        };
+        let parsed = self.unseal(message)?;
+        // Authored content must come from the node it names and carry that
+        // node's signature.
+        if let Some(record) = parsed.signed() {
+            if record.author_id() != source.to_base58() || signing::verify(record) != Verification::Verified {
+                return None;
+            }
+        }
+        Some(parsed)
+    }
+
+    /// Unwraps a sealed message with the matching session key. Plain review
+    /// content for a session we hold a key for is refused so it can't be
+    /// downgraded to cleartext.
+    fn unseal(&self, message: MeshMessage) -> Option<MeshMessage> {
+        match message {
+            MeshMessage::Sealed(sealed) => {
+                let key = self.keys.get(&sealed.session_id)?.get(&sealed.epoch)?;
+                let inner: MeshMessage = serde_json::from_slice(&encryption::open(key, &sealed)?).ok()?;
+                (inner.sealed_session() == Some(sealed.session_id.as_str())).then_some(inner)
+            }
+            message => match message.sealed_session() {
+                Some(id) if self.keys.contains_key(id) => None,
+                _ => Some(message),
+            },
+        }
+    }
+
+    /// Handles whatever the swarm has ready without waiting and returns the
+    /// messages received since the last call. Meant to be called from a UI
+    /// tick rather than an async task.
+    pub fn poll_messages(&mut self) -> Vec<MeshMessage> {
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        while let Poll::Ready(Some(event)) = self.swarm.poll_next_unpin(&mut cx) {
+            self.handle_event(event);
+        }
+        std::mem::take(&mut self.inbox)
+    }
+
+    /// The addresses this node is bound to, with its PeerId appended so they
+    /// can be handed straight to `--peer` or `cli peer add`.
+    pub fn listen_addresses(&self) -> Vec<Multiaddr> {
+        let peer_id = *self.swarm.local_peer_id();
+        self.swarm.listeners().map(|address| address.clone().with(Protocol::P2p(peer_id))).collect()
     }
 
     pub fn get_known_peers(&self) -> HashSet<PeerId> {
         self.swarm.behaviour().mdns.discovered_nodes().cloned().collect()
     }
-}
 
-impl Future for NetworkManager {
-    type Output = ();
+    /// Peers currently subscribed to the review topic, i.e. those a publish reaches.
+    pub fn subscribed_peers(&self) -> &HashSet<PeerId> {
+        &self.subscribers
+    }
 
-    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        loop {
-            match self.swarm.poll_next_unpin(cx) {
-                Poll::Ready(Some(event)) => match event {
-                    SwarmEvent::Behaviour(ReviewMeshBehaviourEvent::Floodsub(floodsub_event)) => {
-                        if let floodsub::FloodsubEvent::Message(message) = floodsub_event {
-                            println!(
-                                "Received: '{:?}' from {:?}",
-                                String::from_utf8_lossy(&message.data),
-                                message.source
-                            );
+    /// Drives the swarm until a peer has subscribed to the review topic or
+    /// `timeout` elapses, returning the number of subscribed peers.
+    pub async fn wait_for_peers(&mut self, timeout: Duration) -> usize {
+        let _ = tokio::time::timeout(timeout, async {
+            while self.subscribers.is_empty() {
+                let event = self.swarm.select_next_some().await;
+                self.handle_event(event);
+            }
+        })
+        .await;
+        self.subscribers.len()
+    }
+
+    /// Addresses a relay started listening on since the last call, with its
+    /// PeerId appended, for the application to show to reviewers.
+    pub fn new_listen_addresses(&mut self) -> Vec<Multiaddr> {
+        std::mem::take(&mut self.new_listen_addresses)
+    }
+
+    /// Bundle requests received since the last call, for the application to
+    /// answer with `respond_bundle`.
+    pub fn bundle_requests(&mut self) -> Vec<IncomingBundleRequest> {
+        std::mem::take(&mut self.bundle_requests)
+    }
+
+    pub fn respond_bundle(&mut self, request: IncomingBundleRequest, response: BundleResponse) {
+        let _ = self.swarm.behaviour_mut().bundles.send_response(request.channel, response);
+    }
+
+    /// Asks `peer` for the bundle of a session and waits up to `timeout` for
+    /// the answer.
+    pub async fn fetch_bundle(&mut self, peer: &PeerId, session_id: &str, timeout: Duration) -> Result<BundleResponse, Box<dyn Error>> {
+        let request = BundleRequest { session_id: session_id.to_string() };
+        let request_id = self.swarm.behaviour_mut().bundles.send_request(peer, request);
+        let response = tokio::time::timeout(timeout, async {
+            loop {
+                if let Some(response) = self.bundle_responses.remove(&request_id) {
+                    return response;
+                }
+                let event = self.swarm.select_next_some().await;
+                self.handle_event(event);
+            }
+        })
+        .await
+        .map_err(|_| "timed out waiting for the bundle")?;
+        Ok(response?)
+    }
+
+    /// Keeps the swarm running for `duration` so queued publishes reach peers.
+    pub async fn run_for(&mut self, duration: Duration) {
+        let _ = tokio::time::timeout(duration, async {
+            loop {
+                let event = self.swarm.select_next_some().await;
+                self.handle_event(event);
+            }
+        })
+        .await;
+    }
+
+    fn handle_event<E>(&mut self, event: SwarmEvent<ReviewMeshBehaviourEvent, E>) {
+        match event {
+            SwarmEvent::Behaviour(ReviewMeshBehaviourEvent::Floodsub(floodsub_event)) => match floodsub_event {
+                // A relay only passes messages on; nothing reads its inbox.
+                FloodsubEvent::Message(message) if !self.relay_server => {
+                    if let Some(parsed) = self.receive(message.source, &message.data) {
+                        self.inbox.push(parsed);
+                    }
+                }
+                FloodsubEvent::Subscribed { peer_id, topic } if topic == self.topic => {
+                    self.subscribers.insert(peer_id);
+                }
+                FloodsubEvent::Unsubscribed { peer_id, topic } if topic == self.topic => {
+                    self.subscribers.remove(&peer_id);
+                }
+                _ => {}
+            },
+            SwarmEvent::Behaviour(ReviewMeshBehaviourEvent::Mdns(mdns_event)) => {
+                match mdns_event {
+                    libp2p_mdns::Event::Discovered(list) => {
+                        for (peer, _) in list {
+                            self.swarm.behaviour_mut().floodsub.add_node_to_partial_view(peer);
                         }
                     }
-                    SwarmEvent::Behaviour(ReviewMeshBehaviourEvent::Mdns(mdns_event)) => {
-                        match mdns_event {
-                            libp2p_mdns::Event::Discovered(list) => {
-                                for (peer, _) in list {
-                                    self.swarm.behaviour_mut().floodsub.add_node_to_partial_view(peer);
-                                }
-                            }
-                            libp2p_mdns::Event::Expired(list) => {
-                                for (peer, _) in list {
-                                    if !self.swarm.behaviour().mdns.has_node(&peer) {
-                                        self.swarm.behaviour_mut().floodsub.remove_node_from_partial_view(&peer);
-                                    }
-                                }
+                    libp2p_mdns::Event::Expired(list) => {
+                        for (peer, _) in list {
+                            if !self.swarm.behaviour().mdns.has_node(&peer) {
+                                self.swarm.behaviour_mut().floodsub.remove_node_from_partial_view(&peer);
+                                self.subscribers.remove(&peer);
                             }
                         }
                     }
-                    SwarmEvent::NewListenAddr { address, .. } => {
-                        println!("Listening on {:?}", address);
+                }
+            }
+            SwarmEvent::Behaviour(ReviewMeshBehaviourEvent::Identify(identify::Event::Received { peer_id, info })) => {
+                for address in &info.listen_addrs {
+                    self.swarm.behaviour_mut().kademlia.add_address(&peer_id, address.clone());
+                }
+                if info.protocols.contains(&relay::HOP_PROTOCOL_NAME) && !self.reservations.contains(&peer_id) {
+                    if let Some(address) = self.bootstrap.get(&peer_id).cloned() {
+                        // Listen through the relay so peers that can't dial
+                        // us directly still can, then look for the rest of
+                        // the mesh through it.
+                        if self.swarm.listen_on(address.with(Protocol::P2pCircuit)).is_ok() {
+                            self.reservations.insert(peer_id);
+                        }
+                        let _ = self.swarm.behaviour_mut().kademlia.bootstrap();
+                    }
+                }
+            }
+            SwarmEvent::Behaviour(ReviewMeshBehaviourEvent::Kademlia(kad::Event::RoutingUpdated { peer, .. })) => {
+                self.swarm.behaviour_mut().floodsub.add_node_to_partial_view(peer);
+            }
+            SwarmEvent::Behaviour(ReviewMeshBehaviourEvent::Bundles(event)) => match event {
+                request_response::Event::Message { peer, message } => match message {
+                    // A relay holds no repository, and nothing would ever
+                    // answer the request if it were queued.
+                    request_response::Message::Request { channel, .. } if self.relay_server => {
+                        let refusal = BundleResponse::Refused("this is a relay; ask the session's owner".to_string());
+                        let _ = self.swarm.behaviour_mut().bundles.send_response(channel, refusal);
+                    }
+                    request_response::Message::Request { request, channel, .. } => {
+                        self.bundle_requests.push(IncomingBundleRequest { peer, session_id: request.session_id, channel });
+                    }
+                    request_response::Message::Response { request_id, response } => {
+                        self.bundle_responses.insert(request_id, Ok(response));
                     }
-                    _ => {}
                 },
+                request_response::Event::OutboundFailure { request_id, error, .. } => {
+                    self.bundle_responses.insert(request_id, Err(error.to_string()));
+                }
+                _ => {}
+            },
+            SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+                self.swarm.behaviour_mut().floodsub.add_node_to_partial_view(peer_id);
+            }
+            SwarmEvent::ConnectionClosed { peer_id, num_established: 0, .. }
+                if !self.bootstrap.contains_key(&peer_id) && !self.swarm.behaviour().mdns.has_node(&peer_id) =>
+            {
+                self.swarm.behaviour_mut().floodsub.remove_node_from_partial_view(&peer_id);
+                self.subscribers.remove(&peer_id);
+            }
+            SwarmEvent::NewListenAddr { address, .. } if self.relay_server => {
+                // A relay hands its own addresses to the peers reserving
+                // through it, so it has to announce them.
+                self.swarm.add_external_address(address.clone());
+                let peer_id = *self.swarm.local_peer_id();
+                self.new_listen_addresses.push(address.with(Protocol::P2p(peer_id)));
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Future for NetworkManager {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        loop {
+            match self.swarm.poll_next_unpin(cx) {
+                Poll::Ready(Some(event)) => self.handle_event(event),
                 Poll::Ready(None) => return Poll::Ready(()),
                 Poll::Pending => return Poll::Pending,
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::Identity;
+    use libp2p::identity::Keypair;
+
+    fn manager() -> NetworkManager {
+        let keypair = Keypair::generate_ed25519();
+        let identity = Identity { name: "alice".to_string(), email: None, peer_id: PeerId::from(keypair.public()).to_base58() };
+        let config = NetworkConfig { listen: vec!["/ip4/127.0.0.1/tcp/0".parse().unwrap()], bootstrap: vec![] };
+        NetworkManager::new(&NodeIdentity { keypair, identity }, &config).unwrap()
+    }
+
+    fn fragment(id: &str, index: u32, total: u32, data: &[u8]) -> Fragment {
+        Fragment { id: id.to_string(), index, total, data: BASE64.encode(data) }
+    }
+
+    #[tokio::test]
+    async fn fragments_reassemble_in_any_order() {
+        let mut network = manager();
+        let peer = PeerId::random();
+        assert_eq!(network.reassemble(peer, fragment("m", 2, 3, b"c")), None);
+        assert_eq!(network.reassemble(peer, fragment("m", 0, 3, b"a")), None);
+        // A repeated piece changes nothing.
+        assert_eq!(network.reassemble(peer, fragment("m", 0, 3, b"a")), None);
+        // The same id from someone else is a different message.
+        assert_eq!(network.reassemble(PeerId::random(), fragment("m", 1, 3, b"b")), None);
+        assert_eq!(network.reassemble(peer, fragment("m", 1, 3, b"b")), Some(b"abc".to_vec()));
+        assert!(!network.partial.contains_key(&(peer, "m".to_string())));
+
+        assert_eq!(network.reassemble(peer, fragment("bad", 3, 3, b"x")), None, "index past the end");
+        assert_eq!(network.reassemble(peer, fragment("bad", 0, MAX_FRAGMENTS + 1, b"x")), None, "too many pieces");
+        assert_eq!(network.reassemble(peer, fragment("m2", 0, 2, b"a")), None);
+        assert_eq!(network.reassemble(peer, fragment("m2", 1, 3, b"b")), None, "total changed midway");
+    }
+
+    #[tokio::test]
+    async fn partial_messages_are_bounded_and_expire() {
+        let mut network = manager();
+        let peer = PeerId::random();
+        for i in 0..MAX_PARTIAL_MESSAGES {
+            network.reassemble(peer, fragment(&i.to_string(), 0, 2, b"a"));
+        }
+        assert_eq!(network.reassemble(peer, fragment("late", 0, 1, b"a")), None, "no room for a new message");
+        // Messages already started can still finish.
+        assert_eq!(network.reassemble(peer, fragment("0", 1, 2, b"b")), Some(b"ab".to_vec()));
+
+        let expired = Instant::now().checked_sub(FRAGMENT_TIMEOUT + Duration::from_secs(1)).unwrap();
+        for (started, _, _) in network.partial.values_mut() {
+            *started = expired;
+        }
+        assert_eq!(network.reassemble(peer, fragment("late", 0, 1, b"a")), Some(b"a".to_vec()));
+        assert!(network.partial.is_empty());
+    }
+
+    #[tokio::test]
+    async fn buffered_bytes_are_bounded_per_peer_and_overall() {
+        let mut network = manager();
+        let piece = [0u8; FRAGMENT_LEN];
+        let buffered = |network: &NetworkManager| -> usize { network.partial.values().map(|(_, len, _)| len).sum() };
+        // Piece 0 never arrives, so nothing completes.
+        let fill = |network: &mut NetworkManager, peer: PeerId| {
+            for message in 0..3 {
+                for index in 1..MAX_FRAGMENTS {
+                    network.reassemble(peer, fragment(&message.to_string(), index, MAX_FRAGMENTS, &piece));
+                }
+            }
+        };
+
+        let oversized = [0u8; FRAGMENT_LEN + 1];
+        assert_eq!(network.reassemble(PeerId::random(), fragment("m", 0, 1, &oversized)), None, "pieces are at most FRAGMENT_LEN");
+
+        let peers: Vec<PeerId> = (0..MAX_BUFFERED / MAX_BUFFERED_PER_PEER).map(|_| PeerId::random()).collect();
+        fill(&mut network, peers[0]);
+        assert_eq!(buffered(&network), MAX_BUFFERED_PER_PEER);
+        assert_eq!(network.reassemble(peers[1], fragment("m", 0, 1, b"a")), Some(b"a".to_vec()), "others aren't held up");
+
+        for peer in &peers[1..] {
+            fill(&mut network, *peer);
+        }
+        assert_eq!(buffered(&network), MAX_BUFFERED);
+        let late = PeerId::random();
+        assert_eq!(network.reassemble(late, fragment("m", 0, 1, b"a")), None, "no room for anyone");
+    }
+
+    #[tokio::test]
+    async fn messages_too_big_to_reassemble_are_not_sent() {
+        let mut network = manager();
+        let chat = ChatLine {
+            id: "c1".to_string(),
+            session_id: "s1".to_string(),
+            author: "alice".to_string(),
+            author_id: String::new(),
+            body: "x".repeat(MAX_FRAGMENTS as usize * FRAGMENT_LEN),
+            created_at: chrono::Utc::now(),
+            signature: String::new(),
+        };
+        assert!(network.publish_chat(&chat).is_err());
+        assert!(network.publish_chat(&ChatLine { body: "x".repeat(4 * FRAGMENT_LEN), ..chat }).is_ok());
+    }
+}
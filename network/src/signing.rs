@@ -0,0 +1,162 @@
+use std::fmt;
+
+use base58::{FromBase58, ToBase58};
+use libp2p::identity::{Keypair, PublicKey};
+use libp2p::PeerId;
+
+use common::{ReviewSession, SessionRecords, Signable};
+
+/// Whether a record's signature matches the node it names as author.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verification {
+    Verified,
+    Unsigned,
+    Invalid,
+}
+
+impl fmt::Display for Verification {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Verification::Verified => "verified",
+            Verification::Unsigned => "unsigned",
+            Verification::Invalid => "invalid signature",
+        })
+    }
+}
+
+/// Signs `record` with `keypair`, replacing any previous signature.
+pub fn sign(record: &mut dyn Signable, keypair: &Keypair) {
+    let signature = keypair
+        .sign(&record.signing_payload())
+        .expect("ed25519 signing cannot fail");
+    record.set_signature(signature.to_base58());
+}
+
+pub fn verify(record: &dyn Signable) -> Verification {
+    if record.signature().is_empty() {
+        return Verification::Unsigned;
+    }
+    let key = record
+        .author_id()
+        .parse::<PeerId>()
+        .ok()
+        .and_then(|peer_id| public_key_of(&peer_id));
+    let signature = record.signature().from_base58().ok();
+    match (key, signature) {
+        (Some(key), Some(signature)) if key.verify(&record.signing_payload(), &signature) => Verification::Verified,
+        _ => Verification::Invalid,
+    }
+}
+
+/// Keeps only the records signed by who they claim, for records that come
+/// from somewhere other than the mesh, like an archive or a git ref. The
+/// session must be signed by its owner, and by the same owner as `known`,
+/// the local copy, if there is one. The diff must come from the owner and
+/// everything else from members. `None` if the session fails.
+pub fn verify_records(mut records: SessionRecords, known: Option<&ReviewSession>) -> Option<SessionRecords> {
+    let valid = |record: &dyn Signable| verify(record) == Verification::Verified;
+    if !valid(&records.session) || known.is_some_and(|k| !k.owner.is_empty() && k.owner != records.session.owner) {
+        return None;
+    }
+    // Membership as of whichever copy of the session is newer.
+    let session = match known {
+        Some(known) if known.revision >= records.session.revision => known.clone(),
+        _ => records.session.clone(),
+    };
+    records.diff = records.diff.filter(|d| valid(d) && d.author_id == session.owner);
+    records.comments.retain(|c| valid(c) && session.is_member(&c.author_id));
+    records.chat.retain(|c| valid(c) && session.is_member(&c.author_id));
+    records.verdicts.retain(|v| valid(v) && session.is_member(&v.author_id));
+    Some(records)
+}
+
+/// Ed25519 PeerIds are an identity multihash of the public key itself, so
+/// the key can be read back without it travelling alongside the record.
+pub(crate) fn public_key_of(peer_id: &PeerId) -> Option<PublicKey> {
+    let bytes = peer_id.to_bytes();
+    match bytes.as_slice() {
+        [0x00, len, key @ ..] if *len as usize == key.len() => PublicKey::try_decode_protobuf(key).ok(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use common::{ChatLine, SessionDetails, SessionState};
+
+    #[test]
+    fn tampering_invalidates_signature() {
+        let keypair = Keypair::generate_ed25519();
+        let mut chat = ChatLine {
+            id: "1".to_string(),
+            session_id: "s".to_string(),
+            author: "alice".to_string(),
+            author_id: PeerId::from(keypair.public()).to_base58(),
+            body: "LGTM".to_string(),
+            created_at: Utc::now(),
+            signature: String::new(),
+        };
+        assert_eq!(verify(&chat), Verification::Unsigned);
+
+        sign(&mut chat, &keypair);
+        assert_eq!(verify(&chat), Verification::Verified);
+
+        chat.body = "Ship it".to_string();
+        assert_eq!(verify(&chat), Verification::Invalid);
+    }
+
+    #[test]
+    fn records_need_the_owner_and_members_to_sign() {
+        let (owner, stranger) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        let peer_id = |keypair: &Keypair| PeerId::from(keypair.public()).to_base58();
+        let mut session = ReviewSession {
+            id: "s".to_string(),
+            title: "Login".to_string(),
+            created_at: Utc::now(),
+            participants: vec![],
+            owner: peer_id(&owner),
+            members: vec![],
+            revoked: vec![],
+            revision: 1,
+            signature: String::new(),
+            state: SessionState::Open,
+            details: SessionDetails::default(),
+        };
+        sign(&mut session, &owner);
+        let chat = |keypair: &Keypair, id: &str| {
+            let mut chat = ChatLine {
+                id: id.to_string(),
+                session_id: "s".to_string(),
+                author: String::new(),
+                author_id: peer_id(keypair),
+                body: "LGTM".to_string(),
+                created_at: Utc::now(),
+                signature: String::new(),
+            };
+            sign(&mut chat, keypair);
+            chat
+        };
+        let unsigned = ChatLine { id: "unsigned".to_string(), signature: String::new(), ..chat(&owner, "") };
+        let records = SessionRecords {
+            session: session.clone(),
+            diff: None,
+            comments: vec![],
+            chat: vec![chat(&owner, "owner"), chat(&stranger, "stranger"), unsigned],
+            verdicts: vec![],
+        };
+
+        let kept = verify_records(records.clone(), None).unwrap();
+        assert_eq!(kept.chat.iter().map(|c| c.id.as_str()).collect::<Vec<_>>(), ["owner"]);
+
+        // A newer local copy that added the stranger lets their line in.
+        let known = ReviewSession { members: vec![peer_id(&stranger)], revision: 2, ..session.clone() };
+        assert_eq!(verify_records(records.clone(), Some(&known)).unwrap().chat.len(), 2);
+
+        // Someone else can't take the session over by signing it themselves.
+        let mut taken = ReviewSession { owner: peer_id(&stranger), ..session };
+        sign(&mut taken, &stranger);
+        assert!(verify_records(SessionRecords { session: taken, ..records }, Some(&known)).is_none());
+    }
+}
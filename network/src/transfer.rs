@@ -0,0 +1,70 @@
+use std::error::Error;
+use std::time::Duration;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use libp2p::request_response::{self, ProtocolSupport, ResponseChannel};
+use libp2p::{PeerId, StreamProtocol};
+use serde::{Deserialize, Serialize};
+
+const BUNDLE_PROTOCOL: StreamProtocol = StreamProtocol::new("/reviewmesh/bundle/1.0.0");
+
+/// The JSON codec's cap on a response.
+const RESPONSE_LIMIT: usize = 10 * 1024 * 1024;
+
+/// The largest bundle that fits in a response once base64 has grown it by a
+/// third, leaving room for the JSON around it.
+pub const MAX_BUNDLE_LEN: usize = (RESPONSE_LIMIT - 1024) / 4 * 3;
+
+/// Asks a session's owner for a git bundle of the reviewed commits.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BundleRequest {
+    pub session_id: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum BundleResponse {
+    /// Base64 of the bundle file, at most [`MAX_BUNDLE_LEN`] bytes before
+    /// encoding.
+    Bundle(String),
+    Refused(String),
+}
+
+impl BundleResponse {
+    /// The bundle file, or a refusal when it is too big to send.
+    pub fn from_bytes(bundle: &[u8]) -> Self {
+        if bundle.len() > MAX_BUNDLE_LEN {
+            return BundleResponse::Refused(format!(
+                "the bundle is {} KiB, over the {} KiB that can be sent; push the branch somewhere the reviewer can fetch it",
+                bundle.len() / 1024,
+                MAX_BUNDLE_LEN / 1024
+            ));
+        }
+        BundleResponse::Bundle(BASE64.encode(bundle))
+    }
+
+    /// The bundle file, or why the peer refused to send it.
+    pub fn into_bytes(self) -> Result<Vec<u8>, Box<dyn Error>> {
+        match self {
+            BundleResponse::Bundle(data) => Ok(BASE64.decode(data)?),
+            BundleResponse::Refused(reason) => Err(reason.into()),
+        }
+    }
+}
+
+pub type Behaviour = request_response::json::Behaviour<BundleRequest, BundleResponse>;
+pub type Event = request_response::Event<BundleRequest, BundleResponse>;
+
+/// A bundle request waiting for the application to answer it.
+pub struct IncomingBundleRequest {
+    pub peer: PeerId,
+    pub session_id: String,
+    pub(crate) channel: ResponseChannel<BundleResponse>,
+}
+
+pub(crate) fn behaviour() -> Behaviour {
+    let mut config = request_response::Config::default();
+    // Writing and sending a bundle can take a while on a busy node.
+    config.set_request_timeout(Duration::from_secs(120));
+    Behaviour::new([(BUNDLE_PROTOCOL, ProtocolSupport::Full)], config)
+}
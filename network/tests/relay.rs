@@ -0,0 +1,131 @@
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use common::testing::TempDir;
+use common::{ChatLine, MeshMessage};
+use network::config::NetworkConfig;
+use network::identity::NodeIdentity;
+use network::transfer::BundleResponse;
+use network::{Multiaddr, NetworkManager};
+
+/// Which part a re-run of this test binary plays, the relay to use and
+/// where the test keeps node keys.
+const ROLE: &str = "REVIEWMESH_TEST_ROLE";
+const RELAY: &str = "REVIEWMESH_TEST_RELAY";
+const DIR: &str = "REVIEWMESH_TEST_DIR";
+
+fn node(dir: &Path, name: &str) -> NodeIdentity {
+    NodeIdentity::load(&dir.join(name), name.to_string(), None).unwrap()
+}
+
+/// Only the relay's address is known; everything else goes through it.
+fn config(relay: Option<&Multiaddr>) -> NetworkConfig {
+    NetworkConfig {
+        listen: vec!["/ip4/127.0.0.1/tcp/0".parse().unwrap()],
+        bootstrap: relay.into_iter().cloned().collect(),
+    }
+}
+
+/// Re-runs this binary as `role`, which the test of that name picks up.
+fn spawn(role: &str, dir: &Path, relay: Option<&Multiaddr>) -> Child {
+    let mut command = Command::new(std::env::current_exe().unwrap());
+    command.args([role, "--exact", "--nocapture"]).env(ROLE, role).env(DIR, dir).stdout(Stdio::piped());
+    if let Some(relay) = relay {
+        command.env(RELAY, relay.to_string());
+    }
+    command.spawn().unwrap()
+}
+
+/// Kills the child processes however the test ends.
+struct Children(Vec<Child>);
+
+impl Drop for Children {
+    fn drop(&mut self) {
+        for child in &mut self.0 {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+#[tokio::test]
+async fn relay_process() {
+    if std::env::var(ROLE).as_deref() != Ok("relay_process") {
+        return;
+    }
+    let dir = PathBuf::from(std::env::var(DIR).unwrap());
+    let mut network = NetworkManager::relay(&node(&dir, "relay"), &config(None)).unwrap();
+    // Prints its addresses for the parent and runs until killed.
+    loop {
+        network.run_for(Duration::from_millis(200)).await;
+        for address in network.new_listen_addresses() {
+            println!("Listening on {}", address);
+        }
+    }
+}
+
+#[tokio::test]
+async fn publisher_process() {
+    if std::env::var(ROLE).as_deref() != Ok("publisher_process") {
+        return;
+    }
+    let relay: Multiaddr = std::env::var(RELAY).unwrap().parse().unwrap();
+    let node = node(&PathBuf::from(std::env::var(DIR).unwrap()), "publisher");
+    let mut network = NetworkManager::new(&node, &config(Some(&relay))).unwrap();
+    network.wait_for_peers(Duration::from_secs(20)).await;
+    let mut chat = ChatLine {
+        id: "c1".to_string(),
+        session_id: "s1".to_string(),
+        author: "alice".to_string(),
+        author_id: node.identity.peer_id.clone(),
+        body: "Hello through the relay".to_string(),
+        created_at: Utc::now(),
+        signature: String::new(),
+    };
+    node.sign(&mut chat);
+    // Floodsub doesn't store messages, so keep sending until killed.
+    loop {
+        network.publish_chat(&chat).unwrap();
+        network.run_for(Duration::from_millis(500)).await;
+    }
+}
+
+#[tokio::test]
+async fn peers_in_separate_processes_talk_through_a_relay() {
+    if std::env::var(ROLE).is_ok() {
+        return;
+    }
+    let dir = TempDir::new("relay");
+    let mut children = Children(vec![spawn("relay_process", dir.path(), None)]);
+    let stdout = children.0[0].stdout.take().unwrap();
+    let relay: Multiaddr = BufReader::new(stdout)
+        .lines()
+        .map_while(Result::ok)
+        // The test harness may have printed on the same line first.
+        .find_map(|line| Some(line.split_once("Listening on ")?.1.to_string()))
+        .expect("the relay printed its address")
+        .parse()
+        .unwrap();
+    children.0.push(spawn("publisher_process", dir.path(), Some(&relay)));
+
+    let mut network = NetworkManager::new(&node(dir.path(), "receiver"), &config(Some(&relay))).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(30);
+    let mut received = None;
+    while received.is_none() && Instant::now() < deadline {
+        network.run_for(Duration::from_millis(200)).await;
+        received = network.poll_messages().into_iter().find_map(|message| match message {
+            MeshMessage::Chat(chat) => Some(chat),
+            _ => None,
+        });
+    }
+    assert_eq!(received.expect("the chat line arrived").body, "Hello through the relay");
+
+    // The relay has no repository, so it turns bundle requests away.
+    let relay_peer = network::config::peer_of(&relay).unwrap();
+    let response = network.fetch_bundle(&relay_peer, "s1", Duration::from_secs(10)).await.unwrap();
+    assert!(matches!(response, BundleResponse::Refused(_)));
+
+}
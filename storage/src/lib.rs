@@ -1,46 +1,244 @@
+use chrono::{DateTime, Utc};
 use rusqlite::{Connection, Result, params};
-use common::{ReviewSession, Comment, ChatLine};
+use common::{ReviewSession, Comment, ChatLine, SessionRecords, SharedDiff, Verdict};
+
+mod search;
+
+pub use search::{HitRecord, SearchFilter, SearchHit};
+
+/// A session with the counts shown when choosing one to open.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionSummary {
+    pub session: ReviewSession,
+    /// Branch of the shared diff, once the owner has opened one.
+    pub target_branch: Option<String>,
+    pub open_comments: usize,
+    pub resolved_comments: usize,
+    /// Newest of the session's creation, diff, comments, chat and verdicts.
+    pub last_activity: DateTime<Utc>,
+}
 
 pub struct Storage {
     conn: Connection,
+    /// Identity of the repository new sessions are filed under; empty when
+    /// the database isn't tied to one.
+    repo: String,
 }
 
+/// Columns of each table. Every table is keyed by repository first, so
+/// repositories sharing a database can use the same session ids.
+const TABLES: [(&str, &str); 6] = [
+    (
+        "sessions",
+        "repo TEXT NOT NULL DEFAULT '', id TEXT, title TEXT, created_at TEXT, participants TEXT,
+         owner TEXT NOT NULL DEFAULT '', members TEXT NOT NULL DEFAULT '[]', revoked TEXT NOT NULL DEFAULT '[]',
+         revision INTEGER NOT NULL DEFAULT 0, signature TEXT NOT NULL DEFAULT '', state TEXT NOT NULL DEFAULT 'open',
+         details TEXT NOT NULL DEFAULT '{}',
+         PRIMARY KEY (repo, id)",
+    ),
+    (
+        "comments",
+        "repo TEXT NOT NULL DEFAULT '', id TEXT, session_id TEXT, author TEXT, author_id TEXT NOT NULL DEFAULT '',
+         file TEXT, hunk_id TEXT, line INTEGER, body TEXT, created_at TEXT, resolved INTEGER, resolved_at TEXT,
+         side TEXT NOT NULL DEFAULT 'new', signature TEXT NOT NULL DEFAULT '',
+         PRIMARY KEY (repo, id)",
+    ),
+    (
+        "chat",
+        "repo TEXT NOT NULL DEFAULT '', id TEXT, session_id TEXT, author TEXT, author_id TEXT NOT NULL DEFAULT '',
+         body TEXT, created_at TEXT, signature TEXT NOT NULL DEFAULT '',
+         PRIMARY KEY (repo, id)",
+    ),
+    (
+        "verdicts",
+        "repo TEXT NOT NULL DEFAULT '', id TEXT, session_id TEXT, author TEXT, author_id TEXT, decision TEXT,
+         body TEXT, created_at TEXT, signature TEXT NOT NULL DEFAULT '',
+         PRIMARY KEY (repo, id)",
+    ),
+    (
+        "session_keys",
+        "repo TEXT NOT NULL DEFAULT '', session_id TEXT, epoch INTEGER, key BLOB,
+         PRIMARY KEY (repo, session_id, epoch)",
+    ),
+    (
+        "diffs",
+        "repo TEXT NOT NULL DEFAULT '', session_id TEXT, target_branch TEXT, head_commit TEXT NOT NULL DEFAULT '',
+         hunks TEXT, author_id TEXT, created_at TEXT, signature TEXT NOT NULL DEFAULT '',
+         PRIMARY KEY (repo, session_id)",
+    ),
+];
+
 impl Storage {
     pub fn new(path: &str) -> Result<Self> {
         let conn = Connection::open(path)?;
-        // Create tables if not exist
-        conn.execute_batch(r#"
-            CREATE TABLE IF NOT EXISTS sessions (
-                id TEXT PRIMARY KEY,
-                title TEXT,
-                created_at TEXT,
-                participants TEXT
-            );
-            CREATE TABLE IF NOT EXISTS comments (
-                id TEXT PRIMARY KEY,
-                session_id TEXT,
-                author TEXT,
-                file TEXT,
-                hunk_id TEXT,
-                line INTEGER,
-                body TEXT,
-                created_at TEXT,
-                resolved INTEGER
-            );
-            CREATE TABLE IF NOT EXISTS chat (
-                id TEXT PRIMARY KEY,
-                session_id TEXT,
-                author TEXT,
-                body TEXT,
-                created_at TEXT
-            );
-        "#)?;
-        Ok(Self { conn })
+        for (table, columns) in TABLES {
+            conn.execute_batch(&format!("CREATE TABLE IF NOT EXISTS {} ({})", table, columns))?;
+        }
+        // Columns added after the first release
+        ensure_column(&conn, "comments", "author_id", "TEXT NOT NULL DEFAULT ''")?;
+        ensure_column(&conn, "chat", "author_id", "TEXT NOT NULL DEFAULT ''")?;
+        ensure_column(&conn, "comments", "signature", "TEXT NOT NULL DEFAULT ''")?;
+        ensure_column(&conn, "chat", "signature", "TEXT NOT NULL DEFAULT ''")?;
+        ensure_column(&conn, "sessions", "owner", "TEXT NOT NULL DEFAULT ''")?;
+        ensure_column(&conn, "sessions", "members", "TEXT NOT NULL DEFAULT '[]'")?;
+        ensure_column(&conn, "sessions", "revoked", "TEXT NOT NULL DEFAULT '[]'")?;
+        ensure_column(&conn, "sessions", "revision", "INTEGER NOT NULL DEFAULT 0")?;
+        ensure_column(&conn, "sessions", "signature", "TEXT NOT NULL DEFAULT ''")?;
+        ensure_column(&conn, "sessions", "state", "TEXT NOT NULL DEFAULT 'open'")?;
+        ensure_column(&conn, "sessions", "details", "TEXT NOT NULL DEFAULT '{}'")?;
+        ensure_column(&conn, "diffs", "head_commit", "TEXT NOT NULL DEFAULT ''")?;
+        ensure_column(&conn, "comments", "resolved_at", "TEXT")?;
+        ensure_column(&conn, "comments", "side", "TEXT NOT NULL DEFAULT 'new'")?;
+        for (table, columns) in TABLES {
+            ensure_column(&conn, table, "repo", "TEXT NOT NULL DEFAULT ''")?;
+            rekey(&conn, table, columns)?;
+        }
+        search::create_index(&conn)?;
+        Ok(Self { conn, repo: String::new() })
+    }
+
+    /// Files new records under `repo` and reads only that repository's,
+    /// plus those saved before records were filed by repository. Lets one
+    /// database serve several repositories, even with the same session ids.
+    pub fn for_repo(mut self, repo: &str) -> Self {
+        self.repo = repo.to_string();
+        self
+    }
+
+    /// Files a session saved before records were filed by repository, with
+    /// its records, under this repository once it is written to from here.
+    fn adopt(&self, session_id: &str) -> Result<()> {
+        if self.repo.is_empty() {
+            return Ok(());
+        }
+        self.conn.execute("UPDATE OR IGNORE sessions SET repo = ?1 WHERE repo = '' AND id = ?2", params![self.repo, session_id])?;
+        for table in ["comments", "chat", "verdicts", "session_keys", "diffs"] {
+            self.conn.execute(
+                &format!("UPDATE OR IGNORE {} SET repo = ?1 WHERE repo = '' AND session_id = ?2", table),
+                params![self.repo, session_id],
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn save_session(&self, session: &ReviewSession) -> Result<()> {
+        self.adopt(&session.id)?;
+        self.conn.execute(
+            "INSERT OR REPLACE INTO sessions (id, title, created_at, participants, owner, members, revoked, revision, signature, repo, state, details) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+            params![
+                session.id,
+                session.title,
+                session.created_at.to_rfc3339(),
+                serde_json::to_string(&session.participants).unwrap_or_default(),
+                session.owner,
+                serde_json::to_string(&session.members).unwrap_or_default(),
+                serde_json::to_string(&session.revoked).unwrap_or_default(),
+                session.revision as i64,
+                session.signature,
+                self.repo,
+                session.state.as_str(),
+                serde_json::to_string(&session.details).unwrap_or_default(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn load_session(&self, session_id: &str) -> Result<Option<ReviewSession>> {
+        let mut stmt = self.conn.prepare("SELECT id, title, created_at, participants, owner, members, revoked, revision, signature, state, details FROM sessions WHERE id = ?1 AND (?2 = '' OR repo IN (?2, '')) ORDER BY repo DESC")?;
+        let mut rows = stmt.query_map(params![session_id, self.repo], session_from_row)?;
+        rows.next().transpose()
+    }
+
+    pub fn list_sessions(&self) -> Result<Vec<ReviewSession>> {
+        let mut stmt = self.conn.prepare("SELECT id, title, created_at, participants, owner, members, revoked, revision, signature, state, details FROM sessions WHERE ?1 = '' OR repo IN (?1, '') ORDER BY created_at")?;
+        let rows = stmt.query_map(params![self.repo], session_from_row)?;
+        Ok(rows.filter_map(Result::ok).collect())
+    }
+
+    /// Every listed session with its activity, most recently active first.
+    pub fn session_summaries(&self) -> Result<Vec<SessionSummary>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT s.id, s.title, s.created_at, s.participants, s.owner, s.members, s.revoked, s.revision, s.signature, s.state, s.details,
+                    d.target_branch,
+                    (SELECT COUNT(*) FROM comments WHERE session_id = s.id AND repo = s.repo AND resolved = 0),
+                    (SELECT COUNT(*) FROM comments WHERE session_id = s.id AND repo = s.repo AND resolved != 0),
+                    MAX(s.created_at,
+                        COALESCE(d.created_at, ''),
+                        COALESCE((SELECT MAX(created_at) FROM comments WHERE session_id = s.id AND repo = s.repo), ''),
+                        COALESCE((SELECT MAX(created_at) FROM chat WHERE session_id = s.id AND repo = s.repo), ''),
+                        COALESCE((SELECT MAX(created_at) FROM verdicts WHERE session_id = s.id AND repo = s.repo), '')) AS last_activity
+             FROM sessions s LEFT JOIN diffs d ON d.session_id = s.id AND d.repo = s.repo
+             WHERE ?1 = '' OR s.repo IN (?1, '')
+             ORDER BY last_activity DESC",
+        )?;
+        let rows = stmt.query_map(params![self.repo], |row| {
+            let session = session_from_row(row)?;
+            Ok(SessionSummary {
+                target_branch: row.get(11)?,
+                open_comments: row.get::<_, i64>(12)? as usize,
+                resolved_comments: row.get::<_, i64>(13)? as usize,
+                last_activity: row.get::<_, String>(14)?.parse().unwrap_or(session.created_at),
+                session,
+            })
+        })?;
+        Ok(rows.filter_map(Result::ok).collect())
+    }
+
+    pub fn save_session_key(&self, session_id: &str, epoch: u64, key: &[u8]) -> Result<()> {
+        self.adopt(session_id)?;
+        self.conn.execute(
+            "INSERT OR REPLACE INTO session_keys (session_id, epoch, key, repo) VALUES (?1, ?2, ?3, ?4)",
+            params![session_id, epoch as i64, key, self.repo],
+        )?;
+        Ok(())
+    }
+
+    /// Every key a session has used, oldest epoch first.
+    pub fn load_session_keys(&self, session_id: &str) -> Result<Vec<(u64, Vec<u8>)>> {
+        let mut stmt = self.conn.prepare("SELECT epoch, key FROM session_keys WHERE session_id = ?1 AND (?2 = '' OR repo IN (?2, '')) ORDER BY epoch")?;
+        let rows = stmt.query_map(params![session_id, self.repo], |row| Ok((row.get::<_, i64>(0)? as u64, row.get(1)?)))?;
+        Ok(rows.filter_map(Result::ok).collect())
+    }
+
+    /// Stores the diff shared for a session, replacing the previous one.
+    pub fn save_diff(&self, diff: &SharedDiff) -> Result<()> {
+        self.adopt(&diff.session_id)?;
+        self.conn.execute(
+            "INSERT OR REPLACE INTO diffs (session_id, target_branch, hunks, author_id, created_at, signature, head_commit, repo) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                diff.session_id,
+                diff.target_branch,
+                serde_json::to_string(&diff.hunks).unwrap_or_default(),
+                diff.author_id,
+                diff.created_at.to_rfc3339(),
+                diff.signature,
+                diff.head_commit,
+                self.repo,
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn load_diff(&self, session_id: &str) -> Result<Option<SharedDiff>> {
+        let mut stmt = self.conn.prepare("SELECT session_id, target_branch, hunks, author_id, created_at, signature, head_commit FROM diffs WHERE session_id = ?1 AND (?2 = '' OR repo IN (?2, '')) ORDER BY repo DESC")?;
+        let mut rows = stmt.query_map(params![session_id, self.repo], |row| {
+            Ok(SharedDiff {
+                session_id: row.get(0)?,
+                target_branch: row.get(1)?,
+                head_commit: row.get(6)?,
+                hunks: serde_json::from_str(&row.get::<_, String>(2)?).unwrap_or_default(),
+                author_id: row.get(3)?,
+                created_at: row.get::<_, String>(4)?.parse().unwrap_or_else(|_| chrono::Utc::now()),
+                signature: row.get(5)?,
+            })
+        })?;
+        rows.next().transpose()
     }
 
     pub fn save_comment(&self, comment: &Comment) -> Result<()> {
+        self.adopt(&comment.session_id)?;
         self.conn.execute(
-            "INSERT OR REPLACE INTO comments (id, session_id, author, file, hunk_id, line, body, created_at, resolved) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            "INSERT OR REPLACE INTO comments (id, session_id, author, file, hunk_id, line, body, created_at, resolved, author_id, signature, resolved_at, side, repo) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)",
             params![
                 comment.id,
                 comment.session_id,
@@ -51,58 +249,151 @@ impl Storage {
                 comment.body,
                 comment.created_at.to_rfc3339(),
                 comment.resolved as i64,
+                comment.author_id,
+                comment.signature,
+                comment.resolved_at.map(|t| t.to_rfc3339()),
+                comment.side.as_str(),
+                self.repo,
             ],
         )?;
         Ok(())
     }
 
     pub fn save_chat(&self, chat: &ChatLine) -> Result<()> {
+        self.adopt(&chat.session_id)?;
         self.conn.execute(
-            "INSERT OR REPLACE INTO chat (id, session_id, author, body, created_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+            "INSERT OR REPLACE INTO chat (id, session_id, author, body, created_at, author_id, signature, repo) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
             params![
                 chat.id,
                 chat.session_id,
                 chat.author,
                 chat.body,
                 chat.created_at.to_rfc3339(),
+                chat.author_id,
+                chat.signature,
+                self.repo,
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn save_verdict(&self, verdict: &Verdict) -> Result<()> {
+        self.adopt(&verdict.session_id)?;
+        self.conn.execute(
+            "INSERT OR REPLACE INTO verdicts (id, session_id, author, author_id, decision, body, created_at, signature, repo) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![
+                verdict.id,
+                verdict.session_id,
+                verdict.author,
+                verdict.author_id,
+                verdict.decision.as_str(),
+                verdict.body,
+                verdict.created_at.to_rfc3339(),
+                verdict.signature,
+                self.repo,
             ],
         )?;
         Ok(())
     }
 
     pub fn load_comments(&self, session_id: &str) -> Result<Vec<Comment>> {
-        let mut stmt = self.conn.prepare("SELECT id, session_id, author, file, hunk_id, line, body, created_at, resolved FROM comments WHERE session_id = ?1")?;
-        let rows = stmt.query_map(params![session_id], |row| {
-            Ok(Comment {
-                id: row.get(0)?,
-                session_id: row.get(1)?,
-                author: row.get(2)?,
-                file: row.get(3)?,
-                hunk_id: row.get(4)?,
-                line: row.get::<_, i64>(5)? as usize,
-                body: row.get(6)?,
-                created_at: row.get::<_, String>(7)?.parse().unwrap_or_else(|_| chrono::Utc::now()),
-                resolved: row.get::<_, i64>(8)? != 0,
-            })
-        })?;
+        let mut stmt = self.conn.prepare("SELECT id, session_id, author, file, hunk_id, line, body, created_at, resolved, author_id, signature, resolved_at, side FROM comments WHERE session_id = ?1 AND (?2 = '' OR repo IN (?2, '')) ORDER BY created_at")?;
+        let rows = stmt.query_map(params![session_id, self.repo], comment_from_row)?;
         Ok(rows.filter_map(Result::ok).collect())
     }
 
+    pub fn load_comment(&self, comment_id: &str) -> Result<Option<Comment>> {
+        let mut stmt = self.conn.prepare("SELECT id, session_id, author, file, hunk_id, line, body, created_at, resolved, author_id, signature, resolved_at, side FROM comments WHERE id = ?1 AND (?2 = '' OR repo IN (?2, '')) ORDER BY repo DESC")?;
+        let mut rows = stmt.query_map(params![comment_id, self.repo], comment_from_row)?;
+        rows.next().transpose()
+    }
+
     pub fn load_chat(&self, session_id: &str) -> Result<Vec<ChatLine>> {
-        let mut stmt = self.conn.prepare("SELECT id, session_id, author, body, created_at FROM chat WHERE session_id = ?1")?;
-        let rows = stmt.query_map(params![session_id], |row| {
-            Ok(ChatLine {
+        let mut stmt = self.conn.prepare("SELECT id, session_id, author, body, created_at, author_id, signature FROM chat WHERE session_id = ?1 AND (?2 = '' OR repo IN (?2, '')) ORDER BY created_at")?;
+        let rows = stmt.query_map(params![session_id, self.repo], chat_from_row)?;
+        Ok(rows.filter_map(Result::ok).collect())
+    }
+
+    /// Every verdict given in a session, oldest first.
+    pub fn load_verdicts(&self, session_id: &str) -> Result<Vec<Verdict>> {
+        let mut stmt = self.conn.prepare("SELECT id, session_id, author, author_id, decision, body, created_at, signature FROM verdicts WHERE session_id = ?1 AND (?2 = '' OR repo IN (?2, '')) ORDER BY created_at")?;
+        let rows = stmt.query_map(params![session_id, self.repo], |row| {
+            let decision: String = row.get(4)?;
+            Ok(Verdict {
                 id: row.get(0)?,
                 session_id: row.get(1)?,
                 author: row.get(2)?,
-                body: row.get(3)?,
-                created_at: row.get::<_, String>(4)?.parse().unwrap_or_else(|_| chrono::Utc::now()),
+                author_id: row.get(3)?,
+                decision: decision.parse().map_err(|e: String| {
+                    rusqlite::Error::FromSqlConversionFailure(4, rusqlite::types::Type::Text, e.into())
+                })?,
+                body: row.get(5)?,
+                created_at: row.get::<_, String>(6)?.parse().unwrap_or_else(|_| chrono::Utc::now()),
+                signature: row.get(7)?,
             })
         })?;
         Ok(rows.filter_map(Result::ok).collect())
     }
 
-    pub fn queue_offline(&self, data: &[u8]) -> Result<()> {
+    pub fn load_records(&self, session_id: &str) -> Result<Option<SessionRecords>> {
+        let Some(session) = self.load_session(session_id)? else {
+            return Ok(None);
+        };
+        Ok(Some(SessionRecords {
+            session,
+            diff: self.load_diff(session_id)?,
+            comments: self.load_comments(session_id)?,
+            chat: self.load_chat(session_id)?,
+            verdicts: self.load_verdicts(session_id)?,
+        }))
+    }
+
+    /// Merges records from another store, keyed by record id, so merging the
+    /// same records twice changes nothing. The session and diff are taken
+    /// when newer, and a comment's resolved flag follows whichever side
+    /// changed it last.
+    pub fn merge_records(&self, records: &SessionRecords) -> Result<()> {
+        let id = &records.session.id;
+        let session = self.load_session(id)?;
+        let newer = session.as_ref().is_none_or(|s| {
+            (s.owner.is_empty() || s.owner == records.session.owner) && records.session.revision > s.revision
+        });
+        if newer {
+            self.save_session(&records.session)?;
+        }
+        if let Some(diff) = &records.diff {
+            if self.load_diff(id)?.is_none_or(|d| diff.created_at > d.created_at) {
+                self.save_diff(diff)?;
+            }
+        }
+        for comment in &records.comments {
+            match self.load_comment(&comment.id)? {
+                Some(mut existing) => {
+                    // Copies from before resolutions were timestamped only
+                    // ever resolved, so without times resolved wins.
+                    let newer = match (comment.resolved_at, existing.resolved_at) {
+                        (None, None) => comment.resolved && !existing.resolved,
+                        (incoming, current) => incoming > current,
+                    };
+                    if newer {
+                        existing.resolved = comment.resolved;
+                        existing.resolved_at = comment.resolved_at;
+                        self.save_comment(&existing)?;
+                    }
+                }
+                None => self.save_comment(comment)?,
+            }
+        }
+        for chat in &records.chat {
+            self.save_chat(chat)?;
+        }
+        for verdict in &records.verdicts {
+            self.save_verdict(verdict)?;
+        }
+        Ok(())
+    }
+
+    pub fn queue_offline(&self, _data: &[u8]) -> Result<()> {
         // Queue for offline replay
         todo!("queue offline")
     }
@@ -112,3 +403,279 @@ impl Storage {
         todo!("replay queue")
     }
 }
+
+/// Rebuilds a table created before rows were keyed by repository with the
+/// key in `columns`. Rowids are kept so the search index still matches.
+fn rekey(conn: &Connection, table: &str, columns: &str) -> Result<()> {
+    let keyed = conn
+        .prepare(&format!("SELECT 1 FROM pragma_table_info('{}') WHERE name = 'repo' AND pk > 0", table))?
+        .exists([])?;
+    if keyed {
+        return Ok(());
+    }
+    let names = conn
+        .prepare(&format!("SELECT name FROM pragma_table_info('{}')", table))?
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>>>()?
+        .join(", ");
+    conn.execute_batch(&format!(
+        "BEGIN;
+         ALTER TABLE {table} RENAME TO {table}_unkeyed;
+         CREATE TABLE {table} ({columns});
+         INSERT INTO {table} (rowid, {names}) SELECT rowid, {names} FROM {table}_unkeyed;
+         DROP TABLE {table}_unkeyed;
+         COMMIT;"
+    ))
+}
+
+/// Adds a column to a table created by an older version.
+fn ensure_column(conn: &Connection, table: &str, column: &str, decl: &str) -> Result<()> {
+    let exists = conn
+        .prepare(&format!("SELECT 1 FROM pragma_table_info('{}') WHERE name = ?1", table))?
+        .exists(params![column])?;
+    if !exists {
+        conn.execute_batch(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, decl))?;
+    }
+    Ok(())
+}
+
+fn session_from_row(row: &rusqlite::Row) -> Result<ReviewSession> {
+    Ok(ReviewSession {
+        id: row.get(0)?,
+        title: row.get(1)?,
+        created_at: row.get::<_, String>(2)?.parse().unwrap_or_else(|_| chrono::Utc::now()),
+        participants: serde_json::from_str(&row.get::<_, String>(3)?).unwrap_or_default(),
+        owner: row.get(4)?,
+        members: serde_json::from_str(&row.get::<_, String>(5)?).unwrap_or_default(),
+        revoked: serde_json::from_str(&row.get::<_, String>(6)?).unwrap_or_default(),
+        revision: row.get::<_, i64>(7)? as u64,
+        signature: row.get(8)?,
+        state: row.get::<_, String>(9)?.parse().unwrap_or_default(),
+        details: serde_json::from_str(&row.get::<_, String>(10)?).unwrap_or_default(),
+    })
+}
+
+fn chat_from_row(row: &rusqlite::Row) -> Result<ChatLine> {
+    Ok(ChatLine {
+        id: row.get(0)?,
+        session_id: row.get(1)?,
+        author: row.get(2)?,
+        author_id: row.get(5)?,
+        body: row.get(3)?,
+        created_at: row.get::<_, String>(4)?.parse().unwrap_or_else(|_| chrono::Utc::now()),
+        signature: row.get(6)?,
+    })
+}
+
+fn comment_from_row(row: &rusqlite::Row) -> Result<Comment> {
+    Ok(Comment {
+        id: row.get(0)?,
+        session_id: row.get(1)?,
+        author: row.get(2)?,
+        author_id: row.get(9)?,
+        file: row.get(3)?,
+        hunk_id: row.get(4)?,
+        line: row.get::<_, i64>(5)? as usize,
+        body: row.get(6)?,
+        created_at: row.get::<_, String>(7)?.parse().unwrap_or_else(|_| chrono::Utc::now()),
+        resolved: row.get::<_, i64>(8)? != 0,
+        resolved_at: row.get::<_, Option<String>>(11)?.and_then(|t| t.parse().ok()),
+        side: row.get::<_, String>(12)?.parse().unwrap_or_default(),
+        signature: row.get(10)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+    use common::testing::TempDir;
+    use common::{SessionDetails, SessionState, Side};
+
+    fn session(id: &str) -> ReviewSession {
+        ReviewSession {
+            id: id.to_string(),
+            title: format!("Review for {}", id),
+            created_at: Utc.timestamp_opt(1_600_000_000, 0).unwrap(),
+            participants: vec!["alice".to_string(), "bob".to_string()],
+            owner: "peer-a".to_string(),
+            members: vec!["peer-a".to_string(), "peer-b".to_string()],
+            revoked: vec![],
+            revision: 2,
+            signature: String::new(),
+            state: SessionState::Open,
+            details: SessionDetails::default(),
+        }
+    }
+
+    fn comment(id: &str, session_id: &str) -> Comment {
+        Comment {
+            id: id.to_string(),
+            session_id: session_id.to_string(),
+            author: "alice".to_string(),
+            author_id: "peer-a".to_string(),
+            file: "src/lib.rs".to_string(),
+            hunk_id: "h1".to_string(),
+            line: 3,
+            side: Side::New,
+            body: "Typo".to_string(),
+            created_at: Utc.timestamp_opt(1_600_000_100, 0).unwrap(),
+            resolved: false,
+            resolved_at: None,
+            signature: String::new(),
+        }
+    }
+
+    #[test]
+    fn session_roundtrip() {
+        let storage = Storage::new(":memory:").unwrap();
+        let session = session("sess1");
+        storage.save_session(&session).unwrap();
+        assert_eq!(storage.load_session("sess1").unwrap(), Some(session.clone()));
+        assert_eq!(storage.load_session("missing").unwrap(), None);
+        assert_eq!(storage.list_sessions().unwrap(), vec![session]);
+    }
+
+    #[test]
+    fn session_state_and_details_roundtrip() {
+        let storage = Storage::new(":memory:").unwrap();
+        let session = ReviewSession {
+            state: SessionState::Approved,
+            details: SessionDetails {
+                description: "Adds login".to_string(),
+                source_branch: "feature/login".to_string(),
+                target_branch: "main".to_string(),
+                labels: vec!["security".to_string()],
+                issues: vec!["#42".to_string()],
+                ..Default::default()
+            },
+            ..session("sess1")
+        };
+        storage.save_session(&session).unwrap();
+        assert_eq!(storage.load_session("sess1").unwrap(), Some(session.clone()));
+        assert_eq!(storage.session_summaries().unwrap()[0].session, session);
+    }
+
+    #[test]
+    fn sessions_are_listed_in_their_repository_only() {
+        let mut storage = Storage::new(":memory:").unwrap();
+        storage.save_session(&session("legacy")).unwrap();
+        storage = storage.for_repo("repo-a");
+        storage.save_session(&session("a")).unwrap();
+        storage = storage.for_repo("repo-b");
+        storage.save_session(&session("b")).unwrap();
+
+        let ids = |storage: &Storage| storage.list_sessions().unwrap().into_iter().map(|s| s.id).collect::<Vec<_>>();
+        assert_eq!(ids(&storage), ["legacy", "b"]);
+        storage = storage.for_repo("repo-a");
+        assert_eq!(ids(&storage), ["legacy", "a"]);
+        assert_eq!(ids(&storage.for_repo("")).len(), 3);
+    }
+
+    #[test]
+    fn repositories_keep_their_own_session_with_the_same_id() {
+        let storage = Storage::new(":memory:").unwrap().for_repo("repo-a");
+        let in_a = ReviewSession { title: "Login".to_string(), ..session("sess1") };
+        storage.save_session(&in_a).unwrap();
+        storage.save_comment(&comment("c1", "sess1")).unwrap();
+        storage.save_session_key("sess1", 0, b"key-a").unwrap();
+
+        let storage = storage.for_repo("repo-b");
+        let in_b = ReviewSession { title: "Parser".to_string(), ..session("sess1") };
+        storage.save_session(&in_b).unwrap();
+        storage.save_session_key("sess1", 0, b"key-b").unwrap();
+        assert_eq!(storage.load_session("sess1").unwrap(), Some(in_b));
+        assert_eq!(storage.load_comments("sess1").unwrap(), vec![]);
+        assert_eq!(storage.load_session_keys("sess1").unwrap(), vec![(0, b"key-b".to_vec())]);
+
+        let storage = storage.for_repo("repo-a");
+        assert_eq!(storage.load_session("sess1").unwrap(), Some(in_a));
+        assert_eq!(storage.load_comments("sess1").unwrap(), vec![comment("c1", "sess1")]);
+        assert_eq!(storage.load_session_keys("sess1").unwrap(), vec![(0, b"key-a".to_vec())]);
+        assert_eq!(storage.session_summaries().unwrap()[0].open_comments, 1);
+    }
+
+    #[test]
+    fn legacy_sessions_move_to_the_first_repository_that_writes_them() {
+        let storage = Storage::new(":memory:").unwrap();
+        storage.save_session(&session("sess1")).unwrap();
+        storage.save_comment(&comment("c1", "sess1")).unwrap();
+
+        let storage = storage.for_repo("repo-a");
+        storage.save_comment(&comment("c2", "sess1")).unwrap();
+        assert_eq!(storage.load_comments("sess1").unwrap().len(), 2);
+        let storage = storage.for_repo("repo-b");
+        assert_eq!(storage.load_session("sess1").unwrap(), None);
+        assert_eq!(storage.load_comments("sess1").unwrap(), vec![]);
+    }
+
+    #[test]
+    fn databases_keyed_by_session_id_alone_are_rekeyed() {
+        let dir = TempDir::new("storage-rekey");
+        let path = dir.join("review_mesh.db");
+        let path = path.to_str().unwrap();
+        {
+            let conn = Connection::open(path).unwrap();
+            conn.execute_batch(
+                "CREATE TABLE sessions (id TEXT PRIMARY KEY, title TEXT, created_at TEXT, participants TEXT);
+                 CREATE TABLE comments (id TEXT PRIMARY KEY, session_id TEXT, author TEXT, file TEXT, hunk_id TEXT,
+                     line INTEGER, body TEXT, created_at TEXT, resolved INTEGER);
+                 CREATE TABLE chat (id TEXT PRIMARY KEY, session_id TEXT, author TEXT, body TEXT, created_at TEXT);
+                 INSERT INTO sessions VALUES ('sess1', 'Login', '2020-09-13T12:26:40+00:00', '[\"alice\"]');
+                 INSERT INTO comments VALUES ('c1', 'sess1', 'alice', 'src/lib.rs', 'h1', 3, 'Missing bounds check',
+                     '2020-09-13T12:28:20+00:00', 0);",
+            )
+            .unwrap();
+            search::create_index(&conn).unwrap();
+        }
+
+        let storage = Storage::new(path).unwrap().for_repo("repo-a");
+        assert_eq!(storage.load_session("sess1").unwrap().unwrap().title, "Login");
+        assert_eq!(storage.search("bounds", &SearchFilter::default()).unwrap().len(), 1);
+        storage.save_comment(&comment("c2", "sess1")).unwrap();
+        storage.for_repo("repo-b").save_session(&session("sess1")).unwrap();
+        let storage = Storage::new(path).unwrap();
+        assert_eq!(storage.list_sessions().unwrap().len(), 2);
+        assert_eq!(storage.for_repo("repo-a").load_comments("sess1").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn summaries_count_comments_and_order_by_activity() {
+        let storage = Storage::new(":memory:").unwrap();
+        let at = |secs: i64| Utc.timestamp_opt(1_600_000_000 + secs, 0).unwrap();
+        for id in ["quiet", "busy"] {
+            storage.save_session(&session(id)).unwrap();
+        }
+        for (id, resolved) in [("c1", false), ("c2", true), ("c3", true)] {
+            storage.save_comment(&Comment { created_at: at(60), resolved, ..comment(id, "busy") }).unwrap();
+        }
+
+        let summaries = storage.session_summaries().unwrap();
+        assert_eq!(summaries[0].session.id, "busy");
+        assert_eq!((summaries[0].open_comments, summaries[0].resolved_comments), (1, 2));
+        assert_eq!(summaries[0].last_activity, at(60));
+        assert_eq!((summaries[1].session.id.as_str(), summaries[1].last_activity), ("quiet", at(0)));
+    }
+
+    #[test]
+    fn merging_records_twice_changes_nothing() {
+        let storage = Storage::new(":memory:").unwrap();
+        let session = session("sess1");
+        let comment = Comment { side: Side::Old, resolved: true, ..comment("c1", "sess1") };
+        storage.save_session(&session).unwrap();
+        storage.save_comment(&Comment { resolved: false, ..comment.clone() }).unwrap();
+
+        let records = SessionRecords { session, diff: None, comments: vec![comment], chat: vec![], verdicts: vec![] };
+        storage.merge_records(&records).unwrap();
+        storage.merge_records(&records).unwrap();
+        assert_eq!(storage.load_records("sess1").unwrap(), Some(records.clone()));
+
+        // Reopening later wins over the older resolution, but not the reverse.
+        let reopened = Comment { resolved: false, resolved_at: Some(Utc.timestamp_opt(1_600_000_200, 0).unwrap()), ..records.comments[0].clone() };
+        let resolved = Comment { resolved: true, resolved_at: Some(Utc.timestamp_opt(1_600_000_150, 0).unwrap()), ..reopened.clone() };
+        for comment in [reopened.clone(), resolved] {
+            storage.merge_records(&SessionRecords { comments: vec![comment], ..records.clone() }).unwrap();
+        }
+        assert_eq!(storage.load_comment("c1").unwrap(), Some(reopened));
+    }
+}
@@ -0,0 +1,219 @@
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, Result, Row};
+
+use common::{ChatLine, Comment};
+
+use crate::{chat_from_row, comment_from_row, Storage};
+
+/// Narrows [`Storage::search`]. Unset fields match everything.
+#[derive(Debug, Clone, Default)]
+pub struct SearchFilter {
+    pub session_id: Option<String>,
+    /// Display name or PeerId.
+    pub author: Option<String>,
+    /// Only comments on this file.
+    pub file: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    /// Only comments that are (or aren't) resolved.
+    pub resolved: Option<bool>,
+    /// Leave chat out of the results.
+    pub comments_only: bool,
+    /// At most this many hits; 0 for all of them.
+    pub limit: usize,
+}
+
+impl SearchFilter {
+    /// Chat has no file or resolved state, so filtering on either leaves it out.
+    fn includes_chat(&self) -> bool {
+        !self.comments_only && self.file.is_none() && self.resolved.is_none()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HitRecord {
+    Comment(Comment),
+    Chat(ChatLine),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SearchHit {
+    pub record: HitRecord,
+    /// The matching part of the body with each match in `[` `]`.
+    pub snippet: String,
+}
+
+/// Creates the full-text indexes over comment and chat bodies, filling them
+/// from existing rows the first time. Triggers keep them current, and
+/// `recursive_triggers` makes `INSERT OR REPLACE` fire the delete trigger.
+pub(crate) fn create_index(conn: &Connection) -> Result<()> {
+    conn.pragma_update(None, "recursive_triggers", true)?;
+    for table in ["comments", "chat"] {
+        let index = format!("{}_fts", table);
+        let exists = conn
+            .prepare("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1")?
+            .exists(params![index])?;
+        conn.execute_batch(&format!(
+            r#"
+            CREATE VIRTUAL TABLE IF NOT EXISTS {index} USING fts5(body, tokenize = 'porter unicode61');
+            CREATE TRIGGER IF NOT EXISTS {table}_fts_insert AFTER INSERT ON {table} BEGIN
+                INSERT INTO {index} (rowid, body) VALUES (new.rowid, new.body);
+            END;
+            CREATE TRIGGER IF NOT EXISTS {table}_fts_delete AFTER DELETE ON {table} BEGIN
+                DELETE FROM {index} WHERE rowid = old.rowid;
+            END;
+            CREATE TRIGGER IF NOT EXISTS {table}_fts_update AFTER UPDATE OF body ON {table} BEGIN
+                UPDATE {index} SET body = new.body WHERE rowid = new.rowid;
+            END;
+            "#
+        ))?;
+        if !exists {
+            conn.execute_batch(&format!("INSERT INTO {index} (rowid, body) SELECT rowid, body FROM {table}"))?;
+        }
+    }
+    Ok(())
+}
+
+impl Storage {
+    /// Comments and chat whose bodies contain every word of `query`, best
+    /// match first. A word ending in `*` matches as a prefix. Only sessions
+    /// of the current repository are searched when the storage is tied to one.
+    pub fn search(&self, query: &str, filter: &SearchFilter) -> Result<Vec<SearchHit>> {
+        let query = match_expression(query);
+        if query.is_empty() {
+            return Ok(vec![]);
+        }
+        let since = filter.since.map(|t| t.to_rfc3339());
+        let until = filter.until.map(|t| t.to_rfc3339());
+        let limit = if filter.limit == 0 { -1 } else { filter.limit as i64 };
+
+        let mut hits: Vec<(f64, SearchHit)> = vec![];
+        let mut stmt = self.conn.prepare(
+            "SELECT c.id, c.session_id, c.author, c.file, c.hunk_id, c.line, c.body, c.created_at, c.resolved, c.author_id, c.signature, c.resolved_at, c.side,
+                    snippet(comments_fts, 0, '[', ']', '...', 12), bm25(comments_fts)
+             FROM comments_fts JOIN comments c ON c.rowid = comments_fts.rowid
+             WHERE comments_fts MATCH ?1
+               AND (?2 IS NULL OR c.session_id = ?2)
+               AND (?3 IS NULL OR c.author = ?3 OR c.author_id = ?3)
+               AND (?4 IS NULL OR c.file = ?4)
+               AND (?5 IS NULL OR c.created_at >= ?5)
+               AND (?6 IS NULL OR c.created_at < ?6)
+               AND (?7 IS NULL OR c.resolved = ?7)
+               AND (?8 = '' OR c.repo IN (?8, ''))
+             ORDER BY bm25(comments_fts) LIMIT ?9",
+        )?;
+        let rows = stmt.query_map(
+            params![query, filter.session_id, filter.author, filter.file, since, until, filter.resolved, self.repo, limit],
+            |row| hit_from_row(row, 13, HitRecord::Comment(comment_from_row(row)?)),
+        )?;
+        hits.extend(rows.filter_map(Result::ok));
+
+        if filter.includes_chat() {
+            let mut stmt = self.conn.prepare(
+                "SELECT c.id, c.session_id, c.author, c.body, c.created_at, c.author_id, c.signature,
+                        snippet(chat_fts, 0, '[', ']', '...', 12), bm25(chat_fts)
+                 FROM chat_fts JOIN chat c ON c.rowid = chat_fts.rowid
+                 WHERE chat_fts MATCH ?1
+                   AND (?2 IS NULL OR c.session_id = ?2)
+                   AND (?3 IS NULL OR c.author = ?3 OR c.author_id = ?3)
+                   AND (?4 IS NULL OR c.created_at >= ?4)
+                   AND (?5 IS NULL OR c.created_at < ?5)
+                   AND (?6 = '' OR c.repo IN (?6, ''))
+                 ORDER BY bm25(chat_fts) LIMIT ?7",
+            )?;
+            let rows = stmt.query_map(params![query, filter.session_id, filter.author, since, until, self.repo, limit], |row| {
+                hit_from_row(row, 7, HitRecord::Chat(chat_from_row(row)?))
+            })?;
+            hits.extend(rows.filter_map(Result::ok));
+        }
+
+        // bm25 ranks lower for better matches.
+        hits.sort_by(|a, b| a.0.total_cmp(&b.0));
+        if filter.limit > 0 {
+            hits.truncate(filter.limit);
+        }
+        Ok(hits.into_iter().map(|(_, hit)| hit).collect())
+    }
+}
+
+fn hit_from_row(row: &Row, snippet: usize, record: HitRecord) -> Result<(f64, SearchHit)> {
+    Ok((row.get(snippet + 1)?, SearchHit { record, snippet: row.get(snippet)? }))
+}
+
+/// Quotes each word so punctuation in `query` can't be read as FTS5 syntax,
+/// keeping a trailing `*` as a prefix match.
+fn match_expression(query: &str) -> String {
+    query
+        .split_whitespace()
+        .filter_map(|word| {
+            let (word, prefix) = match word.strip_suffix('*') {
+                Some(stem) => (stem, "*"),
+                None => (word, ""),
+            };
+            (!word.is_empty()).then(|| format!("\"{}\"{}", word.replace('"', "\"\""), prefix))
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use common::Side;
+
+    #[test]
+    fn search_follows_edits_and_filters() {
+        let storage = Storage::new(":memory:").unwrap();
+        let created_at = Utc.timestamp_opt(1_600_000_000, 0).unwrap();
+        let comment = Comment {
+            id: "c1".to_string(),
+            session_id: "s1".to_string(),
+            author: "alice".to_string(),
+            author_id: "peer-a".to_string(),
+            file: "src/login.rs".to_string(),
+            hunk_id: "h1".to_string(),
+            line: 3,
+            side: Side::New,
+            body: "Missing bounds check on the password length".to_string(),
+            created_at,
+            resolved: false,
+            resolved_at: None,
+            signature: String::new(),
+        };
+        storage.save_comment(&comment).unwrap();
+        storage
+            .save_chat(&ChatLine {
+                id: "m1".to_string(),
+                session_id: "s1".to_string(),
+                author: "bob".to_string(),
+                author_id: "peer-b".to_string(),
+                body: "Who owns the password checks?".to_string(),
+                created_at,
+                signature: String::new(),
+            })
+            .unwrap();
+        let ids = |query: &str, filter: &SearchFilter| {
+            storage
+                .search(query, filter)
+                .unwrap()
+                .into_iter()
+                .map(|hit| match hit.record {
+                    HitRecord::Comment(c) => c.id,
+                    HitRecord::Chat(c) => c.id,
+                })
+                .collect::<Vec<_>>()
+        };
+        let all = SearchFilter::default();
+
+        assert_eq!(ids("password", &all).len(), 2);
+        assert_eq!(ids("checks", &all).len(), 2, "stemming matches check and checks");
+        assert_eq!(ids("pass*", &SearchFilter { author: Some("bob".to_string()), ..all.clone() }), ["m1"]);
+        assert_eq!(ids("password", &SearchFilter { resolved: Some(false), ..all.clone() }), ["c1"]);
+        assert!(ids("\"unbalanced (", &all).is_empty());
+
+        storage.save_comment(&Comment { body: "Off by one".to_string(), resolved: true, ..comment }).unwrap();
+        assert_eq!(ids("password", &all), ["m1"]);
+        assert_eq!(ids("one", &SearchFilter { resolved: Some(true), ..all }), ["c1"]);
+    }
+}